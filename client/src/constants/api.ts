@@ -3,8 +3,23 @@ const apiBase = `${apiDomain}/api`;
 
 export const API = {
   LOGIN: `${apiBase}/login`,
+  LOGIN_TWO_FACTOR: `${apiBase}/login/2fa`,
+  PASSKEY_LOGIN_START: `${apiBase}/passkeys/login/start`,
+  PASSKEY_LOGIN_FINISH: `${apiBase}/passkeys/login/finish`,
+  PASSKEY_REGISTER_START: `${apiBase}/passkeys/register/start`,
+  PASSKEY_REGISTER_FINISH: `${apiBase}/passkeys/register/finish`,
+  AUTH_CONFIG: `${apiBase}/auth/config`,
+  OIDC_CONFIG: `${apiBase}/oidc/config`,
+  OIDC_LOGIN: `${apiBase}/oidc/login`,
+  OIDC_LINK: `${apiBase}/oidc/link`,
   REGISTER: `${apiBase}/register`,
-  CREATE_USER: `${apiBase}/create_user`,
+  TOKEN_REFRESH: `${apiBase}/token/refresh`,
+  EMAIL_VERIFY_CONFIRM: `${apiBase}/email/verify/confirm`,
+  EMAIL_CHANGE_CONFIRM: `${apiBase}/user/email/confirm`,
+  PASSWORD_RESET_REQUEST: `${apiBase}/password/reset/request`,
+  PASSWORD_RESET_CONFIRM: `${apiBase}/password/reset/confirm`,
+  LOGOUT: `${apiBase}/logout`,
+  LOGOUT_ALL: `${apiBase}/logout/all`,
   GET_USER: `${apiBase}/user`,
   GET_USER_LINKS: `${apiBase}/user/links`,
   CREATE_LINK: `${apiBase}/link`,
@@ -17,12 +17,33 @@ const router = createRouter({
       component: () => import("../views/LoginScreen.vue"),
       meta: { requiresGuest: true },
     },
+    {
+      path: "/login/sso",
+      name: "ssoCallback",
+      component: () => import("../views/SsoCallback.vue"),
+      meta: { requiresGuest: true },
+    },
     {
       path: "/signup",
       name: "signup",
       component: () => import("../views/SignUpScreen.vue"),
       meta: { requiresGuest: true },
     },
+    {
+      path: "/verify-email",
+      name: "verifyEmail",
+      component: () => import("../views/VerifyEmail.vue"),
+    },
+    {
+      path: "/confirm-email-change",
+      name: "confirmEmailChange",
+      component: () => import("../views/ConfirmEmailChange.vue"),
+    },
+    {
+      path: "/reset-password",
+      name: "resetPassword",
+      component: () => import("../views/ResetPassword.vue"),
+    },
     {
       path: "/",
       name: "home",
@@ -53,6 +74,7 @@ router.beforeEach(async (to, from, next) => {
     }
   }
 
+  await authService.loadAuthMode();
   const isAuthenticated = authService.isAuthenticated();
 
   // Handle guest-only routes (like login)
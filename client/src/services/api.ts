@@ -1,6 +1,10 @@
 // src/services/api.ts
 import { useUserStore } from "@/stores/user";
-import axios, { type AxiosInstance } from "axios";
+import axios, {
+  type AxiosInstance,
+  type InternalAxiosRequestConfig,
+} from "axios";
+import authService from "./auth";
 
 const apiDomain = import.meta.env.VITE_API_BASE_URL || "http://localhost:3000";
 const api: AxiosInstance = axios.create({
@@ -8,6 +12,27 @@ const api: AxiosInstance = axios.create({
   timeout: 10000, // Optional
 });
 
+type RetriableRequestConfig = InternalAxiosRequestConfig & {
+  _retried?: boolean;
+  _rateLimited?: boolean;
+};
+
+// Longest Retry-After worth waiting out before retrying a rate limited request
+const MAX_RATE_LIMIT_WAIT_SECONDS = 10;
+
+// Share one refresh between requests that fail at the same time,
+// since reusing a rotated refresh token revokes the session
+let refreshInFlight: Promise<string | null> | null = null;
+
+const refreshAccessToken = (): Promise<string | null> => {
+  if (!refreshInFlight) {
+    refreshInFlight = authService.refresh().finally(() => {
+      refreshInFlight = null;
+    });
+  }
+  return refreshInFlight;
+};
+
 // Request interceptor to add auth header
 api.interceptors.request.use(
   (config) => {
@@ -25,18 +50,50 @@ api.interceptors.request.use(
 
 // Response interceptor for token refresh and error handling
 api.interceptors.response.use(
-  (response) => {
-    // Check for new token in response headers (auto-refresh from backend)
-    const newToken = response.headers["x-new-auth-token"];
-    if (newToken) {
-      localStorage.setItem("token", newToken);
+  (response) => response,
+  async (error) => {
+    const config = error.config as RetriableRequestConfig | undefined;
+
+    // Back off once when the server says we're sending too much
+    if (error.response?.status === 429 && config && !config._rateLimited) {
+      const retryAfter = Number(
+        error.response.headers["retry-after"] ??
+          error.response.headers["ratelimit-reset"],
+      );
+      if (retryAfter > 0 && retryAfter <= MAX_RATE_LIMIT_WAIT_SECONDS) {
+        config._rateLimited = true;
+        await new Promise((resolve) => setTimeout(resolve, retryAfter * 1000));
+        return api(config);
+      }
     }
-    return response;
-  },
-  (error) => {
-    if (error.response?.status === 401) {
-      // Token expired or invalid - clear and redirect
+
+    if (error.response?.status === 401 && config && !config._retried) {
+      config._retried = true;
+
+      // Another tab may already have refreshed the shared token
+      const usedToken = config.headers
+        .get("Authorization")
+        ?.toString()
+        .replace("Bearer ", "");
+      const storedToken = localStorage.getItem("token");
+      const newToken =
+        storedToken && storedToken !== usedToken
+          ? storedToken
+          : await refreshAccessToken();
+
+      if (newToken) {
+        return api(config);
+      }
+
+      // Only log out if no other tab has signed in again in the meantime
+      const latestToken = localStorage.getItem("token");
+      if (latestToken && latestToken !== usedToken) {
+        return api(config);
+      }
+
+      // Refresh failed - clear and redirect
       localStorage.removeItem("token");
+      localStorage.removeItem("refresh_token");
       const userStore = useUserStore();
       userStore.clearUser();
       window.location.href = "/";
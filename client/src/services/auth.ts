@@ -1,6 +1,10 @@
 import { API } from "@/constants/api";
 import { useUserStore } from "@/stores/user";
-import type { AuthResponse } from "@/types/User";
+import type {
+  AuthResponse,
+  LoginResponse,
+  RefreshTokenResponse,
+} from "@/types/User";
 import axios from "axios";
 
 const authApi = axios.create({
@@ -8,25 +12,163 @@ const authApi = axios.create({
   timeout: 10000,
 });
 
+/**
+ * "proxy" when a trusted reverse proxy signs users in, "local" for a single-user install.
+ * Neither has a login screen.
+ */
+export type AuthMode = "password" | "proxy" | "local";
+
+/** Who can create an account: anyone, only people with an invite code, or nobody */
+export type RegistrationMode = "open" | "invite" | "closed";
+
+let authMode: AuthMode = "password";
+let registrationMode: RegistrationMode = "open";
+let authModeRequest: Promise<AuthMode> | null = null;
+
 export const authService = {
-  async login(email: string, password: string): Promise<AuthResponse> {
-    const response = await authApi.post<AuthResponse>(API.LOGIN, {
+  /**
+   * Asks the server how users sign in, once per page load.
+   * Falls back to the login screen if the server can't be reached.
+   */
+  loadAuthMode(): Promise<AuthMode> {
+    if (!authModeRequest) {
+      authModeRequest = authApi
+        .get<{ mode: AuthMode; registration: RegistrationMode }>(
+          API.AUTH_CONFIG,
+        )
+        .then((response) => {
+          authMode = response.data.mode;
+          registrationMode = response.data.registration;
+          return authMode;
+        })
+        .catch(() => authMode);
+    }
+    return authModeRequest;
+  },
+
+  async loadRegistrationMode(): Promise<RegistrationMode> {
+    await this.loadAuthMode();
+    return registrationMode;
+  },
+
+  async login(email: string, password: string): Promise<LoginResponse> {
+    const response = await authApi.post<LoginResponse>(API.LOGIN, {
       email,
       password,
     });
     return response.data;
   },
 
-  async register(email: string, password: string): Promise<AuthResponse> {
+  /**
+   * Finishes a login that returned a two-factor challenge.
+   * Codes with a dash are treated as recovery codes, anything else as an authenticator code.
+   */
+  async loginTwoFactor(
+    challengeToken: string,
+    code: string,
+  ): Promise<AuthResponse> {
+    const isRecoveryCode = code.includes("-");
+    const response = await authApi.post<AuthResponse>(API.LOGIN_TWO_FACTOR, {
+      challenge_token: challengeToken,
+      code: isRecoveryCode ? undefined : code,
+      recovery_code: isRecoveryCode ? code : undefined,
+    });
+    return response.data;
+  },
+
+  async register(
+    email: string,
+    password: string,
+    inviteCode?: string,
+  ): Promise<AuthResponse> {
     const response = await authApi.post<AuthResponse>(API.REGISTER, {
       email,
       password,
+      invite_code: inviteCode || undefined,
     });
     return response.data;
   },
 
+  /**
+   * Exchanges the stored refresh token for a new access token.
+   * The refresh token is rotated, so the new one replaces it.
+   * @returns the new access token, or null if the session can't be refreshed
+   */
+  async refresh(retried = false): Promise<string | null> {
+    const refreshToken = localStorage.getItem("refresh_token");
+    if (!refreshToken) {
+      return null;
+    }
+
+    try {
+      const response = await authApi.post<RefreshTokenResponse>(
+        API.TOKEN_REFRESH,
+        { refresh_token: refreshToken },
+      );
+      this.setToken(response.data.token, response.data.refresh_token);
+      return response.data.token;
+    } catch {
+      // Another tab may have rotated the token while this refresh was in flight,
+      // in which case it stored a new one to carry on with
+      const storedRefreshToken = localStorage.getItem("refresh_token");
+      if (
+        !retried &&
+        storedRefreshToken &&
+        storedRefreshToken !== refreshToken
+      ) {
+        return this.refresh(true);
+      }
+      return null;
+    }
+  },
+
+  /** Redeems the token from a verification email */
+  async confirmEmail(token: string): Promise<void> {
+    await authApi.post(API.EMAIL_VERIFY_CONFIRM, { token });
+  },
+
+  /**
+   * Redeems the token from an email change confirmation, which signs the account out everywhere
+   * else and starts a session here with the new email
+   */
+  async confirmEmailChange(token: string): Promise<AuthResponse> {
+    const response = await authApi.post<AuthResponse>(
+      API.EMAIL_CHANGE_CONFIRM,
+      { token },
+    );
+    this.setToken(response.data.token, response.data.refresh_token);
+    return response.data;
+  },
+
+  /** Emails a reset link, the server answers the same whether or not the account exists */
+  async requestPasswordReset(email: string): Promise<void> {
+    await authApi.post(API.PASSWORD_RESET_REQUEST, { email });
+  },
+
+  /** Sets a new password with the token from a reset email, signing out every session */
+  async confirmPasswordReset(
+    token: string,
+    newPassword: string,
+  ): Promise<void> {
+    await authApi.post(API.PASSWORD_RESET_CONFIRM, {
+      token,
+      new_password: newPassword,
+    });
+  },
+
   logout(): void {
+    // Revoke the session server-side, but don't hold up the local logout on it
+    const token = localStorage.getItem("token");
+    if (token) {
+      authApi
+        .post(API.LOGOUT, null, {
+          headers: { Authorization: `Bearer ${token}` },
+        })
+        .catch(() => {});
+    }
+
     localStorage.removeItem("token");
+    localStorage.removeItem("refresh_token");
     const userStore = useUserStore();
     userStore.clearUser();
   },
@@ -36,11 +178,14 @@ export const authService = {
   },
 
   isAuthenticated(): boolean {
-    return !!localStorage.getItem("token");
+    return authMode !== "password" || !!localStorage.getItem("token");
   },
 
-  setToken(token: string): void {
+  setToken(token: string, refreshToken?: string): void {
     localStorage.setItem("token", token);
+    if (refreshToken) {
+      localStorage.setItem("refresh_token", refreshToken);
+    }
   },
 };
 
@@ -0,0 +1,110 @@
+import { API } from "@/constants/api";
+import type { AuthResponse } from "@/types/User";
+import axios from "axios";
+import api from "./api";
+
+const authApi = axios.create({
+  baseURL: import.meta.env.VITE_API_BASE_URL || "http://localhost:3000",
+  timeout: 60000,
+});
+
+// WebAuthn works with ArrayBuffers, the server sends and expects base64url strings
+const toBuffer = (value: string): ArrayBuffer => {
+  const base64 = value.replace(/-/g, "+").replace(/_/g, "/");
+  const padded = base64.padEnd(base64.length + ((4 - (base64.length % 4)) % 4), "=");
+  return Uint8Array.from(atob(padded), (c) => c.charCodeAt(0)).buffer;
+};
+
+const toBase64Url = (buffer: ArrayBuffer | null): string | undefined => {
+  if (!buffer) return undefined;
+  const binary = String.fromCharCode(...new Uint8Array(buffer));
+  return btoa(binary).replace(/\+/g, "-").replace(/\//g, "_").replace(/=+$/, "");
+};
+
+type CredentialDescriptorJSON = { id: string; type: PublicKeyCredentialType };
+
+export const passkeyService = {
+  isSupported(): boolean {
+    return typeof window !== "undefined" && !!window.PublicKeyCredential;
+  },
+
+  /**
+   * Signs in with whichever passkey the browser offers for this site.
+   * @returns the same tokens and user as a password login
+   */
+  async login(): Promise<AuthResponse> {
+    const start = await authApi.post(API.PASSKEY_LOGIN_START);
+    const options = start.data.options.publicKey;
+
+    const credential = (await navigator.credentials.get({
+      publicKey: {
+        ...options,
+        challenge: toBuffer(options.challenge),
+        allowCredentials: (options.allowCredentials ?? []).map(
+          (c: CredentialDescriptorJSON) => ({ ...c, id: toBuffer(c.id) }),
+        ),
+      },
+    })) as PublicKeyCredential | null;
+    if (!credential) {
+      throw new Error("No passkey was selected");
+    }
+
+    const response = credential.response as AuthenticatorAssertionResponse;
+    const finish = await authApi.post<AuthResponse>(API.PASSKEY_LOGIN_FINISH, {
+      ceremony_id: start.data.ceremony_id,
+      credential: {
+        id: credential.id,
+        rawId: toBase64Url(credential.rawId),
+        type: credential.type,
+        extensions: credential.getClientExtensionResults(),
+        response: {
+          authenticatorData: toBase64Url(response.authenticatorData),
+          clientDataJSON: toBase64Url(response.clientDataJSON),
+          signature: toBase64Url(response.signature),
+          userHandle: toBase64Url(response.userHandle),
+        },
+      },
+    });
+    return finish.data;
+  },
+
+  /**
+   * Registers a new passkey for the signed-in user.
+   */
+  async register(name?: string): Promise<void> {
+    const start = await api.post(API.PASSKEY_REGISTER_START);
+    const options = start.data.options.publicKey;
+
+    const credential = (await navigator.credentials.create({
+      publicKey: {
+        ...options,
+        challenge: toBuffer(options.challenge),
+        user: { ...options.user, id: toBuffer(options.user.id) },
+        excludeCredentials: (options.excludeCredentials ?? []).map(
+          (c: CredentialDescriptorJSON) => ({ ...c, id: toBuffer(c.id) }),
+        ),
+      },
+    })) as PublicKeyCredential | null;
+    if (!credential) {
+      throw new Error("Passkey registration was cancelled");
+    }
+
+    const response = credential.response as AuthenticatorAttestationResponse;
+    await api.post(API.PASSKEY_REGISTER_FINISH, {
+      ceremony_id: start.data.ceremony_id,
+      name,
+      credential: {
+        id: credential.id,
+        rawId: toBase64Url(credential.rawId),
+        type: credential.type,
+        extensions: credential.getClientExtensionResults(),
+        response: {
+          attestationObject: toBase64Url(response.attestationObject),
+          clientDataJSON: toBase64Url(response.clientDataJSON),
+        },
+      },
+    });
+  },
+};
+
+export default passkeyService;
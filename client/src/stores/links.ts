@@ -1,7 +1,6 @@
 import { API } from "@/constants/api";
 import api from "@/services/api";
 import { useUserSettingsStore } from "@/stores/settings";
-import { useUserStore } from "@/stores/user";
 import type { CreateLinkRequest, Link, UpdateLinkRequest } from "@/types/Link";
 import { CacheKeys, cache } from "@/utils/cache";
 import type { AxiosError } from "axios";
@@ -79,21 +78,12 @@ export const useLinksStore = defineStore("links", {
 
     async postLink(link: CreateLinkRequest) {
       this.isLoading = true;
-      const userStore = useUserStore();
-      const authToken = userStore.getAuthToken();
       const settingsStore = useUserSettingsStore();
       const metadata_on = settingsStore.settings.metadata;
 
-      // Only proceed if we have an auth token
-      if (!authToken) {
-        console.warn("No auth token available for create link");
-        return;
-      }
-
       try {
         const response = await api.post(API.CREATE_LINK, link, {
           headers: {
-            "X-User-Authorization": authToken,
             "X-Fetch-Metadata": metadata_on,
           },
         });
@@ -109,8 +99,10 @@ export const useLinksStore = defineStore("links", {
       } catch (error) {
         this.error = error as string;
         this.isLoading = false;
-        if ((error as AxiosError).status === 502) {
-          return 502;
+        const status = (error as AxiosError).status;
+        // 409 means there's already a link to this URL, resending with allow_duplicate adds it anyway
+        if (status === 502 || status === 409) {
+          return status;
         }
         return false;
       } finally {
@@ -146,7 +138,7 @@ export const useLinksStore = defineStore("links", {
       }
     },
 
-    async updateLink(link: Link) {
+    async updateLink(link: Link, allowDuplicate = false) {
       this.isLoading = true;
       this.links = this.links.map((l) => (l.id === link.id ? link : l));
       // because all the fields are technically optional on the backend,
@@ -159,6 +151,7 @@ export const useLinksStore = defineStore("links", {
         icon: link.icon,
         column_type: link.column_type,
         order_index: link.order_index,
+        allow_duplicate: allowDuplicate,
       };
       /*
         if 200, link was updated, nothing else to do
@@ -173,6 +166,9 @@ export const useLinksStore = defineStore("links", {
       } catch (error) {
         this.error = error as string;
         this.isLoading = false;
+        if ((error as AxiosError).status === 409) {
+          return 409;
+        }
         return false;
       } finally {
         this.isLoading = false;
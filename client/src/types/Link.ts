@@ -20,6 +20,8 @@ export type CreateLinkRequest = {
   owner_type: string;
   owner_id: string;
   column_type: string;
+  /** Add the link even when the owner already has one to the same URL */
+  allow_duplicate?: boolean;
 };
 
 export type UpdateLinkRequest = {
@@ -30,4 +32,6 @@ export type UpdateLinkRequest = {
   icon: string | null;
   column_type: string | null;
   order_index?: number;
+  /** Move the link onto a URL the owner already has a link to */
+  allow_duplicate?: boolean;
 };
@@ -28,9 +28,23 @@ export type AuthUser = {
 // Response from login/register endpoints
 export type AuthResponse = {
   token: string;
+  refresh_token: string;
   user: User;
 };
 
+// Returned by login instead of tokens when the account has two-factor authentication on
+export type TwoFactorChallenge = {
+  two_factor_required: true;
+  challenge_token: string;
+};
+
+export type LoginResponse = AuthResponse | TwoFactorChallenge;
+
+export type RefreshTokenResponse = {
+  token: string;
+  refresh_token: string;
+};
+
 export type UserDataResponse = {
   user: User;
   settings: settings_blob;
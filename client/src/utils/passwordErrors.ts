@@ -0,0 +1,20 @@
+// Messages for the error codes the server sends when it refuses a new password
+type PasswordErrorResponse = {
+  error?: string;
+  min_length?: number;
+};
+
+export const passwordErrorMessage = (
+  data: PasswordErrorResponse | undefined,
+): string | null => {
+  switch (data?.error) {
+    case "password_too_short":
+      return `Password must be at least ${data.min_length ?? 8} characters`;
+    case "password_too_weak":
+      return "Password is too easy to guess. Try a longer phrase or mix in unrelated words.";
+    case "password_breached":
+      return "This password has appeared in a data breach. Please choose a different one.";
+    default:
+      return null;
+  }
+};
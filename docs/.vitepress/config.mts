@@ -43,6 +43,9 @@ export default defineConfig({
           { text: 'Confluence', link: '/guides/confluence-integration' },
           { text: 'Jira', link: '/guides/jira-integration' },
           { text: 'Linear', link: '/guides/linear-integration' },
+          { text: 'API Tokens', link: '/guides/api-tokens' },
+          { text: 'Administration', link: '/guides/administration' },
+          { text: 'Organizations and Teams', link: '/guides/organizations-and-teams' },
         ],
       }
     ],
@@ -0,0 +1,6 @@
+-- Canonical URL for duplicate-link detection
+-- Populated by the server from links.url; existing rows are backfilled on startup
+
+ALTER TABLE links ADD COLUMN canonical_url TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_links_owner_canonical_url ON links(owner_id, owner_type, canonical_url);
@@ -0,0 +1,6 @@
+-- Soft delete for links
+-- Deleted links stay in the trash until restored, purged, or removed by the retention job
+
+ALTER TABLE links ADD COLUMN deleted_at TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_links_deleted_at ON links(deleted_at);
@@ -0,0 +1,15 @@
+-- Link version history
+-- Each row is a snapshot of a link taken just before it was updated
+
+CREATE TABLE IF NOT EXISTS link_revisions (
+    id TEXT PRIMARY KEY,
+    link_id TEXT NOT NULL REFERENCES links(id) ON DELETE CASCADE,
+    title TEXT NOT NULL,
+    url TEXT NOT NULL,
+    icon TEXT,
+    description TEXT,
+    column_type TEXT NOT NULL,
+    created_at TEXT NOT NULL DEFAULT (datetime('now'))
+);
+
+CREATE INDEX IF NOT EXISTS idx_link_revisions_link_id ON link_revisions(link_id, created_at);
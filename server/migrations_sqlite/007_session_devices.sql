@@ -0,0 +1,6 @@
+-- Device details for the active sessions list
+
+ALTER TABLE sessions ADD COLUMN device_label TEXT;
+ALTER TABLE sessions ADD COLUMN user_agent TEXT;
+ALTER TABLE sessions ADD COLUMN ip_address TEXT;
+ALTER TABLE sessions ADD COLUMN last_seen_at TEXT;
@@ -0,0 +1,4 @@
+-- Email changes are confirmed by a token sent to the new address
+-- The address being confirmed is kept on the token until it's redeemed
+
+ALTER TABLE auth_tokens ADD COLUMN new_email TEXT;
@@ -0,0 +1,25 @@
+-- WebAuthn passkeys for passwordless sign-in
+-- passkey_json is the serialized credential, including its signature counter
+
+CREATE TABLE IF NOT EXISTS passkeys (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    credential_id TEXT UNIQUE NOT NULL,
+    name TEXT NOT NULL,
+    passkey_json TEXT NOT NULL,
+    created_at TEXT NOT NULL DEFAULT (datetime('now')),
+    last_used_at TEXT
+);
+
+CREATE INDEX IF NOT EXISTS idx_passkeys_user_id ON passkeys(user_id);
+
+-- Server-side state of in-progress registration and authentication ceremonies
+-- Each one is single-use and short-lived, so challenges can't be replayed
+
+CREATE TABLE IF NOT EXISTS webauthn_ceremonies (
+    id TEXT PRIMARY KEY,
+    user_id TEXT REFERENCES users(id) ON DELETE CASCADE,
+    kind TEXT NOT NULL,
+    state_json TEXT NOT NULL,
+    expires_at TEXT NOT NULL
+);
@@ -0,0 +1,10 @@
+-- Failed sign-in attempts per account and per IP, for brute-force protection
+-- key is "account:<email>", "ip:<address>" or "register:<address>"
+-- Failures are counted until window_expires_at, which each new failure pushes back
+
+CREATE TABLE IF NOT EXISTS login_attempts (
+    key TEXT PRIMARY KEY,
+    failures INTEGER NOT NULL,
+    window_expires_at TEXT NOT NULL,
+    locked_until TEXT
+);
@@ -0,0 +1,16 @@
+-- Instance administrators and disabled accounts
+-- The first registered user becomes the admin; existing instances promote their oldest account
+
+ALTER TABLE users ADD COLUMN is_admin INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE users ADD COLUMN disabled_at TEXT;
+
+UPDATE users SET is_admin = 1
+WHERE id = (SELECT id FROM users ORDER BY created_at LIMIT 1)
+  AND NOT EXISTS (SELECT 1 FROM users WHERE is_admin = 1);
+
+-- Instance-wide settings admins can change at runtime, overriding the environment
+CREATE TABLE IF NOT EXISTS instance_settings (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL,
+    updated_at TEXT NOT NULL DEFAULT (datetime('now'))
+);
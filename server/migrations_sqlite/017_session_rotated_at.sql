@@ -0,0 +1,4 @@
+-- When a session's refresh token was last rotated, so a refresh racing the rotation
+-- with the previous token can be tolerated for a moment instead of revoking the session
+
+ALTER TABLE sessions ADD COLUMN rotated_at TEXT;
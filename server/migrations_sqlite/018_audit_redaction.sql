@@ -0,0 +1,19 @@
+-- Let deleting an account erase the snapshots audit events hold of its data
+-- Events stay append-only otherwise: the only update allowed clears before_json/after_json
+
+DROP TRIGGER IF EXISTS audit_events_no_update;
+
+CREATE TRIGGER IF NOT EXISTS audit_events_no_update
+BEFORE UPDATE ON audit_events
+WHEN NEW.id IS NOT OLD.id
+    OR NEW.actor_id IS NOT OLD.actor_id
+    OR NEW.organization_id IS NOT OLD.organization_id
+    OR NEW.entity_type IS NOT OLD.entity_type
+    OR NEW.entity_id IS NOT OLD.entity_id
+    OR NEW.action IS NOT OLD.action
+    OR NEW.created_at IS NOT OLD.created_at
+    OR NEW.before_json IS NOT NULL AND NEW.before_json IS NOT OLD.before_json
+    OR NEW.after_json IS NOT NULL AND NEW.after_json IS NOT OLD.after_json
+BEGIN
+    SELECT RAISE(ABORT, 'audit_events is append-only');
+END;
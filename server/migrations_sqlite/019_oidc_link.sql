@@ -0,0 +1,4 @@
+-- The signed-in user a pending OIDC login will link the provider account to,
+-- so an existing account is only ever linked by someone who can already sign in to it
+
+ALTER TABLE oidc_login_states ADD COLUMN link_user_id TEXT REFERENCES users(id) ON DELETE CASCADE;
@@ -0,0 +1,115 @@
+use axum::http::Method;
+
+use crate::tokens;
+
+/// Marks a bearer token as a personal API token rather than a JWT
+pub const PREFIX: &str = "omt_";
+
+/// Scopes a token can be granted
+pub const SCOPES: [&str; 4] = [
+    "links:read",
+    "links:write",
+    "settings:read",
+    "settings:write",
+];
+
+/// New random API token, shown to the user once and stored as `tokens::hash`
+pub fn generate() -> String {
+    format!("{}{}", PREFIX, tokens::generate())
+}
+
+/// Validate requested scopes, returning them deduplicated in a stable order
+pub fn parse_scopes(requested: &[String]) -> Option<Vec<&'static str>> {
+    if requested.is_empty() {
+        return None;
+    }
+    if requested
+        .iter()
+        .any(|scope| !SCOPES.contains(&scope.as_str()))
+    {
+        return None;
+    }
+    Some(
+        SCOPES
+            .iter()
+            .copied()
+            .filter(|scope| requested.iter().any(|r| r == scope))
+            .collect(),
+    )
+}
+
+/// The scope an API token needs for an endpoint, relative to `/api`
+/// Anything not listed here, like account and token management, is off limits to API tokens
+pub fn required_scope(method: &Method, path: &str) -> Option<&'static str> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    match (method.clone(), segments.as_slice()) {
+        (Method::GET, ["user", "links"])
+        | (Method::GET, ["user", "links", "duplicates"])
+        | (Method::GET, ["user", "links", "trash"])
+        | (Method::GET, ["link", _, "revisions"]) => Some("links:read"),
+        (Method::POST, ["link"])
+        | (Method::PUT, ["link"])
+        | (Method::DELETE, ["link", _])
+        | (Method::POST, ["link", _, "restore"])
+        | (Method::DELETE, ["link", _, "purge"])
+        | (Method::POST, ["link", _, "revisions", _, "revert"]) => Some("links:write"),
+        (Method::GET, ["settings"]) => Some("settings:read"),
+        (Method::POST, ["settings"]) | (Method::PUT, ["settings"]) => Some("settings:write"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_link_and_settings_routes_to_scopes() {
+        let cases = [
+            (Method::GET, "/user/links", Some("links:read")),
+            (Method::GET, "/link/abc/revisions", Some("links:read")),
+            (Method::POST, "/link", Some("links:write")),
+            (Method::PUT, "/link/", Some("links:write")),
+            (Method::DELETE, "/link/abc", Some("links:write")),
+            (
+                Method::POST,
+                "/link/abc/revisions/def/revert",
+                Some("links:write"),
+            ),
+            (Method::GET, "/settings", Some("settings:read")),
+            (Method::PUT, "/settings", Some("settings:write")),
+        ];
+        for (method, path, scope) in cases {
+            assert_eq!(required_scope(&method, path), scope, "{} {}", method, path);
+        }
+    }
+
+    #[test]
+    fn keeps_everything_else_off_limits() {
+        let cases = [
+            (Method::GET, "/link"),
+            (Method::DELETE, "/user/links"),
+            (Method::GET, "/user"),
+            (Method::DELETE, "/user"),
+            (Method::POST, "/api_tokens"),
+            (Method::PUT, "/user/password"),
+            (Method::GET, "/admin/users"),
+            (Method::DELETE, "/settings"),
+        ];
+        for (method, path) in cases {
+            assert_eq!(required_scope(&method, path), None, "{} {}", method, path);
+        }
+    }
+
+    #[test]
+    fn parses_known_scopes_only() {
+        let requested = ["links:write".to_string(), "links:read".to_string()];
+        assert_eq!(
+            parse_scopes(&requested),
+            Some(vec!["links:read", "links:write"])
+        );
+        assert_eq!(parse_scopes(&[]), None);
+        assert_eq!(parse_scopes(&["admin".to_string()]), None);
+    }
+}
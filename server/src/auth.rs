@@ -0,0 +1,575 @@
+use crate::api_tokens;
+use crate::database::{self, Database};
+use crate::proxy_auth::{self, ProxyAuthConfig};
+use crate::tokens;
+use crate::AppState;
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, FromRequestParts, State},
+    http::{header, request::Parts, HeaderMap, Method, Request, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use std::net::{IpAddr, SocketAddr};
+
+/// The authenticated user for a request, taking it as a handler argument is what makes
+/// the endpoint require a signed-in user
+/// Session and token fields are empty when an API token, a trusted proxy or local mode
+/// authenticated the request
+#[derive(Clone, Debug)]
+pub struct AuthUser {
+    pub user_id: String,
+    pub email: String,
+    pub session_id: String,
+    pub token_id: String,
+    pub token_expires_at: usize,
+    /// Set when a personal API token authenticated the request, scopes are checked here
+    pub api_token_id: Option<String>,
+}
+
+impl FromRequestParts<AppState> for AuthUser {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        app_state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        if let Some(auth_user) = parts.extensions.get::<AuthUser>() {
+            return Ok(auth_user.clone());
+        }
+
+        let auth_user = authenticate(parts, app_state).await?;
+
+        sentry::configure_scope(|scope| {
+            scope.set_user(Some(sentry::User {
+                email: Some(auth_user.email.clone()),
+                id: Some(auth_user.user_id.clone()),
+                ..Default::default()
+            }));
+            scope.set_tag("http.method", parts.method.as_str());
+        });
+        tracing::Span::current().record("user_id", auth_user.user_id.as_str());
+
+        parts.extensions.insert(auth_user.clone());
+        Ok(auth_user)
+    }
+}
+
+/// Middleware for local mode, where anyone who reaches the server is the local user
+/// Only requests addressed to this machine are answered, so a page that rebinds its own
+/// hostname to 127.0.0.1 can't read or change the user's data
+pub async fn require_local_host(
+    State(app_state): State<AppState>,
+    request: Request<Body>,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    if app_state.local_user.is_some() {
+        let host = request
+            .headers()
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .or_else(|| request.uri().host());
+        if !host.is_some_and(is_local_host) {
+            tracing::warn!("Rejected local mode request for host {:?}", host);
+            return Err(StatusCode::FORBIDDEN);
+        }
+    }
+    Ok(next.run(request).await)
+}
+
+/// Whether a Host header names this machine: `localhost`, a loopback address or `APP_URL`'s host
+fn is_local_host(host: &str) -> bool {
+    let hostname = match host.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    };
+    if hostname.eq_ignore_ascii_case("localhost") {
+        return true;
+    }
+    if let Ok(ip) = hostname.parse::<IpAddr>() {
+        return ip.is_loopback();
+    }
+    url::Url::parse(&crate::app_url())
+        .ok()
+        .and_then(|url| {
+            url.host_str()
+                .map(|app_host| app_host.eq_ignore_ascii_case(hostname))
+        })
+        .unwrap_or(false)
+}
+
+/// A signed-in instance administrator, taking it as a handler argument restricts
+/// the endpoint to admins
+/// The flag is read fresh on every request, so taking it away applies immediately
+#[derive(Clone, Debug)]
+pub struct AdminUser(pub AuthUser);
+
+impl FromRequestParts<AppState> for AdminUser {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        app_state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let auth_user = AuthUser::from_request_parts(parts, app_state).await?;
+        crate::require_instance_admin(&app_state.database, &auth_user.user_id).await?;
+        Ok(AdminUser(auth_user))
+    }
+}
+
+async fn authenticate(parts: &Parts, app_state: &AppState) -> Result<AuthUser, StatusCode> {
+    tracing::debug!("Authenticating user");
+    let database = &app_state.database;
+    let peer = parts
+        .extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+
+    // Single-user local mode, everyone who can reach 127.0.0.1 is the local user
+    if let Some(local_user) = &app_state.local_user {
+        return Ok(local_user.clone());
+    }
+
+    if let Some(proxy) = &app_state.proxy_auth {
+        return authenticate_proxy_user(app_state, proxy, peer, &parts.headers).await;
+    }
+
+    let token = parts
+        .headers
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim_start_matches("Bearer ").to_string())
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    if token.starts_with(api_tokens::PREFIX) {
+        return authenticate_api_token(database, &parts.method, parts.uri.path(), &token).await;
+    }
+
+    // Validate JWT token
+    let claims = app_state.jwt_keys.validate_jwt(&token).map_err(|e| {
+        tracing::warn!("JWT validation failed: {:?}", e);
+        StatusCode::UNAUTHORIZED
+    })?;
+
+    // Reject tokens that were logged out, or whose session was revoked
+    let revoked = database
+        .is_token_revoked(&claims.jti, &claims.sid)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to check token revocation: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    if revoked {
+        tracing::warn!("Rejected revoked token for user: {}", claims.user_id);
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    tracing::debug!("User authenticated: {}", claims.user_id);
+
+    // Keep the session's last-seen time and address fresh for the sessions list
+    let ip_address = peer.map(|ip| {
+        app_state
+            .trusted_proxies
+            .client_ip(ip, &parts.headers)
+            .to_string()
+    });
+    if let Err(e) = database
+        .touch_session(&claims.sid, ip_address.as_deref())
+        .await
+    {
+        tracing::warn!("Failed to update session last-seen time: {:?}", e);
+    }
+
+    Ok(AuthUser {
+        user_id: claims.user_id,
+        email: claims.email,
+        session_id: claims.sid,
+        token_id: claims.jti,
+        token_expires_at: claims.exp,
+        api_token_id: None,
+    })
+}
+
+/// Sign in the user named in the proxy's headers, provisioning them on first sight
+/// The headers are only believed when the request comes straight from a trusted proxy
+async fn authenticate_proxy_user(
+    app_state: &AppState,
+    proxy: &ProxyAuthConfig,
+    peer: Option<IpAddr>,
+    headers: &HeaderMap,
+) -> Result<AuthUser, StatusCode> {
+    let database = &app_state.database;
+    match peer {
+        Some(ip) if proxy.is_trusted(&ip) => {}
+        _ => {
+            tracing::warn!("Rejected request from untrusted proxy: {:?}", peer);
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    let identity = proxy.identity(headers).ok_or_else(|| {
+        tracing::warn!("Trusted proxy sent no {} header", proxy.user_header);
+        StatusCode::UNAUTHORIZED
+    })?;
+
+    let linked = database
+        .get_user_by_identity(proxy_auth::IDENTITY_ISSUER, &identity.username)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to look up proxy identity: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let user = match linked {
+        Some(user) => user,
+        None => {
+            let email = identity.email.clone().ok_or_else(|| {
+                tracing::warn!(
+                    "Can't provision proxy user {} without a {} header",
+                    identity.username,
+                    proxy.email_header
+                );
+                StatusCode::UNAUTHORIZED
+            })?;
+            let user = match database.get_user_by_email(&email).await {
+                Ok(user) => user,
+                Err(_) => crate::provision_user(app_state, email).await?,
+            };
+            database
+                .link_identity(
+                    proxy_auth::IDENTITY_ISSUER,
+                    &identity.username,
+                    &user.id,
+                    identity.email.as_deref(),
+                )
+                .await
+                .map_err(|e| {
+                    tracing::error!("Failed to link proxy identity: {:?}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })?;
+            crate::record_audit(
+                database,
+                database::AuditEvent::new(&user.id, "user", &user.id, "login")
+                    .after(&serde_json::json!({ "proxy_user": identity.username })),
+            )
+            .await;
+            user
+        }
+    };
+
+    if user.disabled_at.is_some() {
+        tracing::warn!("Rejected proxy sign-in for disabled user {}", user.id);
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    tracing::debug!("User authenticated by proxy: {}", user.id);
+    Ok(AuthUser {
+        user_id: user.id,
+        email: user.email,
+        session_id: String::new(),
+        token_id: String::new(),
+        token_expires_at: 0,
+        api_token_id: None,
+    })
+}
+
+/// Authenticate a personal API token, which only reaches endpoints covered by its scopes
+async fn authenticate_api_token(
+    database: &Database,
+    method: &Method,
+    path: &str,
+    token: &str,
+) -> Result<AuthUser, StatusCode> {
+    let (api_token, email) = database
+        .use_api_token(&tokens::hash(token))
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to look up API token: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or_else(|| {
+            tracing::warn!("Rejected unknown or expired API token");
+            StatusCode::UNAUTHORIZED
+        })?;
+
+    let allowed = api_tokens::required_scope(method, path)
+        .is_some_and(|scope| api_token.scopes.split_whitespace().any(|s| s == scope));
+    if !allowed {
+        tracing::warn!(
+            "API token {} isn't allowed to {} {}",
+            api_token.id,
+            method,
+            path
+        );
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    tracing::debug!("User authenticated with API token: {}", api_token.user_id);
+    Ok(AuthUser {
+        user_id: api_token.user_id,
+        email,
+        session_id: String::new(),
+        token_id: String::new(),
+        token_expires_at: 0,
+        api_token_id: Some(api_token.id),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{create_test_user, test_app_state};
+    use axum::http::Request;
+    use std::sync::Arc;
+
+    /// Request parts as the extractor sees them, coming from `peer`
+    fn request(method: Method, path: &str, headers: &[(&str, &str)], peer: &str) -> Parts {
+        let mut builder = Request::builder().method(method).uri(path);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        let peer: SocketAddr = format!("{}:50000", peer).parse().unwrap();
+        builder
+            .extension(ConnectInfo(peer))
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0
+    }
+
+    fn bearer(token: &str) -> String {
+        format!("Bearer {}", token)
+    }
+
+    async fn authenticate_bearer(
+        app_state: &AppState,
+        token: &str,
+    ) -> Result<AuthUser, StatusCode> {
+        let parts = request(
+            Method::GET,
+            "/user",
+            &[("Authorization", &bearer(token))],
+            "127.0.0.1",
+        );
+        authenticate(&parts, app_state).await
+    }
+
+    /// Sign a user in the way the login handlers do, returning their access token
+    async fn sign_in(app_state: &AppState, email: &str) -> (database::User, String) {
+        let user = create_test_user(&app_state.database, email).await;
+        let device = database::SessionDevice {
+            device_label: None,
+            user_agent: None,
+            ip_address: None,
+        };
+        let (token, _) = crate::start_session(app_state, &user, &device)
+            .await
+            .unwrap();
+        (user, token)
+    }
+
+    #[tokio::test]
+    async fn accepts_a_signed_in_users_jwt() {
+        let app_state = test_app_state().await;
+        let (user, token) = sign_in(&app_state, "a@example.com").await;
+
+        let auth_user = authenticate_bearer(&app_state, &token).await.unwrap();
+        assert_eq!(auth_user.user_id, user.id);
+        assert_eq!(auth_user.email, user.email);
+        assert!(!auth_user.session_id.is_empty());
+        assert_eq!(auth_user.api_token_id, None);
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_and_forged_jwts() {
+        let app_state = test_app_state().await;
+        let (user, _) = sign_in(&app_state, "a@example.com").await;
+
+        let parts = request(Method::GET, "/user", &[], "127.0.0.1");
+        assert_eq!(
+            authenticate(&parts, &app_state).await.unwrap_err(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        // The same user, signed by another instance's key
+        let other = test_app_state().await;
+        let forged = other
+            .jwt_keys
+            .generate_jwt(&user.id, &user.email, "session")
+            .unwrap();
+        assert_eq!(
+            authenticate_bearer(&app_state, &forged).await.unwrap_err(),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_a_revoked_jti() {
+        let app_state = test_app_state().await;
+        let (_, token) = sign_in(&app_state, "a@example.com").await;
+        let claims = app_state.jwt_keys.validate_jwt(&token).unwrap();
+
+        app_state
+            .database
+            .revoke_token(&claims.jti, &chrono::Utc::now())
+            .await
+            .unwrap();
+        assert_eq!(
+            authenticate_bearer(&app_state, &token).await.unwrap_err(),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_tokens_from_a_revoked_session() {
+        let app_state = test_app_state().await;
+        let (_, token) = sign_in(&app_state, "a@example.com").await;
+        let claims = app_state.jwt_keys.validate_jwt(&token).unwrap();
+
+        app_state
+            .database
+            .revoke_session(&claims.sid)
+            .await
+            .unwrap();
+        assert_eq!(
+            authenticate_bearer(&app_state, &token).await.unwrap_err(),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn api_tokens_only_reach_their_scopes() {
+        let app_state = test_app_state().await;
+        let user = create_test_user(&app_state.database, "a@example.com").await;
+        let token = api_tokens::generate();
+        let api_token = app_state
+            .database
+            .create_api_token(
+                &user.id,
+                "read only",
+                &tokens::hash(&token),
+                "links:read",
+                None,
+            )
+            .await
+            .unwrap();
+        let authorization = bearer(&token);
+
+        let parts = request(
+            Method::GET,
+            "/user/links",
+            &[("Authorization", &authorization)],
+            "127.0.0.1",
+        );
+        let auth_user = authenticate(&parts, &app_state).await.unwrap();
+        assert_eq!(auth_user.user_id, user.id);
+        assert_eq!(auth_user.api_token_id, Some(api_token.id));
+
+        for (method, path) in [
+            (Method::POST, "/link"),
+            (Method::DELETE, "/link/some-id"),
+            (Method::GET, "/user"),
+            (Method::POST, "/api_tokens"),
+        ] {
+            let parts = request(
+                method.clone(),
+                path,
+                &[("Authorization", &authorization)],
+                "127.0.0.1",
+            );
+            assert_eq!(
+                authenticate(&parts, &app_state).await.unwrap_err(),
+                StatusCode::FORBIDDEN,
+                "{} {}",
+                method,
+                path
+            );
+        }
+
+        assert_eq!(
+            authenticate_bearer(&app_state, &api_tokens::generate())
+                .await
+                .unwrap_err(),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn proxy_headers_only_count_from_trusted_proxies() {
+        let mut app_state = test_app_state().await;
+        app_state.proxy_auth = Some(Arc::new(ProxyAuthConfig {
+            user_header: "Remote-User".to_string(),
+            email_header: "Remote-Email".to_string(),
+            trusted_proxies: vec![proxy_auth::TrustedNetwork::parse("10.0.0.2").unwrap()],
+        }));
+        let headers = [
+            ("Remote-User", "alice"),
+            ("Remote-Email", "alice@example.com"),
+        ];
+
+        let parts = request(Method::GET, "/user", &headers, "10.0.0.9");
+        assert_eq!(
+            authenticate(&parts, &app_state).await.unwrap_err(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        let parts = request(Method::GET, "/user", &[], "10.0.0.2");
+        assert_eq!(
+            authenticate(&parts, &app_state).await.unwrap_err(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        let parts = request(Method::GET, "/user", &headers, "10.0.0.2");
+        let auth_user = authenticate(&parts, &app_state).await.unwrap();
+        assert_eq!(auth_user.email, "alice@example.com");
+
+        // Known by their proxy username from then on
+        let parts = request(
+            Method::GET,
+            "/user",
+            &[("Remote-User", "alice")],
+            "10.0.0.2",
+        );
+        let again = authenticate(&parts, &app_state).await.unwrap();
+        assert_eq!(again.user_id, auth_user.user_id);
+    }
+
+    #[tokio::test]
+    async fn local_mode_signs_every_request_in_as_the_local_user() {
+        let mut app_state = test_app_state().await;
+        let local_user = crate::ensure_local_user(&app_state).await.unwrap();
+        app_state.local_user = Some(local_user.clone());
+
+        let parts = request(Method::GET, "/user", &[], "127.0.0.1");
+        let auth_user = authenticate(&parts, &app_state).await.unwrap();
+        assert_eq!(auth_user.user_id, local_user.user_id);
+
+        let auth_user = authenticate_bearer(&app_state, "not-a-token")
+            .await
+            .unwrap();
+        assert_eq!(auth_user.user_id, local_user.user_id);
+    }
+
+    #[test]
+    fn only_local_hosts_reach_local_mode() {
+        for host in [
+            "localhost",
+            "localhost:3000",
+            "LOCALHOST",
+            "127.0.0.1:3000",
+            "[::1]:3000",
+        ] {
+            assert!(is_local_host(host), "{}", host);
+        }
+        for host in [
+            "evil.example.com",
+            "evil.example.com:3000",
+            "192.168.1.20:3000",
+            "localhost.evil.example.com",
+            "",
+        ] {
+            assert!(!is_local_host(host), "{}", host);
+        }
+    }
+}
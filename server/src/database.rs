@@ -2,7 +2,7 @@ use std::collections::HashMap;
 use std::path::PathBuf;
 
 use anyhow::Result;
-use bcrypt::{hash, verify, DEFAULT_COST};
+use bcrypt::DEFAULT_COST;
 use chrono::{DateTime, Utc};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
@@ -11,6 +11,8 @@ use sqlx::{
     FromRow, Row,
 };
 
+use crate::link_url;
+
 // Type definitions matching Database.ts
 #[derive(Serialize, Deserialize, Debug, Clone, FromRow)]
 pub struct User {
@@ -19,11 +21,29 @@ pub struct User {
     #[serde(skip_serializing)]
     pub password_hash: String,
     pub created_at: String,
+    #[sqlx(default)]
+    #[serde(default)]
+    pub email_verified_at: Option<String>,
+    /// Instance administrator, the first user to register becomes one
+    #[sqlx(default)]
+    #[serde(default)]
+    pub is_admin: bool,
+    /// Disabled accounts can't sign in
+    #[sqlx(default)]
+    #[serde(default)]
+    pub disabled_at: Option<String>,
     #[sqlx(skip)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub auth_token: Option<String>,
 }
 
+/// What a single-use emailed token can be redeemed for
+pub const TOKEN_PURPOSE_VERIFY_EMAIL: &str = "verify_email";
+pub const TOKEN_PURPOSE_RESET_PASSWORD: &str = "reset_password";
+pub const TOKEN_PURPOSE_CHANGE_EMAIL: &str = "change_email";
+/// Issued after a correct password when the account has 2FA, redeemed with a TOTP or recovery code
+pub const TOKEN_PURPOSE_TWO_FACTOR_LOGIN: &str = "two_factor_login";
+
 #[derive(Debug, Serialize, Deserialize, Clone, FromRow)]
 pub struct Link {
     pub id: String,
@@ -36,6 +56,30 @@ pub struct Link {
     pub created_at: String,
     pub description: Option<String>,
     pub column_type: String,
+    #[sqlx(default)]
+    #[serde(default)]
+    pub canonical_url: Option<String>,
+    #[sqlx(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, FromRow)]
+pub struct LinkRevision {
+    pub id: String,
+    pub link_id: String,
+    pub title: String,
+    pub url: String,
+    pub icon: Option<String>,
+    pub description: Option<String>,
+    pub column_type: String,
+    pub created_at: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DuplicateLinkGroup {
+    pub canonical_url: String,
+    pub links: Vec<Link>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, FromRow)]
@@ -67,6 +111,31 @@ pub struct UserMembership {
     pub created_at: String,
 }
 
+/// An organization or team, with the user's role when listed for them
+#[derive(Debug, Serialize, Deserialize, Clone, FromRow)]
+pub struct Workspace {
+    pub id: String,
+    pub name: String,
+    pub owner_id: Option<String>,
+    /// Organization a team belongs to, never set for organizations
+    #[sqlx(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub organization_id: Option<String>,
+    pub created_at: String,
+    #[sqlx(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+}
+
+/// A workspace membership with the member's email, for listing who's in it
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct Member {
+    pub user_id: String,
+    pub email: String,
+    pub role: String,
+    pub created_at: String,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, FromRow)]
 pub struct UserSettings {
     pub user_id: String,
@@ -74,6 +143,160 @@ pub struct UserSettings {
     pub created_at: String,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, FromRow)]
+pub struct Session {
+    pub id: String,
+    pub user_id: String,
+    #[serde(skip_serializing)]
+    pub refresh_token_hash: String,
+    #[serde(skip_serializing)]
+    pub previous_refresh_token_hash: Option<String>,
+    pub rotated_at: Option<String>,
+    pub created_at: String,
+    pub expires_at: String,
+    pub revoked_at: Option<String>,
+    #[sqlx(default)]
+    pub device_label: Option<String>,
+    #[sqlx(default)]
+    pub user_agent: Option<String>,
+    #[sqlx(default)]
+    pub ip_address: Option<String>,
+    #[sqlx(default)]
+    pub last_seen_at: Option<String>,
+}
+
+/// A user's TOTP secret, pending until `enabled_at` is set
+#[derive(Debug, Clone, FromRow)]
+pub struct UserTotp {
+    pub user_id: String,
+    pub secret: String,
+    pub enabled_at: Option<String>,
+    pub last_used_step: Option<i64>,
+    pub created_at: String,
+}
+
+/// A pending single sign-on, linking the provider account to `link_user_id` when it's set
+#[derive(Debug, Clone, FromRow)]
+pub struct OidcLoginState {
+    pub code_verifier: String,
+    pub nonce: String,
+    pub link_user_id: Option<String>,
+}
+
+/// A registered passkey, the credential itself stays server-side
+#[derive(Debug, Serialize, Deserialize, Clone, FromRow)]
+pub struct PasskeyCredential {
+    pub id: String,
+    #[serde(skip_serializing)]
+    pub user_id: String,
+    #[serde(skip_serializing)]
+    pub credential_id: String,
+    pub name: String,
+    #[serde(skip_serializing)]
+    pub passkey_json: String,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+}
+
+/// A personal API token, the token itself is only shown once when it's created
+#[derive(Debug, Serialize, Deserialize, Clone, FromRow)]
+pub struct ApiToken {
+    pub id: String,
+    #[serde(skip_serializing)]
+    pub user_id: String,
+    pub name: String,
+    #[serde(skip_serializing)]
+    pub token_hash: String,
+    pub scopes: String,
+    pub created_at: String,
+    pub expires_at: Option<String>,
+    pub last_used_at: Option<String>,
+}
+
+/// An invite to register, the code itself is only shown once when it's created
+#[derive(Debug, Serialize, Deserialize, Clone, FromRow)]
+pub struct Invite {
+    pub id: String,
+    #[serde(skip_serializing)]
+    pub code_hash: String,
+    pub created_by: String,
+    pub organization_id: Option<String>,
+    pub role: String,
+    pub email: Option<String>,
+    pub max_uses: Option<i64>,
+    pub uses: i64,
+    pub created_at: String,
+    pub expires_at: Option<String>,
+}
+
+/// Instance-wide counts for the admin dashboard
+#[derive(Debug, Serialize, FromRow)]
+pub struct InstanceStats {
+    pub users: i64,
+    pub admins: i64,
+    pub disabled_users: i64,
+    pub new_users_last_30_days: i64,
+    pub links: i64,
+    pub trashed_links: i64,
+    pub organizations: i64,
+    pub teams: i64,
+    pub active_sessions: i64,
+    pub api_tokens: i64,
+}
+
+/// Where a session was started from, shown in the active sessions list
+#[derive(Debug, Clone, Default)]
+pub struct SessionDevice {
+    pub device_label: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, FromRow)]
+pub struct AuditEvent {
+    pub id: String,
+    pub actor_id: String,
+    pub organization_id: Option<String>,
+    pub entity_type: String,
+    pub entity_id: String,
+    pub action: String,
+    pub before_json: Option<String>,
+    pub after_json: Option<String>,
+    pub created_at: String,
+}
+
+impl AuditEvent {
+    pub fn new(actor_id: &str, entity_type: &str, entity_id: &str, action: &str) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            actor_id: actor_id.to_string(),
+            organization_id: None,
+            entity_type: entity_type.to_string(),
+            entity_id: entity_id.to_string(),
+            action: action.to_string(),
+            before_json: None,
+            after_json: None,
+            created_at: Utc::now().to_rfc3339(),
+        }
+    }
+
+    /// Attribute the event to an organization so its admins can see it
+    pub fn in_organization(mut self, organization_id: Option<&str>) -> Self {
+        self.organization_id = organization_id.map(|id| id.to_string());
+        self
+    }
+
+    pub fn before<T: Serialize>(mut self, before: &T) -> Self {
+        self.before_json = serde_json::to_string(before).ok();
+        self
+    }
+
+    pub fn after<T: Serialize>(mut self, after: &T) -> Self {
+        self.after_json = serde_json::to_string(after).ok();
+        self
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UserData {
     pub user: User,
@@ -89,7 +312,7 @@ pub struct Database {
 }
 
 /// Get the platform-appropriate data directory for storing the database
-fn get_data_dir() -> PathBuf {
+pub fn get_data_dir() -> PathBuf {
     if let Some(data_dir) = dirs::data_local_dir() {
         data_dir.join("omega-tab")
     } else {
@@ -147,6 +370,22 @@ fn migrate_legacy_database(new_data_dir: &PathBuf) -> Result<()> {
     Ok(())
 }
 
+/// bcrypt is deliberately slow, so it runs on the blocking pool instead of stalling the runtime
+async fn hash_password(password: &str) -> Result<String> {
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || bcrypt::hash(password, DEFAULT_COST))
+        .await?
+        .map_err(|e| anyhow::anyhow!("Failed to hash password: {}", e))
+}
+
+async fn verify_password_hash(password: &str, password_hash: &str) -> Result<bool> {
+    let password = password.to_string();
+    let password_hash = password_hash.to_string();
+    tokio::task::spawn_blocking(move || bcrypt::verify(password, &password_hash))
+        .await?
+        .map_err(|e| anyhow::anyhow!("Failed to verify password: {}", e))
+}
+
 impl Database {
     pub async fn new(_database_url: String) -> Result<Self> {
         // Create data directory if it doesn't exist
@@ -177,6 +416,30 @@ impl Database {
 
         tracing::info!("Database migrations completed successfully");
 
+        let database = Self {
+            client: Client::new(),
+            pool,
+        };
+
+        if let Err(e) = database.backfill_canonical_urls().await {
+            tracing::warn!("Failed to backfill canonical URLs: {}", e);
+        }
+
+        Ok(database)
+    }
+
+    /// A migrated database that only lives as long as the test using it
+    #[cfg(test)]
+    pub async fn in_memory() -> Result<Self> {
+        // Each connection to :memory: is its own database, so keep exactly one open
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect("sqlite::memory:")
+            .await?;
+        sqlx::migrate!("./migrations_sqlite").run(&pool).await?;
+
         Ok(Self {
             client: Client::new(),
             pool,
@@ -233,6 +496,22 @@ impl Database {
 
     // Password authentication methods
     pub async fn register_user(&self, email: &str, password: &str) -> Result<User> {
+        self.insert_registered_user(email, password, false)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("500"))
+    }
+
+    /// Register the instance's first account, or None when someone else already has
+    pub async fn register_first_user(&self, email: &str, password: &str) -> Result<Option<User>> {
+        self.insert_registered_user(email, password, true).await
+    }
+
+    async fn insert_registered_user(
+        &self,
+        email: &str,
+        password: &str,
+        only_first: bool,
+    ) -> Result<Option<User>> {
         tracing::info!("Registering new user: {}", email);
 
         // Check if user already exists
@@ -242,38 +521,47 @@ impl Database {
         }
 
         // Hash password
-        let password_hash = hash(password, DEFAULT_COST)
-            .map_err(|e| anyhow::anyhow!("Failed to hash password: {}", e))?;
+        let password_hash = hash_password(password).await?;
 
         // Generate user ID
         let user_id = format!("user_{}", uuid::Uuid::new_v4());
         let created_at = Utc::now().to_rfc3339();
 
-        // Insert user
-        let result = sqlx::query(
-            "INSERT INTO users (id, email, password_hash, created_at) VALUES (?, ?, ?, ?)",
-        )
-        .bind(&user_id)
-        .bind(email)
-        .bind(&password_hash)
-        .bind(&created_at)
-        .execute(&self.pool)
-        .await?;
-
-        if result.rows_affected() == 0 {
-            tracing::error!("Failed to create user: {}", email);
-            return Err(anyhow::anyhow!("Failed to create user"));
+        let user = self
+            .insert_user(&user_id, email, &password_hash, &created_at, only_first)
+            .await?;
+        if user.is_some() {
+            tracing::info!("Successfully registered user: {}", email);
         }
 
-        tracing::info!("Successfully registered user: {}", email);
+        Ok(user)
+    }
 
-        Ok(User {
-            id: user_id,
-            email: email.to_string(),
-            password_hash,
-            created_at,
-            auth_token: None,
-        })
+    /// Insert a user, the first one becomes the instance admin
+    /// With `only_first` nothing is inserted once there are users, checked in the same statement
+    /// so concurrent sign-ups can't both be first
+    async fn insert_user(
+        &self,
+        id: &str,
+        email: &str,
+        password_hash: &str,
+        created_at: &str,
+        only_first: bool,
+    ) -> Result<Option<User>> {
+        let user = sqlx::query_as::<_, User>(
+            "INSERT INTO users (id, email, password_hash, created_at, is_admin)
+             SELECT ?, ?, ?, ?, NOT EXISTS (SELECT 1 FROM users)
+             WHERE NOT ? OR NOT EXISTS (SELECT 1 FROM users)
+             RETURNING *",
+        )
+        .bind(id)
+        .bind(email)
+        .bind(password_hash)
+        .bind(created_at)
+        .bind(only_first)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(user)
     }
 
     pub async fn verify_password(&self, email: &str, password: &str) -> Result<User> {
@@ -281,8 +569,7 @@ impl Database {
 
         let user = self.get_user_by_email(email).await?;
 
-        let is_valid = verify(password, &user.password_hash)
-            .map_err(|e| anyhow::anyhow!("Failed to verify password: {}", e))?;
+        let is_valid = verify_password_hash(password, &user.password_hash).await?;
 
         if !is_valid {
             tracing::warn!("Invalid password for user: {}", email);
@@ -293,27 +580,199 @@ impl Database {
         Ok(user)
     }
 
-    pub async fn create_user(&self, user: User) -> Result<User> {
-        tracing::info!("Creating new user: {}", user.email);
+    /// Replace a user's password with a bcrypt hash of the new one
+    pub async fn update_password(&self, user_id: &str, password: &str) -> Result<()> {
+        tracing::info!("Updating password for user: {}", user_id);
 
-        // Insert new user
-        let result = sqlx::query(
-            "INSERT INTO users (id, email, password_hash, created_at) VALUES (?, ?, ?, ?)",
+        let password_hash = hash_password(password).await?;
+
+        let result = sqlx::query("UPDATE users SET password_hash = ? WHERE id = ?")
+            .bind(&password_hash)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(anyhow::anyhow!("404"));
+        }
+
+        tracing::info!("Successfully updated password for user: {}", user_id);
+        Ok(())
+    }
+
+    pub async fn mark_email_verified(&self, user_id: &str) -> Result<()> {
+        tracing::info!("Marking email verified for user: {}", user_id);
+
+        sqlx::query("UPDATE users SET email_verified_at = ? WHERE id = ?")
+            .bind(Utc::now().to_rfc3339())
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Store a new single-use token, replacing any unused token the user has for the same purpose
+    /// Email change tokens carry the address they confirm in `new_email`
+    pub async fn create_auth_token(
+        &self,
+        user_id: &str,
+        purpose: &str,
+        token_hash: &str,
+        expires_at: &DateTime<Utc>,
+        new_email: Option<&str>,
+    ) -> Result<()> {
+        tracing::info!("Creating {} token for user: {}", purpose, user_id);
+
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query(
+            "DELETE FROM auth_tokens WHERE user_id = ? AND purpose = ? AND used_at IS NULL",
         )
-        .bind(&user.id)
-        .bind(&user.email)
-        .bind(&user.password_hash)
-        .bind(&user.created_at)
-        .execute(&self.pool)
+        .bind(user_id)
+        .bind(purpose)
+        .execute(&mut *tx)
         .await?;
 
-        if result.rows_affected() == 0 {
-            tracing::error!("Failed to create user: {}", user.email);
-            return Err(anyhow::anyhow!("Failed to create user: database error"));
+        sqlx::query(
+            "INSERT INTO auth_tokens (id, user_id, purpose, token_hash, expires_at, created_at, new_email)
+             VALUES (?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(uuid::Uuid::new_v4().to_string())
+        .bind(user_id)
+        .bind(purpose)
+        .bind(token_hash)
+        .bind(expires_at.to_rfc3339())
+        .bind(Utc::now().to_rfc3339())
+        .bind(new_email)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Redeem a single-use token, returning the user it was issued to
+    /// Fails if the token doesn't exist, was already used, or has expired
+    pub async fn consume_auth_token(&self, purpose: &str, token_hash: &str) -> Result<String> {
+        tracing::info!("Consuming {} token", purpose);
+
+        let now = Utc::now().to_rfc3339();
+        let user_id: Option<String> = sqlx::query_scalar(
+            "UPDATE auth_tokens SET used_at = ?
+             WHERE token_hash = ? AND purpose = ? AND used_at IS NULL AND expires_at > ?
+             RETURNING user_id",
+        )
+        .bind(&now)
+        .bind(token_hash)
+        .bind(purpose)
+        .bind(&now)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match user_id {
+            Some(user_id) => Ok(user_id),
+            None => {
+                tracing::info!("Invalid, used or expired {} token", purpose);
+                Err(anyhow::anyhow!("Invalid or expired token"))
+            }
         }
+    }
 
-        tracing::info!("Successfully created user: {}", user.email);
-        Ok(user)
+    /// Look up who an unexpired, unused token belongs to without redeeming it
+    pub async fn peek_auth_token(&self, purpose: &str, token_hash: &str) -> Result<String> {
+        let user_id: Option<String> = sqlx::query_scalar(
+            "SELECT user_id FROM auth_tokens
+             WHERE token_hash = ? AND purpose = ? AND used_at IS NULL AND expires_at > ?",
+        )
+        .bind(token_hash)
+        .bind(purpose)
+        .bind(Utc::now().to_rfc3339())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        user_id.ok_or_else(|| anyhow::anyhow!("Invalid or expired token"))
+    }
+
+    /// Redeem an email change token, returning who it was issued to and the address it confirms
+    pub async fn consume_email_change_token(&self, token_hash: &str) -> Result<(String, String)> {
+        tracing::info!("Consuming email change token");
+
+        let now = Utc::now().to_rfc3339();
+        let change: Option<(String, Option<String>)> = sqlx::query_as(
+            "UPDATE auth_tokens SET used_at = ?
+             WHERE token_hash = ? AND purpose = ? AND used_at IS NULL AND expires_at > ?
+             RETURNING user_id, new_email",
+        )
+        .bind(&now)
+        .bind(token_hash)
+        .bind(TOKEN_PURPOSE_CHANGE_EMAIL)
+        .bind(&now)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match change {
+            Some((user_id, Some(new_email))) => Ok((user_id, new_email)),
+            _ => {
+                tracing::info!("Invalid, used or expired email change token");
+                Err(anyhow::anyhow!("Invalid or expired token"))
+            }
+        }
+    }
+
+    /// Switch a user to a confirmed new email address
+    pub async fn update_email(&self, user_id: &str, email: &str) -> Result<()> {
+        tracing::info!("Updating email for user: {}", user_id);
+
+        let existing = sqlx::query("SELECT id FROM users WHERE email = ? AND id != ?")
+            .bind(email)
+            .bind(user_id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        if existing.is_some() {
+            tracing::warn!("User already exists: {}", email);
+            return Err(anyhow::anyhow!("User already exists"));
+        }
+
+        sqlx::query("UPDATE users SET email = ?, email_verified_at = ? WHERE id = ?")
+            .bind(email)
+            .bind(Utc::now().to_rfc3339())
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        tracing::info!("Successfully updated email for user: {}", user_id);
+        Ok(())
+    }
+
+    pub async fn create_user(&self, user: User) -> Result<User> {
+        self.create_user_if(user, false)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("500"))
+    }
+
+    /// Create the instance's first account, or None when someone else already has
+    pub async fn create_first_user(&self, user: User) -> Result<Option<User>> {
+        self.create_user_if(user, true).await
+    }
+
+    async fn create_user_if(&self, user: User, only_first: bool) -> Result<Option<User>> {
+        tracing::info!("Creating new user: {}", user.email);
+
+        let created = self
+            .insert_user(
+                &user.id,
+                &user.email,
+                &user.password_hash,
+                &user.created_at,
+                only_first,
+            )
+            .await?;
+        if created.is_some() {
+            tracing::info!("Successfully created user: {}", user.email);
+        }
+        Ok(created)
     }
 
     pub async fn delete_user(&self, id: &str) -> Result<()> {
@@ -333,218 +792,1624 @@ impl Database {
         Ok(())
     }
 
-    // Links
-    pub async fn get_links(&self, owner_id: &str, owner_type: &str) -> Result<Vec<Link>> {
-        tracing::info!("Fetching links for owner {}: {}", owner_type, owner_id);
+    // Two-factor authentication
+    pub async fn get_user_totp(&self, user_id: &str) -> Result<Option<UserTotp>> {
+        let totp = sqlx::query_as::<_, UserTotp>("SELECT * FROM user_totp WHERE user_id = ?")
+            .bind(user_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(totp)
+    }
 
-        let links = sqlx::query_as::<_, Link>(
-            "SELECT * FROM links WHERE owner_id = ? AND owner_type = ?",
+    /// Store a new pending secret, replacing any earlier enrollment that was never confirmed
+    pub async fn start_totp_enrollment(&self, user_id: &str, secret: &str) -> Result<()> {
+        tracing::info!("Starting TOTP enrollment for user: {}", user_id);
+
+        sqlx::query(
+            "INSERT INTO user_totp (user_id, secret, created_at) VALUES (?, ?, ?)
+             ON CONFLICT(user_id) DO UPDATE SET secret = excluded.secret, created_at = excluded.created_at
+             WHERE user_totp.enabled_at IS NULL",
         )
-        .bind(owner_id)
-        .bind(owner_type)
-        .fetch_all(&self.pool)
+        .bind(user_id)
+        .bind(secret)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&self.pool)
         .await?;
 
-        tracing::info!("Successfully fetched {} links", links.len());
-        Ok(links)
+        Ok(())
     }
 
-    pub async fn get_link(&self, id: &str, owner_id: &str) -> Result<Link> {
-        tracing::info!("Fetching link: {} for owner: {}", id, owner_id);
+    /// Turn on 2FA once the first code checks out, replacing any old recovery codes
+    pub async fn enable_totp(
+        &self,
+        user_id: &str,
+        step: u64,
+        recovery_code_hashes: &[String],
+    ) -> Result<()> {
+        tracing::info!("Enabling TOTP for user: {}", user_id);
 
-        let link = sqlx::query_as::<_, Link>("SELECT * FROM links WHERE id = ? AND owner_id = ?")
-            .bind(id)
-            .bind(owner_id)
-            .fetch_optional(&self.pool)
-            .await?;
+        let mut tx = self.pool.begin().await?;
 
-        match link {
-            Some(link) => {
-                tracing::info!("Successfully fetched link");
-                Ok(link)
-            }
-            None => {
-                tracing::info!("Link not found: {}", id);
-                Err(anyhow::anyhow!("Link not found"))
+        sqlx::query("UPDATE user_totp SET enabled_at = ?, last_used_step = ? WHERE user_id = ?")
+            .bind(Utc::now().to_rfc3339())
+            .bind(step as i64)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query("DELETE FROM recovery_codes WHERE user_id = ?")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        for code_hash in recovery_code_hashes {
+            sqlx::query(
+                "INSERT INTO recovery_codes (id, user_id, code_hash, created_at) VALUES (?, ?, ?, ?)",
+            )
+            .bind(uuid::Uuid::new_v4().to_string())
+            .bind(user_id)
+            .bind(code_hash)
+            .bind(Utc::now().to_rfc3339())
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Record the time step of an accepted code
+    /// Returns false if that step (or a later one) was already used, which means the code is a replay
+    pub async fn record_totp_step(&self, user_id: &str, step: u64) -> Result<bool> {
+        let result = sqlx::query(
+            "UPDATE user_totp SET last_used_step = ?
+             WHERE user_id = ? AND (last_used_step IS NULL OR last_used_step < ?)",
+        )
+        .bind(step as i64)
+        .bind(user_id)
+        .bind(step as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Use up a recovery code, returning whether it was valid
+    pub async fn consume_recovery_code(&self, user_id: &str, code_hash: &str) -> Result<bool> {
+        let result = sqlx::query(
+            "UPDATE recovery_codes SET used_at = ?
+             WHERE user_id = ? AND code_hash = ? AND used_at IS NULL",
+        )
+        .bind(Utc::now().to_rfc3339())
+        .bind(user_id)
+        .bind(code_hash)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    pub async fn count_unused_recovery_codes(&self, user_id: &str) -> Result<i64> {
+        let count: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM recovery_codes WHERE user_id = ? AND used_at IS NULL",
+        )
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(count)
+    }
+
+    pub async fn disable_totp(&self, user_id: &str) -> Result<()> {
+        tracing::info!("Disabling TOTP for user: {}", user_id);
+
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query("DELETE FROM user_totp WHERE user_id = ?")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query("DELETE FROM recovery_codes WHERE user_id = ?")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Delete a user and everything they own in one transaction
+    /// Links don't reference their owner with a foreign key, so they're purged explicitly,
+    /// along with the organizations and teams the user owns and everything those own
+    pub async fn delete_account(&self, user_id: &str) -> Result<()> {
+        tracing::info!("Deleting account and data for user: {}", user_id);
+
+        let mut tx = self.pool.begin().await?;
+
+        let organization_ids: Vec<String> =
+            sqlx::query_scalar("SELECT id FROM organizations WHERE owner_id = ?")
+                .bind(user_id)
+                .fetch_all(&mut *tx)
+                .await?;
+
+        let mut team_ids: Vec<String> =
+            sqlx::query_scalar("SELECT id FROM teams WHERE owner_id = ?")
+                .bind(user_id)
+                .fetch_all(&mut *tx)
+                .await?;
+        for organization_id in &organization_ids {
+            let org_team_ids: Vec<String> =
+                sqlx::query_scalar("SELECT id FROM teams WHERE organization_id = ?")
+                    .bind(organization_id)
+                    .fetch_all(&mut *tx)
+                    .await?;
+            team_ids.extend(org_team_ids);
+        }
+        team_ids.sort();
+        team_ids.dedup();
+
+        let mut owners: Vec<(&str, &str)> = vec![(user_id, "user")];
+        owners.extend(
+            organization_ids
+                .iter()
+                .map(|id| (id.as_str(), "organization")),
+        );
+        owners.extend(team_ids.iter().map(|id| (id.as_str(), "team")));
+
+        // Links and subscriptions of every owner, plus everyone's memberships in deleted orgs and teams
+        for (owner_id, owner_type) in &owners {
+            sqlx::query("DELETE FROM links WHERE owner_id = ? AND owner_type = ?")
+                .bind(owner_id)
+                .bind(owner_type)
+                .execute(&mut *tx)
+                .await?;
+
+            sqlx::query("DELETE FROM subscriptions WHERE entity_id = ? AND entity_type = ?")
+                .bind(owner_id)
+                .bind(owner_type)
+                .execute(&mut *tx)
+                .await?;
+
+            if *owner_type != "user" {
+                sqlx::query("DELETE FROM user_memberships WHERE entity_id = ? AND entity_type = ?")
+                    .bind(owner_id)
+                    .bind(owner_type)
+                    .execute(&mut *tx)
+                    .await?;
             }
         }
+
+        for team_id in &team_ids {
+            sqlx::query("DELETE FROM teams WHERE id = ?")
+                .bind(team_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        for organization_id in &organization_ids {
+            sqlx::query("DELETE FROM organizations WHERE id = ?")
+                .bind(organization_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        // Audit events outlive the account, but not the snapshots of its data they hold:
+        // the user's own changes, changes made to them and anything else mentioning their email
+        let email: Option<String> = sqlx::query_scalar("SELECT email FROM users WHERE id = ?")
+            .bind(user_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+        let email = email.unwrap_or_default();
+        sqlx::query(
+            "UPDATE audit_events SET before_json = NULL, after_json = NULL
+             WHERE (before_json IS NOT NULL OR after_json IS NOT NULL)
+             AND (actor_id = ? OR entity_id = ?
+                  OR (? != '' AND (instr(before_json, ?) > 0 OR instr(after_json, ?) > 0)))",
+        )
+        .bind(user_id)
+        .bind(user_id)
+        .bind(&email)
+        .bind(&email)
+        .bind(&email)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query("DELETE FROM login_attempts WHERE key = ?")
+            .bind(crate::login_throttle::account_key(&email))
+            .execute(&mut *tx)
+            .await?;
+
+        // Invites bound to the address would otherwise keep it
+        sqlx::query("DELETE FROM invites WHERE lower(email) = lower(?)")
+            .bind(&email)
+            .execute(&mut *tx)
+            .await?;
+
+        for table in ["user_memberships", "user_settings", "feedback_timestamps"] {
+            sqlx::query(&format!("DELETE FROM {} WHERE user_id = ?", table))
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        // Sessions and auth tokens go with the user through ON DELETE CASCADE
+        let result = sqlx::query("DELETE FROM users WHERE id = ?")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            tracing::info!("No user found to delete with ID: {}", user_id);
+            return Err(anyhow::anyhow!("404"));
+        }
+
+        tx.commit().await?;
+
+        tracing::info!(
+            "Deleted user {} with {} organizations and {} teams",
+            user_id,
+            organization_ids.len(),
+            team_ids.len()
+        );
+        Ok(())
+    }
+
+    // Links
+    pub async fn get_links(&self, owner_id: &str, owner_type: &str) -> Result<Vec<Link>> {
+        tracing::info!("Fetching links for owner {}: {}", owner_type, owner_id);
+
+        let links = sqlx::query_as::<_, Link>(
+            "SELECT * FROM links WHERE owner_id = ? AND owner_type = ? AND deleted_at IS NULL",
+        )
+        .bind(owner_id)
+        .bind(owner_type)
+        .fetch_all(&self.pool)
+        .await?;
+
+        tracing::info!("Successfully fetched {} links", links.len());
+        Ok(links)
+    }
+
+    /// Fetch a link whoever owns it, callers check the user may see it
+    pub async fn get_link_by_id(&self, id: &str) -> Result<Link> {
+        let link = sqlx::query_as::<_, Link>("SELECT * FROM links WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        link.ok_or_else(|| anyhow::anyhow!("404"))
     }
 
     pub async fn create_link(&self, link: Link) -> Result<Link> {
         tracing::info!(
-            "Creating new link for owner {}: {}",
-            link.owner_id,
-            link.url
+            "Creating new link for owner {}: {}",
+            link.owner_id,
+            link.url
+        );
+
+        let result = sqlx::query(
+            "INSERT INTO links (id, title, url, icon, order_index, owner_type, owner_id, created_at, description, column_type, canonical_url)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+        )
+        .bind(&link.id)
+        .bind(&link.title)
+        .bind(&link.url)
+        .bind(&link.icon)
+        .bind(&link.order_index)
+        .bind(&link.owner_type)
+        .bind(&link.owner_id)
+        .bind(&link.created_at)
+        .bind(&link.description)
+        .bind(&link.column_type)
+        .bind(&link.canonical_url)
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            tracing::error!("Failed to create link");
+            return Err(anyhow::anyhow!("Failed to create link: database error"));
+        }
+
+        tracing::info!("Successfully created link: {}", link.id);
+        Ok(link)
+    }
+
+    /// Update a link, saving its previous title, URL, description and icon as a revision
+    pub async fn update_link(&self, link: Link) -> Result<()> {
+        tracing::info!("Updating link: {}", link.id);
+
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query(
+            "INSERT INTO link_revisions (id, link_id, title, url, icon, description, column_type, created_at)
+             SELECT ?, id, title, url, icon, description, column_type, ?
+             FROM links WHERE id = ? AND deleted_at IS NULL",
+        )
+        .bind(uuid::Uuid::new_v4().to_string())
+        .bind(Utc::now().to_rfc3339())
+        .bind(&link.id)
+        .execute(&mut *tx)
+        .await?;
+
+        let result = sqlx::query(
+            "UPDATE links
+            SET title = ?, url = ?, icon = ?,
+            order_index = ?, description = ?, column_type = ?,
+            canonical_url = ?
+            WHERE id = ? AND deleted_at IS NULL",
+        )
+        .bind(&link.title)
+        .bind(&link.url)
+        .bind(&link.icon)
+        .bind(&link.order_index)
+        .bind(&link.description)
+        .bind(&link.column_type)
+        .bind(&link.canonical_url)
+        .bind(&link.id)
+        .execute(&mut *tx)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(anyhow::anyhow!("Link not found or update failed"));
+        }
+
+        tx.commit().await?;
+
+        tracing::info!("Successfully updated link: {}", link.id);
+        Ok(())
+    }
+
+    pub async fn get_link_revisions(&self, link_id: &str) -> Result<Vec<LinkRevision>> {
+        tracing::info!("Fetching revisions for link: {}", link_id);
+
+        let revisions = sqlx::query_as::<_, LinkRevision>(
+            "SELECT * FROM link_revisions WHERE link_id = ? ORDER BY created_at DESC",
+        )
+        .bind(link_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        tracing::info!("Successfully fetched {} revisions", revisions.len());
+        Ok(revisions)
+    }
+
+    pub async fn get_link_revision(
+        &self,
+        link_id: &str,
+        revision_id: &str,
+    ) -> Result<LinkRevision> {
+        tracing::info!("Fetching revision {} for link: {}", revision_id, link_id);
+
+        let revision = sqlx::query_as::<_, LinkRevision>(
+            "SELECT * FROM link_revisions WHERE id = ? AND link_id = ?",
+        )
+        .bind(revision_id)
+        .bind(link_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match revision {
+            Some(revision) => Ok(revision),
+            None => {
+                tracing::info!("Revision not found: {}", revision_id);
+                Err(anyhow::anyhow!("404"))
+            }
+        }
+    }
+
+    /// Move a link to the trash, it can be restored until it's purged
+    pub async fn delete_link(&self, id: &str) -> Result<()> {
+        tracing::info!("Deleting link: {}", id);
+
+        let result =
+            sqlx::query("UPDATE links SET deleted_at = ? WHERE id = ? AND deleted_at IS NULL")
+                .bind(Utc::now().to_rfc3339())
+                .bind(id)
+                .execute(&self.pool)
+                .await?;
+
+        if result.rows_affected() == 0 {
+            tracing::info!("No link found to delete with ID: {}", id);
+            return Err(anyhow::anyhow!("Link not found"));
+        }
+
+        tracing::info!("Successfully moved link to trash: {}", id);
+        Ok(())
+    }
+
+    pub async fn get_trashed_links(&self, owner_id: &str, owner_type: &str) -> Result<Vec<Link>> {
+        tracing::info!(
+            "Fetching trashed links for owner {}: {}",
+            owner_type,
+            owner_id
+        );
+
+        let links = sqlx::query_as::<_, Link>(
+            "SELECT * FROM links WHERE owner_id = ? AND owner_type = ? AND deleted_at IS NOT NULL
+             ORDER BY deleted_at DESC",
+        )
+        .bind(owner_id)
+        .bind(owner_type)
+        .fetch_all(&self.pool)
+        .await?;
+
+        tracing::info!("Successfully fetched {} trashed links", links.len());
+        Ok(links)
+    }
+
+    pub async fn restore_link(&self, id: &str) -> Result<()> {
+        tracing::info!("Restoring link: {}", id);
+
+        let result = sqlx::query(
+            "UPDATE links SET deleted_at = NULL WHERE id = ? AND deleted_at IS NOT NULL",
+        )
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            tracing::info!("No trashed link found to restore with ID: {}", id);
+            return Err(anyhow::anyhow!("Link not found"));
+        }
+
+        tracing::info!("Successfully restored link: {}", id);
+        Ok(())
+    }
+
+    /// Permanently delete a link that's already in the trash
+    pub async fn purge_link(&self, id: &str) -> Result<()> {
+        tracing::info!("Purging link: {}", id);
+
+        let result = sqlx::query("DELETE FROM links WHERE id = ? AND deleted_at IS NOT NULL")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            tracing::info!("No trashed link found to purge with ID: {}", id);
+            return Err(anyhow::anyhow!("Link not found"));
+        }
+
+        tracing::info!("Successfully purged link: {}", id);
+        Ok(())
+    }
+
+    /// Permanently delete every link that has been in the trash since before the cutoff
+    pub async fn purge_trash_before(&self, cutoff: &DateTime<Utc>) -> Result<u64> {
+        tracing::info!("Purging links trashed before {}", cutoff.to_rfc3339());
+
+        let result =
+            sqlx::query("DELETE FROM links WHERE deleted_at IS NOT NULL AND deleted_at < ?")
+                .bind(cutoff.to_rfc3339())
+                .execute(&self.pool)
+                .await?;
+
+        tracing::info!("Purged {} trashed links", result.rows_affected());
+        Ok(result.rows_affected())
+    }
+
+    /// Find an existing link for the owner that normalizes to the same canonical URL
+    /// Find a live link of the owner's with the canonical URL, other than `exclude_id`
+    pub async fn find_duplicate_link(
+        &self,
+        owner_id: &str,
+        owner_type: &str,
+        canonical_url: &str,
+        exclude_id: Option<&str>,
+    ) -> Result<Option<Link>> {
+        tracing::info!(
+            "Checking for duplicate link for owner {}: {}",
+            owner_id,
+            canonical_url
+        );
+
+        let link = sqlx::query_as::<_, Link>(
+            "SELECT * FROM links
+             WHERE owner_id = ? AND owner_type = ? AND canonical_url = ? AND deleted_at IS NULL
+               AND id IS NOT ?
+             LIMIT 1",
+        )
+        .bind(owner_id)
+        .bind(owner_type)
+        .bind(canonical_url)
+        .bind(exclude_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(link)
+    }
+
+    /// Group an owner's links that share a canonical URL, for cleanup
+    pub async fn get_duplicate_link_groups(
+        &self,
+        owner_id: &str,
+        owner_type: &str,
+    ) -> Result<Vec<DuplicateLinkGroup>> {
+        tracing::info!(
+            "Fetching duplicate links for owner {}: {}",
+            owner_type,
+            owner_id
+        );
+
+        let links = sqlx::query_as::<_, Link>(
+            "SELECT * FROM links
+             WHERE owner_id = ? AND owner_type = ? AND deleted_at IS NULL AND canonical_url IN (
+                SELECT canonical_url FROM links
+                WHERE owner_id = ? AND owner_type = ? AND canonical_url IS NOT NULL
+                AND deleted_at IS NULL
+                GROUP BY canonical_url HAVING COUNT(*) > 1
+             )
+             ORDER BY canonical_url, created_at",
+        )
+        .bind(owner_id)
+        .bind(owner_type)
+        .bind(owner_id)
+        .bind(owner_type)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut groups: Vec<DuplicateLinkGroup> = Vec::new();
+        for link in links {
+            let canonical_url = link.canonical_url.clone().unwrap_or_default();
+            match groups.last_mut() {
+                Some(group) if group.canonical_url == canonical_url => group.links.push(link),
+                _ => groups.push(DuplicateLinkGroup {
+                    canonical_url,
+                    links: vec![link],
+                }),
+            }
+        }
+
+        tracing::info!("Found {} duplicate link groups", groups.len());
+        Ok(groups)
+    }
+
+    /// Fill in canonical_url for links created before it was tracked
+    async fn backfill_canonical_urls(&self) -> Result<()> {
+        let rows = sqlx::query("SELECT id, url FROM links WHERE canonical_url IS NULL")
+            .fetch_all(&self.pool)
+            .await?;
+
+        if rows.is_empty() {
+            return Ok(());
+        }
+
+        tracing::info!("Backfilling canonical URLs for {} links", rows.len());
+
+        for row in rows {
+            let id: String = row.try_get("id")?;
+            let url: String = row.try_get("url")?;
+
+            let canonical_url = match link_url::normalize(&url, link_url::strip_tracking_enabled())
+            {
+                Ok(canonical_url) => canonical_url,
+                Err(e) => {
+                    tracing::warn!("Could not normalize URL for link {}: {}", id, e);
+                    continue;
+                }
+            };
+
+            sqlx::query("UPDATE links SET canonical_url = ? WHERE id = ?")
+                .bind(&canonical_url)
+                .bind(&id)
+                .execute(&self.pool)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    // User Memberships
+    pub async fn get_user_memberships(&self, user_id: &str) -> Result<Vec<UserMembership>> {
+        tracing::info!("Fetching memberships for user: {}", user_id);
+
+        let memberships = sqlx::query_as::<_, UserMembership>(
+            "SELECT * FROM user_memberships WHERE user_id = ?",
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        tracing::info!("Successfully fetched {} memberships", memberships.len());
+        Ok(memberships)
+    }
+
+    pub async fn add_member(&self, membership: UserMembership) -> Result<()> {
+        tracing::info!(
+            "Adding member: {} to entity: {}",
+            membership.user_id,
+            membership.entity_id
+        );
+
+        let result = sqlx::query(
+            "INSERT INTO user_memberships (user_id, entity_id, entity_type, role, created_at)
+             VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(&membership.user_id)
+        .bind(&membership.entity_id)
+        .bind(&membership.entity_type)
+        .bind(&membership.role)
+        .bind(&membership.created_at)
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            tracing::error!("Failed to add member");
+            return Err(anyhow::anyhow!("Failed to add member: database error"));
+        }
+
+        tracing::info!("Successfully added member");
+        Ok(())
+    }
+
+    pub async fn update_member_role(
+        &self,
+        user_id: &str,
+        entity_id: &str,
+        role: &str,
+    ) -> Result<UserMembership> {
+        tracing::info!(
+            "Updating role for user: {} in entity: {}",
+            user_id,
+            entity_id
+        );
+
+        let result = sqlx::query(
+            "UPDATE user_memberships SET role = ? WHERE user_id = ? AND entity_id = ?",
+        )
+        .bind(role)
+        .bind(user_id)
+        .bind(entity_id)
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(anyhow::anyhow!("Membership not found or update failed"));
+        }
+
+        // Fetch and return the updated membership
+        let membership = sqlx::query_as::<_, UserMembership>(
+            "SELECT * FROM user_memberships WHERE user_id = ? AND entity_id = ?",
+        )
+        .bind(user_id)
+        .bind(entity_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        tracing::info!("Successfully updated member role");
+        Ok(membership)
+    }
+
+    pub async fn remove_member(&self, user_id: &str, entity_id: &str) -> Result<()> {
+        tracing::info!("Removing member: {} from entity: {}", user_id, entity_id);
+
+        let result =
+            sqlx::query("DELETE FROM user_memberships WHERE user_id = ? AND entity_id = ?")
+                .bind(user_id)
+                .bind(entity_id)
+                .execute(&self.pool)
+                .await?;
+
+        if result.rows_affected() == 0 {
+            tracing::info!("No membership found to delete");
+            return Err(anyhow::anyhow!("Membership not found"));
+        }
+
+        tracing::info!("Successfully removed member");
+        Ok(())
+    }
+
+    pub async fn get_membership(
+        &self,
+        user_id: &str,
+        entity_id: &str,
+    ) -> Result<Option<UserMembership>> {
+        tracing::info!(
+            "Fetching membership for user: {} in entity: {}",
+            user_id,
+            entity_id
+        );
+
+        let membership = sqlx::query_as::<_, UserMembership>(
+            "SELECT * FROM user_memberships WHERE user_id = ? AND entity_id = ?",
+        )
+        .bind(user_id)
+        .bind(entity_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(membership)
+    }
+
+    // Sessions
+    pub async fn create_session(
+        &self,
+        user_id: &str,
+        refresh_token_hash: &str,
+        expires_at: &DateTime<Utc>,
+        device: &SessionDevice,
+    ) -> Result<Session> {
+        tracing::info!("Creating session for user: {}", user_id);
+
+        let now = Utc::now().to_rfc3339();
+        let session = Session {
+            id: uuid::Uuid::new_v4().to_string(),
+            user_id: user_id.to_string(),
+            refresh_token_hash: refresh_token_hash.to_string(),
+            previous_refresh_token_hash: None,
+            rotated_at: None,
+            created_at: now.clone(),
+            expires_at: expires_at.to_rfc3339(),
+            revoked_at: None,
+            device_label: device.device_label.clone(),
+            user_agent: device.user_agent.clone(),
+            ip_address: device.ip_address.clone(),
+            last_seen_at: Some(now),
+        };
+
+        sqlx::query(
+            "INSERT INTO sessions (id, user_id, refresh_token_hash, created_at, expires_at, device_label, user_agent, ip_address, last_seen_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&session.id)
+        .bind(&session.user_id)
+        .bind(&session.refresh_token_hash)
+        .bind(&session.created_at)
+        .bind(&session.expires_at)
+        .bind(&session.device_label)
+        .bind(&session.user_agent)
+        .bind(&session.ip_address)
+        .bind(&session.last_seen_at)
+        .execute(&self.pool)
+        .await?;
+
+        tracing::info!("Successfully created session: {}", session.id);
+        Ok(session)
+    }
+
+    /// Find the session a refresh token belongs to, either as its current or previous token
+    pub async fn get_session_by_refresh_token(&self, refresh_token_hash: &str) -> Result<Session> {
+        let session = sqlx::query_as::<_, Session>(
+            "SELECT * FROM sessions WHERE refresh_token_hash = ? OR previous_refresh_token_hash = ?",
+        )
+        .bind(refresh_token_hash)
+        .bind(refresh_token_hash)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match session {
+            Some(session) => Ok(session),
+            None => {
+                tracing::info!("No session found for refresh token");
+                Err(anyhow::anyhow!("404"))
+            }
+        }
+    }
+
+    /// Swap a session's refresh token for a new one
+    /// Fails if the token was already rotated by a concurrent request
+    pub async fn rotate_session(
+        &self,
+        id: &str,
+        old_refresh_token_hash: &str,
+        new_refresh_token_hash: &str,
+        expires_at: &DateTime<Utc>,
+    ) -> Result<()> {
+        tracing::info!("Rotating refresh token for session: {}", id);
+
+        let result = sqlx::query(
+            "UPDATE sessions
+             SET refresh_token_hash = ?, previous_refresh_token_hash = ?, rotated_at = ?,
+             expires_at = ?
+             WHERE id = ? AND refresh_token_hash = ? AND revoked_at IS NULL",
+        )
+        .bind(new_refresh_token_hash)
+        .bind(old_refresh_token_hash)
+        .bind(Utc::now().to_rfc3339())
+        .bind(expires_at.to_rfc3339())
+        .bind(id)
+        .bind(old_refresh_token_hash)
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(anyhow::anyhow!("Session not found or already rotated"));
+        }
+
+        Ok(())
+    }
+
+    /// Active (not revoked or expired) sessions for a user, most recently used first
+    pub async fn get_active_sessions(&self, user_id: &str) -> Result<Vec<Session>> {
+        tracing::info!("Fetching active sessions for user: {}", user_id);
+
+        let sessions = sqlx::query_as::<_, Session>(
+            "SELECT * FROM sessions
+             WHERE user_id = ? AND revoked_at IS NULL AND expires_at > ?
+             ORDER BY COALESCE(last_seen_at, created_at) DESC",
+        )
+        .bind(user_id)
+        .bind(Utc::now().to_rfc3339())
+        .fetch_all(&self.pool)
+        .await?;
+
+        tracing::info!("Successfully fetched {} sessions", sessions.len());
+        Ok(sessions)
+    }
+
+    /// Record that a session was just used
+    /// Only writes when the last recorded use is older than a few minutes to keep requests cheap
+    pub async fn touch_session(&self, id: &str, ip_address: Option<&str>) -> Result<()> {
+        let now = Utc::now();
+        let stale_before = (now - chrono::Duration::minutes(5)).to_rfc3339();
+
+        sqlx::query(
+            "UPDATE sessions SET last_seen_at = ?, ip_address = COALESCE(?, ip_address)
+             WHERE id = ? AND (last_seen_at IS NULL OR last_seen_at < ?)",
+        )
+        .bind(now.to_rfc3339())
+        .bind(ip_address)
+        .bind(id)
+        .bind(&stale_before)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Revoke one of a user's sessions, failing if it doesn't belong to them
+    pub async fn revoke_user_session(&self, user_id: &str, id: &str) -> Result<()> {
+        tracing::info!("Revoking session {} for user {}", id, user_id);
+
+        let result = sqlx::query(
+            "UPDATE sessions SET revoked_at = ? WHERE id = ? AND user_id = ? AND revoked_at IS NULL",
+        )
+        .bind(Utc::now().to_rfc3339())
+        .bind(id)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            tracing::info!("No active session found to revoke with ID: {}", id);
+            return Err(anyhow::anyhow!("404"));
+        }
+
+        Ok(())
+    }
+
+    pub async fn revoke_session(&self, id: &str) -> Result<()> {
+        tracing::info!("Revoking session: {}", id);
+
+        sqlx::query("UPDATE sessions SET revoked_at = ? WHERE id = ? AND revoked_at IS NULL")
+            .bind(Utc::now().to_rfc3339())
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Revoke every active session for a user, returning how many were revoked
+    pub async fn revoke_user_sessions(&self, user_id: &str) -> Result<u64> {
+        tracing::info!("Revoking all sessions for user: {}", user_id);
+
+        let result = sqlx::query(
+            "UPDATE sessions SET revoked_at = ? WHERE user_id = ? AND revoked_at IS NULL",
+        )
+        .bind(Utc::now().to_rfc3339())
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        tracing::info!("Revoked {} sessions", result.rows_affected());
+        Ok(result.rows_affected())
+    }
+
+    /// Revoke every session a user has except the one they're using
+    pub async fn revoke_other_sessions(&self, user_id: &str, session_id: &str) -> Result<u64> {
+        tracing::info!("Revoking other sessions for user: {}", user_id);
+
+        let result = sqlx::query(
+            "UPDATE sessions SET revoked_at = ? WHERE user_id = ? AND id != ? AND revoked_at IS NULL",
+        )
+        .bind(Utc::now().to_rfc3339())
+        .bind(user_id)
+        .bind(session_id)
+        .execute(&self.pool)
+        .await?;
+
+        tracing::info!("Revoked {} sessions", result.rows_affected());
+        Ok(result.rows_affected())
+    }
+
+    /// Revoke a single access token until it expires
+    pub async fn revoke_token(&self, jti: &str, expires_at: &DateTime<Utc>) -> Result<()> {
+        tracing::info!("Revoking access token: {}", jti);
+
+        sqlx::query("INSERT OR IGNORE INTO revoked_tokens (jti, expires_at) VALUES (?, ?)")
+            .bind(jti)
+            .bind(expires_at.to_rfc3339())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Whether an access token can no longer be used, because it was revoked
+    /// or its session was revoked, expired or removed
+    pub async fn is_token_revoked(&self, jti: &str, session_id: &str) -> Result<bool> {
+        let revoked: bool = sqlx::query_scalar(
+            "SELECT EXISTS(SELECT 1 FROM revoked_tokens WHERE jti = ?)
+             OR NOT EXISTS(
+                SELECT 1 FROM sessions WHERE id = ? AND revoked_at IS NULL AND expires_at > ?
+             )",
+        )
+        .bind(jti)
+        .bind(session_id)
+        .bind(Utc::now().to_rfc3339())
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(revoked)
+    }
+
+    /// Drop sessions and revoked tokens that can no longer be used anyway
+    pub async fn purge_expired_sessions(&self) -> Result<()> {
+        let now = Utc::now().to_rfc3339();
+
+        let sessions = sqlx::query("DELETE FROM sessions WHERE expires_at < ?")
+            .bind(&now)
+            .execute(&self.pool)
+            .await?;
+
+        let tokens = sqlx::query("DELETE FROM revoked_tokens WHERE expires_at < ?")
+            .bind(&now)
+            .execute(&self.pool)
+            .await?;
+
+        sqlx::query("DELETE FROM webauthn_ceremonies WHERE expires_at < ?")
+            .bind(&now)
+            .execute(&self.pool)
+            .await?;
+
+        sqlx::query("DELETE FROM oidc_login_states WHERE expires_at < ?")
+            .bind(&now)
+            .execute(&self.pool)
+            .await?;
+
+        sqlx::query(
+            "DELETE FROM login_attempts
+             WHERE window_expires_at < ? AND (locked_until IS NULL OR locked_until < ?)",
+        )
+        .bind(&now)
+        .bind(&now)
+        .execute(&self.pool)
+        .await?;
+
+        tracing::info!(
+            "Purged {} expired sessions and {} revoked tokens",
+            sessions.rows_affected(),
+            tokens.rows_affected()
+        );
+        Ok(())
+    }
+
+    // Brute-force protection
+    /// When the key is locked out until, if it currently is
+    pub async fn get_login_lockout(&self, key: &str) -> Result<Option<DateTime<Utc>>> {
+        let locked_until: Option<String> = sqlx::query_scalar(
+            "SELECT locked_until FROM login_attempts WHERE key = ? AND locked_until > ?",
+        )
+        .bind(key)
+        .bind(Utc::now().to_rfc3339())
+        .fetch_optional(&self.pool)
+        .await?
+        .flatten();
+
+        Ok(locked_until
+            .and_then(|locked_until| DateTime::parse_from_rfc3339(&locked_until).ok())
+            .map(|locked_until| locked_until.with_timezone(&Utc)))
+    }
+
+    /// Count a failed attempt, starting over if the previous window has passed
+    /// Returns the number of failures in the current window
+    pub async fn record_login_failure(
+        &self,
+        key: &str,
+        window_expires_at: &DateTime<Utc>,
+    ) -> Result<i64> {
+        let failures: i64 = sqlx::query_scalar(
+            "INSERT INTO login_attempts (key, failures, window_expires_at) VALUES (?, 1, ?)
+             ON CONFLICT(key) DO UPDATE SET
+                failures = CASE WHEN login_attempts.window_expires_at < ? THEN 1
+                                ELSE login_attempts.failures + 1 END,
+                window_expires_at = MAX(login_attempts.window_expires_at, excluded.window_expires_at)
+             RETURNING failures",
+        )
+        .bind(key)
+        .bind(window_expires_at.to_rfc3339())
+        .bind(Utc::now().to_rfc3339())
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(failures)
+    }
+
+    /// Lock the key out, keeping its failures counted until well after the lockout ends
+    pub async fn lock_out_login(
+        &self,
+        key: &str,
+        locked_until: &DateTime<Utc>,
+        window_expires_at: &DateTime<Utc>,
+    ) -> Result<()> {
+        sqlx::query(
+            "UPDATE login_attempts SET locked_until = ?, window_expires_at = ? WHERE key = ?",
+        )
+        .bind(locked_until.to_rfc3339())
+        .bind(window_expires_at.to_rfc3339())
+        .bind(key)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn clear_login_attempts(&self, key: &str) -> Result<()> {
+        sqlx::query("DELETE FROM login_attempts WHERE key = ?")
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    // Passkeys
+    pub async fn create_passkey(
+        &self,
+        user_id: &str,
+        credential_id: &str,
+        name: &str,
+        passkey_json: &str,
+    ) -> Result<PasskeyCredential> {
+        tracing::info!("Registering passkey for user: {}", user_id);
+
+        let existing = sqlx::query("SELECT id FROM passkeys WHERE credential_id = ?")
+            .bind(credential_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        if existing.is_some() {
+            tracing::warn!("Passkey already exists: {}", credential_id);
+            return Err(anyhow::anyhow!("Passkey already exists"));
+        }
+
+        let passkey = sqlx::query_as::<_, PasskeyCredential>(
+            "INSERT INTO passkeys (id, user_id, credential_id, name, passkey_json, created_at)
+             VALUES (?, ?, ?, ?, ?, ?)
+             RETURNING *",
+        )
+        .bind(uuid::Uuid::new_v4().to_string())
+        .bind(user_id)
+        .bind(credential_id)
+        .bind(name)
+        .bind(passkey_json)
+        .bind(Utc::now().to_rfc3339())
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(passkey)
+    }
+
+    pub async fn get_passkeys(&self, user_id: &str) -> Result<Vec<PasskeyCredential>> {
+        let passkeys = sqlx::query_as::<_, PasskeyCredential>(
+            "SELECT * FROM passkeys WHERE user_id = ? ORDER BY created_at",
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(passkeys)
+    }
+
+    pub async fn get_passkey_by_credential_id(
+        &self,
+        credential_id: &str,
+    ) -> Result<PasskeyCredential> {
+        let passkey = sqlx::query_as::<_, PasskeyCredential>(
+            "SELECT * FROM passkeys WHERE credential_id = ?",
+        )
+        .bind(credential_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        passkey.ok_or_else(|| anyhow::anyhow!("404"))
+    }
+
+    /// Store the credential's new signature counter after a sign-in
+    pub async fn update_passkey_after_login(&self, id: &str, passkey_json: &str) -> Result<()> {
+        sqlx::query("UPDATE passkeys SET passkey_json = ?, last_used_at = ? WHERE id = ?")
+            .bind(passkey_json)
+            .bind(Utc::now().to_rfc3339())
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete_passkey(&self, user_id: &str, id: &str) -> Result<()> {
+        tracing::info!("Deleting passkey {} for user: {}", id, user_id);
+
+        let result = sqlx::query("DELETE FROM passkeys WHERE id = ? AND user_id = ?")
+            .bind(id)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(anyhow::anyhow!("404"));
+        }
+        Ok(())
+    }
+
+    /// Park the server-side state of a WebAuthn ceremony until the browser answers
+    pub async fn create_webauthn_ceremony(
+        &self,
+        user_id: Option<&str>,
+        kind: &str,
+        state_json: &str,
+        expires_at: &DateTime<Utc>,
+    ) -> Result<String> {
+        let id = uuid::Uuid::new_v4().to_string();
+
+        sqlx::query(
+            "INSERT INTO webauthn_ceremonies (id, user_id, kind, state_json, expires_at)
+             VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(&id)
+        .bind(user_id)
+        .bind(kind)
+        .bind(state_json)
+        .bind(expires_at.to_rfc3339())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(id)
+    }
+
+    /// Remove and return a ceremony's state, so each challenge can only be answered once
+    pub async fn take_webauthn_ceremony(
+        &self,
+        id: &str,
+        kind: &str,
+    ) -> Result<(Option<String>, String)> {
+        let ceremony: Option<(Option<String>, String)> = sqlx::query_as(
+            "DELETE FROM webauthn_ceremonies WHERE id = ? AND kind = ? AND expires_at > ?
+             RETURNING user_id, state_json",
+        )
+        .bind(id)
+        .bind(kind)
+        .bind(Utc::now().to_rfc3339())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        ceremony.ok_or_else(|| anyhow::anyhow!("404"))
+    }
+
+    // Single sign-on
+    pub async fn create_oidc_login_state(
+        &self,
+        state_hash: &str,
+        code_verifier: &str,
+        nonce: &str,
+        link_user_id: Option<&str>,
+        expires_at: &DateTime<Utc>,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO oidc_login_states (state_hash, code_verifier, nonce, link_user_id, expires_at)
+             VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(state_hash)
+        .bind(code_verifier)
+        .bind(nonce)
+        .bind(link_user_id)
+        .bind(expires_at.to_rfc3339())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Remove and return a pending login, so a state can only be used once
+    pub async fn take_oidc_login_state(&self, state_hash: &str) -> Result<OidcLoginState> {
+        let state = sqlx::query_as::<_, OidcLoginState>(
+            "DELETE FROM oidc_login_states WHERE state_hash = ? AND expires_at > ?
+             RETURNING code_verifier, nonce, link_user_id",
+        )
+        .bind(state_hash)
+        .bind(Utc::now().to_rfc3339())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        state.ok_or_else(|| anyhow::anyhow!("404"))
+    }
+
+    pub async fn get_user_by_identity(&self, issuer: &str, subject: &str) -> Result<Option<User>> {
+        let user = sqlx::query_as::<_, User>(
+            "SELECT u.* FROM users u
+             JOIN user_identities i ON i.user_id = u.id
+             WHERE i.issuer = ? AND i.subject = ?",
+        )
+        .bind(issuer)
+        .bind(subject)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(user)
+    }
+
+    /// Link a provider account to a user, or refresh the link's email and last login
+    pub async fn link_identity(
+        &self,
+        issuer: &str,
+        subject: &str,
+        user_id: &str,
+        email: Option<&str>,
+    ) -> Result<()> {
+        let now = Utc::now().to_rfc3339();
+
+        sqlx::query(
+            "INSERT INTO user_identities (issuer, subject, user_id, email, created_at, last_login_at)
+             VALUES (?, ?, ?, ?, ?, ?)
+             ON CONFLICT(issuer, subject) DO UPDATE SET email = excluded.email, last_login_at = excluded.last_login_at",
+        )
+        .bind(issuer)
+        .bind(subject)
+        .bind(user_id)
+        .bind(email)
+        .bind(&now)
+        .bind(&now)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    // Personal API tokens
+    pub async fn create_api_token(
+        &self,
+        user_id: &str,
+        name: &str,
+        token_hash: &str,
+        scopes: &str,
+        expires_at: Option<&DateTime<Utc>>,
+    ) -> Result<ApiToken> {
+        tracing::info!("Creating API token {:?} for user: {}", name, user_id);
+
+        let token = sqlx::query_as::<_, ApiToken>(
+            "INSERT INTO api_tokens (id, user_id, name, token_hash, scopes, created_at, expires_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?)
+             RETURNING *",
+        )
+        .bind(uuid::Uuid::new_v4().to_string())
+        .bind(user_id)
+        .bind(name)
+        .bind(token_hash)
+        .bind(scopes)
+        .bind(Utc::now().to_rfc3339())
+        .bind(expires_at.map(|expires_at| expires_at.to_rfc3339()))
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(token)
+    }
+
+    pub async fn get_api_tokens(&self, user_id: &str) -> Result<Vec<ApiToken>> {
+        let tokens = sqlx::query_as::<_, ApiToken>(
+            "SELECT * FROM api_tokens WHERE user_id = ? ORDER BY created_at",
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(tokens)
+    }
+
+    /// Look up an unexpired token by hash and record that it was used, along with its owner's email
+    pub async fn use_api_token(&self, token_hash: &str) -> Result<Option<(ApiToken, String)>> {
+        let now = Utc::now().to_rfc3339();
+
+        let token = sqlx::query_as::<_, ApiToken>(
+            "UPDATE api_tokens SET last_used_at = ?
+             WHERE token_hash = ? AND (expires_at IS NULL OR expires_at > ?)
+             RETURNING *",
+        )
+        .bind(&now)
+        .bind(token_hash)
+        .bind(&now)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let token = match token {
+            Some(token) => token,
+            None => return Ok(None),
+        };
+        // Tokens stop working while their owner's account is disabled
+        let email: Option<String> =
+            sqlx::query_scalar("SELECT email FROM users WHERE id = ? AND disabled_at IS NULL")
+                .bind(&token.user_id)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(email.map(|email| (token, email)))
+    }
+
+    pub async fn delete_api_token(&self, user_id: &str, id: &str) -> Result<()> {
+        tracing::info!("Revoking API token {} for user: {}", id, user_id);
+
+        let result = sqlx::query("DELETE FROM api_tokens WHERE id = ? AND user_id = ?")
+            .bind(id)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(anyhow::anyhow!("404"));
+        }
+        Ok(())
+    }
+
+    // Invites
+    pub async fn count_users(&self) -> Result<i64> {
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(count)
+    }
+
+    pub async fn create_invite(&self, invite: &Invite) -> Result<()> {
+        tracing::info!(
+            "Creating invite {} for organization {:?}",
+            invite.id,
+            invite.organization_id
         );
 
-        let result = sqlx::query(
-            "INSERT INTO links (id, title, url, icon, order_index, owner_type, owner_id, created_at, description, column_type)
-             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+        sqlx::query(
+            "INSERT INTO invites (id, code_hash, created_by, organization_id, role, email, max_uses, uses, created_at, expires_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
         )
-        .bind(&link.id)
-        .bind(&link.title)
-        .bind(&link.url)
-        .bind(&link.icon)
-        .bind(&link.order_index)
-        .bind(&link.owner_type)
-        .bind(&link.owner_id)
-        .bind(&link.created_at)
-        .bind(&link.description)
-        .bind(&link.column_type)
+        .bind(&invite.id)
+        .bind(&invite.code_hash)
+        .bind(&invite.created_by)
+        .bind(&invite.organization_id)
+        .bind(&invite.role)
+        .bind(&invite.email)
+        .bind(invite.max_uses)
+        .bind(invite.uses)
+        .bind(&invite.created_at)
+        .bind(&invite.expires_at)
         .execute(&self.pool)
         .await?;
+        Ok(())
+    }
 
-        if result.rows_affected() == 0 {
-            tracing::error!("Failed to create link");
-            return Err(anyhow::anyhow!("Failed to create link: database error"));
-        }
-
-        tracing::info!("Successfully created link: {}", link.id);
-        Ok(link)
+    /// An organization's invites, or the instance-wide ones without an organization
+    pub async fn get_invites(&self, organization_id: Option<&str>) -> Result<Vec<Invite>> {
+        let invites = sqlx::query_as::<_, Invite>(
+            "SELECT * FROM invites WHERE organization_id IS ? ORDER BY created_at",
+        )
+        .bind(organization_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(invites)
     }
 
-    pub async fn update_link(&self, link: Link) -> Result<()> {
-        tracing::info!("Updating link: {}", link.id);
+    pub async fn get_invite(&self, id: &str) -> Result<Invite> {
+        sqlx::query_as::<_, Invite>("SELECT * FROM invites WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("404"))
+    }
 
-        let result = sqlx::query(
-            "UPDATE links
-            SET title = ?, url = ?, icon = ?,
-            order_index = ?, description = ?, column_type = ?
-            WHERE id = ?",
+    /// Use up one redemption of an invite, if it's unexpired, has uses left and isn't bound
+    /// to a different email
+    pub async fn claim_invite(&self, code_hash: &str, email: &str) -> Result<Option<Invite>> {
+        let invite = sqlx::query_as::<_, Invite>(
+            "UPDATE invites SET uses = uses + 1
+             WHERE code_hash = ?
+               AND (max_uses IS NULL OR uses < max_uses)
+               AND (expires_at IS NULL OR expires_at > ?)
+               AND (email IS NULL OR email = ? COLLATE NOCASE)
+             RETURNING *",
         )
-        .bind(&link.title)
-        .bind(&link.url)
-        .bind(&link.icon)
-        .bind(&link.order_index)
-        .bind(&link.description)
-        .bind(&link.column_type)
-        .bind(&link.id)
-        .execute(&self.pool)
+        .bind(code_hash)
+        .bind(Utc::now().to_rfc3339())
+        .bind(email.trim())
+        .fetch_optional(&self.pool)
         .await?;
+        Ok(invite)
+    }
 
-        if result.rows_affected() == 0 {
-            return Err(anyhow::anyhow!("Link not found or update failed"));
-        }
-
-        tracing::info!("Successfully updated link: {}", link.id);
+    /// Give back a claimed use when the registration it was for didn't go through
+    pub async fn release_invite(&self, id: &str) -> Result<()> {
+        sqlx::query("UPDATE invites SET uses = uses - 1 WHERE id = ? AND uses > 0")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
         Ok(())
     }
 
-    pub async fn delete_link(&self, id: &str) -> Result<()> {
-        tracing::info!("Deleting link: {}", id);
+    pub async fn delete_invite(&self, id: &str) -> Result<()> {
+        tracing::info!("Deleting invite {}", id);
 
-        let result = sqlx::query("DELETE FROM links WHERE id = ?")
+        let result = sqlx::query("DELETE FROM invites WHERE id = ?")
             .bind(id)
             .execute(&self.pool)
             .await?;
 
         if result.rows_affected() == 0 {
-            tracing::info!("No link found to delete with ID: {}", id);
-            return Err(anyhow::anyhow!("Link not found"));
+            return Err(anyhow::anyhow!("404"));
         }
-
-        tracing::info!("Successfully deleted link: {}", id);
         Ok(())
     }
 
-    // User Memberships
-    pub async fn get_user_memberships(&self, user_id: &str) -> Result<Vec<UserMembership>> {
-        tracing::info!("Fetching memberships for user: {}", user_id);
-
-        let memberships = sqlx::query_as::<_, UserMembership>(
-            "SELECT * FROM user_memberships WHERE user_id = ?",
+    // Instance administration
+    /// Users whose email contains `query`, oldest first, along with the total number of matches
+    pub async fn search_users(
+        &self,
+        query: Option<&str>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<User>, i64)> {
+        let pattern = query.map(|query| {
+            let escaped = query
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            format!("%{}%", escaped)
+        });
+
+        let users = sqlx::query_as::<_, User>(
+            "SELECT * FROM users
+             WHERE ? IS NULL OR email LIKE ? ESCAPE '\\'
+             ORDER BY created_at
+             LIMIT ? OFFSET ?",
         )
-        .bind(user_id)
+        .bind(&pattern)
+        .bind(&pattern)
+        .bind(limit)
+        .bind(offset)
         .fetch_all(&self.pool)
         .await?;
 
-        tracing::info!("Successfully fetched {} memberships", memberships.len());
-        Ok(memberships)
+        let total: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM users WHERE ? IS NULL OR email LIKE ? ESCAPE '\\'",
+        )
+        .bind(&pattern)
+        .bind(&pattern)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok((users, total))
     }
 
-    pub async fn add_member(&self, membership: UserMembership) -> Result<()> {
-        tracing::info!(
-            "Adding member: {} to entity: {}",
-            membership.user_id,
-            membership.entity_id
-        );
+    pub async fn set_user_disabled(&self, user_id: &str, disabled: bool) -> Result<()> {
+        tracing::info!("Setting disabled = {} for user: {}", disabled, user_id);
 
-        let result = sqlx::query(
-            "INSERT INTO user_memberships (user_id, entity_id, entity_type, role, created_at)
-             VALUES (?, ?, ?, ?, ?)",
-        )
-        .bind(&membership.user_id)
-        .bind(&membership.entity_id)
-        .bind(&membership.entity_type)
-        .bind(&membership.role)
-        .bind(&membership.created_at)
-        .execute(&self.pool)
-        .await?;
+        let disabled_at = disabled.then(|| Utc::now().to_rfc3339());
+        let result = sqlx::query("UPDATE users SET disabled_at = ? WHERE id = ?")
+            .bind(disabled_at)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
 
         if result.rows_affected() == 0 {
-            tracing::error!("Failed to add member");
-            return Err(anyhow::anyhow!("Failed to add member: database error"));
+            return Err(anyhow::anyhow!("404"));
         }
-
-        tracing::info!("Successfully added member");
         Ok(())
     }
 
-    pub async fn update_member_role(
-        &self,
-        user_id: &str,
-        entity_id: &str,
-        role: &str,
-    ) -> Result<UserMembership> {
-        tracing::info!(
-            "Updating role for user: {} in entity: {}",
-            user_id,
-            entity_id
-        );
+    pub async fn get_instance_stats(&self) -> Result<InstanceStats> {
+        let now = Utc::now();
+        let stats = sqlx::query_as::<_, InstanceStats>(
+            "SELECT
+                (SELECT COUNT(*) FROM users) AS users,
+                (SELECT COUNT(*) FROM users WHERE is_admin = 1) AS admins,
+                (SELECT COUNT(*) FROM users WHERE disabled_at IS NOT NULL) AS disabled_users,
+                (SELECT COUNT(*) FROM users WHERE created_at > ?) AS new_users_last_30_days,
+                (SELECT COUNT(*) FROM links WHERE deleted_at IS NULL) AS links,
+                (SELECT COUNT(*) FROM links WHERE deleted_at IS NOT NULL) AS trashed_links,
+                (SELECT COUNT(*) FROM organizations) AS organizations,
+                (SELECT COUNT(*) FROM teams) AS teams,
+                (SELECT COUNT(*) FROM sessions WHERE revoked_at IS NULL AND expires_at > ?) AS active_sessions,
+                (SELECT COUNT(*) FROM api_tokens) AS api_tokens",
+        )
+        .bind((now - chrono::Duration::days(30)).to_rfc3339())
+        .bind(now.to_rfc3339())
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(stats)
+    }
 
-        let result = sqlx::query(
-            "UPDATE user_memberships SET role = ? WHERE user_id = ? AND entity_id = ?",
+    pub async fn get_instance_setting(&self, key: &str) -> Result<Option<String>> {
+        let value: Option<String> =
+            sqlx::query_scalar("SELECT value FROM instance_settings WHERE key = ?")
+                .bind(key)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(value)
+    }
+
+    pub async fn set_instance_setting(&self, key: &str, value: &str) -> Result<()> {
+        tracing::info!("Setting instance setting {} = {}", key, value);
+
+        sqlx::query(
+            "INSERT INTO instance_settings (key, value, updated_at) VALUES (?, ?, ?)
+             ON CONFLICT(key) DO UPDATE SET value = excluded.value, updated_at = excluded.updated_at",
         )
-        .bind(role)
-        .bind(user_id)
-        .bind(entity_id)
+        .bind(key)
+        .bind(value)
+        .bind(Utc::now().to_rfc3339())
         .execute(&self.pool)
         .await?;
+        Ok(())
+    }
 
-        if result.rows_affected() == 0 {
-            return Err(anyhow::anyhow!("Membership not found or update failed"));
-        }
+    // Audit log
+    pub async fn record_audit_event(&self, event: &AuditEvent) -> Result<()> {
+        tracing::info!(
+            "Recording audit event {} on {} {} by {}",
+            event.action,
+            event.entity_type,
+            event.entity_id,
+            event.actor_id
+        );
 
-        // Fetch and return the updated membership
-        let membership = sqlx::query_as::<_, UserMembership>(
-            "SELECT * FROM user_memberships WHERE user_id = ? AND entity_id = ?",
+        sqlx::query(
+            "INSERT INTO audit_events (id, actor_id, organization_id, entity_type, entity_id, action, before_json, after_json, created_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
         )
-        .bind(user_id)
-        .bind(entity_id)
-        .fetch_one(&self.pool)
+        .bind(&event.id)
+        .bind(&event.actor_id)
+        .bind(&event.organization_id)
+        .bind(&event.entity_type)
+        .bind(&event.entity_id)
+        .bind(&event.action)
+        .bind(&event.before_json)
+        .bind(&event.after_json)
+        .bind(&event.created_at)
+        .execute(&self.pool)
         .await?;
 
-        tracing::info!("Successfully updated member role");
-        Ok(membership)
+        Ok(())
     }
 
-    pub async fn remove_member(&self, user_id: &str, entity_id: &str) -> Result<()> {
-        tracing::info!("Removing member: {} from entity: {}", user_id, entity_id);
+    /// Page through audit events, newest first, returning the page and the total count
+    /// Filters on the organization when one is given, otherwise on the actor
+    pub async fn get_audit_events(
+        &self,
+        actor_id: &str,
+        organization_id: Option<&str>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<AuditEvent>, i64)> {
+        let (filter, value) = match organization_id {
+            Some(organization_id) => ("organization_id = ?", organization_id),
+            None => ("actor_id = ?", actor_id),
+        };
 
-        let result =
-            sqlx::query("DELETE FROM user_memberships WHERE user_id = ? AND entity_id = ?")
-                .bind(user_id)
-                .bind(entity_id)
-                .execute(&self.pool)
-                .await?;
+        tracing::info!("Fetching audit events where {} {}", filter, value);
 
-        if result.rows_affected() == 0 {
-            tracing::info!("No membership found to delete");
-            return Err(anyhow::anyhow!("Membership not found"));
-        }
+        let events = sqlx::query_as::<_, AuditEvent>(&format!(
+            "SELECT * FROM audit_events WHERE {} ORDER BY created_at DESC LIMIT ? OFFSET ?",
+            filter
+        ))
+        .bind(value)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
 
-        tracing::info!("Successfully removed member");
-        Ok(())
+        let total: i64 = sqlx::query_scalar(&format!(
+            "SELECT COUNT(*) FROM audit_events WHERE {}",
+            filter
+        ))
+        .bind(value)
+        .fetch_one(&self.pool)
+        .await?;
+
+        tracing::info!("Successfully fetched {} audit events", events.len());
+        Ok((events, total))
     }
 
     // Plans
@@ -843,6 +2708,7 @@ impl Database {
             l.created_at as link_created_at,
             l.description as link_description,
             l.column_type as link_column_type,
+            l.canonical_url as link_canonical_url,
             s.id as subscription_id,
             s.entity_id as subscription_entity_id,
             s.entity_type as subscription_entity_type,
@@ -853,7 +2719,7 @@ impl Database {
             us.settings_blob as settings_blob,
             us.created_at as settings_created_at
             FROM users u
-            LEFT JOIN links l ON u.id = l.owner_id
+            LEFT JOIN links l ON u.id = l.owner_id AND l.deleted_at IS NULL
             LEFT JOIN subscriptions s ON u.id = s.entity_id
             LEFT JOIN user_settings us ON u.id = us.user_id
             WHERE u.id = ?
@@ -872,6 +2738,9 @@ impl Database {
             id: first_row.get("id"),
             email: first_row.get("email"),
             created_at: first_row.get("created_at"),
+            email_verified_at: first_row.try_get("email_verified_at").ok(),
+            is_admin: first_row.try_get("is_admin").unwrap_or(false),
+            disabled_at: first_row.try_get("disabled_at").ok(),
             auth_token: None,
             password_hash: first_row.get::<String, _>("password_hash"),
         };
@@ -929,6 +2798,8 @@ impl Database {
                         created_at: row.try_get("link_created_at").unwrap_or_default(),
                         description: row.try_get("link_description").ok(),
                         column_type: row.try_get("link_column_type").unwrap_or_default(),
+                        canonical_url: row.try_get("link_canonical_url").ok(),
+                        deleted_at: None,
                     })
                 } else {
                     None
@@ -1048,4 +2919,214 @@ impl Database {
         tracing::info!("Successfully created team: {}", team_id);
         Ok(team_id)
     }
+
+    pub async fn get_workspace(&self, entity_type: &str, id: &str) -> Result<Workspace> {
+        let table = workspace_table(entity_type)?;
+
+        let workspace =
+            sqlx::query_as::<_, Workspace>(&format!("SELECT * FROM {} WHERE id = ?", table))
+                .bind(id)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        workspace.ok_or_else(|| anyhow::anyhow!("404"))
+    }
+
+    /// Organizations or teams the user is a member of, with their role in each
+    pub async fn get_user_workspaces(
+        &self,
+        user_id: &str,
+        entity_type: &str,
+    ) -> Result<Vec<Workspace>> {
+        let table = workspace_table(entity_type)?;
+
+        let workspaces = sqlx::query_as::<_, Workspace>(&format!(
+            "SELECT w.*, m.role FROM {} w
+             JOIN user_memberships m ON m.entity_id = w.id AND m.entity_type = ?
+             WHERE m.user_id = ?
+             ORDER BY w.name COLLATE NOCASE",
+            table
+        ))
+        .bind(entity_type)
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(workspaces)
+    }
+
+    pub async fn rename_workspace(
+        &self,
+        entity_type: &str,
+        id: &str,
+        name: &str,
+    ) -> Result<Workspace> {
+        let table = workspace_table(entity_type)?;
+        tracing::info!("Renaming {} {}", entity_type, id);
+
+        let workspace = sqlx::query_as::<_, Workspace>(&format!(
+            "UPDATE {} SET name = ? WHERE id = ? RETURNING *",
+            table
+        ))
+        .bind(name)
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        workspace.ok_or_else(|| anyhow::anyhow!("404"))
+    }
+
+    pub async fn get_members(&self, entity_type: &str, entity_id: &str) -> Result<Vec<Member>> {
+        let members = sqlx::query_as::<_, Member>(
+            "SELECT m.user_id, u.email, m.role, m.created_at FROM user_memberships m
+             JOIN users u ON u.id = m.user_id
+             WHERE m.entity_id = ? AND m.entity_type = ?
+             ORDER BY m.created_at",
+        )
+        .bind(entity_id)
+        .bind(entity_type)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(members)
+    }
+
+    /// Remove a user from every team in an organization, when they leave or are removed from it
+    pub async fn remove_member_from_teams(
+        &self,
+        user_id: &str,
+        organization_id: &str,
+    ) -> Result<u64> {
+        let result = sqlx::query(
+            "DELETE FROM user_memberships
+             WHERE user_id = ? AND entity_type = 'team'
+             AND entity_id IN (SELECT id FROM teams WHERE organization_id = ?)",
+        )
+        .bind(user_id)
+        .bind(organization_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Hand a workspace to another of its members, who becomes an admin if they weren't already
+    pub async fn transfer_ownership(
+        &self,
+        entity_type: &str,
+        id: &str,
+        new_owner_id: &str,
+    ) -> Result<Workspace> {
+        let table = workspace_table(entity_type)?;
+        tracing::info!(
+            "Transferring {} {} to user {}",
+            entity_type,
+            id,
+            new_owner_id
+        );
+
+        let mut tx = self.pool.begin().await?;
+
+        let result = sqlx::query(
+            "UPDATE user_memberships SET role = 'admin'
+             WHERE user_id = ? AND entity_id = ? AND entity_type = ?",
+        )
+        .bind(new_owner_id)
+        .bind(id)
+        .bind(entity_type)
+        .execute(&mut *tx)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(anyhow::anyhow!("404"));
+        }
+
+        let workspace = sqlx::query_as::<_, Workspace>(&format!(
+            "UPDATE {} SET owner_id = ? WHERE id = ? RETURNING *",
+            table
+        ))
+        .bind(new_owner_id)
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("404"))?;
+
+        tx.commit().await?;
+
+        Ok(workspace)
+    }
+
+    /// Delete an organization or team with its links, subscription and memberships
+    /// An organization takes its teams and invites with it
+    pub async fn delete_workspace(&self, entity_type: &str, id: &str) -> Result<()> {
+        let table = workspace_table(entity_type)?;
+        tracing::info!("Deleting {} {}", entity_type, id);
+
+        let mut tx = self.pool.begin().await?;
+
+        let team_ids: Vec<String> = if entity_type == "organization" {
+            sqlx::query_scalar("SELECT id FROM teams WHERE organization_id = ?")
+                .bind(id)
+                .fetch_all(&mut *tx)
+                .await?
+        } else {
+            Vec::new()
+        };
+
+        let mut owners: Vec<(&str, &str)> = vec![(id, entity_type)];
+        owners.extend(team_ids.iter().map(|id| (id.as_str(), "team")));
+
+        for (owner_id, owner_type) in &owners {
+            for query in [
+                "DELETE FROM links WHERE owner_id = ? AND owner_type = ?",
+                "DELETE FROM subscriptions WHERE entity_id = ? AND entity_type = ?",
+                "DELETE FROM user_memberships WHERE entity_id = ? AND entity_type = ?",
+            ] {
+                sqlx::query(query)
+                    .bind(owner_id)
+                    .bind(owner_type)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+
+        if entity_type == "organization" {
+            sqlx::query("DELETE FROM teams WHERE organization_id = ?")
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query("DELETE FROM invites WHERE organization_id = ?")
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        let result = sqlx::query(&format!("DELETE FROM {} WHERE id = ?", table))
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(anyhow::anyhow!("404"));
+        }
+
+        tx.commit().await?;
+
+        tracing::info!(
+            "Deleted {} {} with {} teams",
+            entity_type,
+            id,
+            team_ids.len()
+        );
+        Ok(())
+    }
+}
+
+/// Table holding workspaces with the given membership entity type
+fn workspace_table(entity_type: &str) -> Result<&'static str> {
+    match entity_type {
+        "organization" => Ok("organizations"),
+        "team" => Ok("teams"),
+        _ => Err(anyhow::anyhow!("Unknown workspace type {}", entity_type)),
+    }
 }
@@ -0,0 +1,160 @@
+use crate::mailer::Email;
+
+/// Escape text for interpolation into HTML element content or a quoted attribute
+pub fn escape_html(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Wrap already-escaped HTML content in the shared email layout
+fn layout(title: &str, content: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\
+         <html><head><meta charset=\"utf-8\"><title>{}</title></head>\
+         <body style=\"font-family: sans-serif; line-height: 1.5; color: #1f2937;\">{}\
+         <p style=\"color: #6b7280; font-size: 12px;\">Sent by OmegaTab</p></body></html>",
+        escape_html(title),
+        content
+    )
+}
+
+/// Email with a single-use link, for email verification and password resets
+pub fn action_link(
+    to: &str,
+    subject: &str,
+    action: &str,
+    link: &str,
+    expires_in_hours: i64,
+) -> Email {
+    let text = format!(
+        "Use the link below to {action}. It expires in {expires_in_hours} hour(s).\n\n\
+         {link}\n\n\
+         If you didn't ask for this, you can ignore this email."
+    );
+    let html = layout(
+        subject,
+        &format!(
+            "<p>Use the link below to {}. It expires in {} hour(s).</p>\
+             <p><a href=\"{}\">{}</a></p>\
+             <p>If you didn't ask for this, you can ignore this email.</p>",
+            escape_html(action),
+            expires_in_hours,
+            escape_html(link),
+            escape_html(link)
+        ),
+    );
+
+    Email {
+        to: to.to_string(),
+        subject: subject.to_string(),
+        html,
+        text,
+    }
+}
+
+/// Feedback forwarded to customer support
+pub fn feedback(
+    to: &str,
+    user_id: &str,
+    user_email: &str,
+    reasons: Option<&str>,
+    comment: Option<&str>,
+) -> Email {
+    let subject = format!("Feedback from: {}", user_email);
+    let reasons = reasons.unwrap_or("None given");
+    let comment = comment.unwrap_or("");
+
+    let text = format!(
+        "Feedback from user: {user_id} | {user_email}\n\nReasons: {reasons}\n\nFeedback:\n{comment}"
+    );
+    let html = layout(
+        &subject,
+        &format!(
+            "<p>Feedback from user: {} | {}</p>\
+             <p>Reasons: {}</p>\
+             <p>Feedback:</p><p style=\"white-space: pre-wrap;\">{}</p>",
+            escape_html(user_id),
+            escape_html(user_email),
+            escape_html(reasons),
+            escape_html(comment)
+        ),
+    );
+
+    Email {
+        to: to.to_string(),
+        subject,
+        html,
+        text,
+    }
+}
+
+/// Final copy of a user's data, sent just before their account is deleted
+pub fn account_export(to: &str, export_json: &str) -> Email {
+    let subject = "Your OmegaTab data export".to_string();
+
+    let text = format!(
+        "Your OmegaTab account is being deleted. Here is a copy of your links and settings.\n\n{export_json}"
+    );
+    let html = layout(
+        &subject,
+        &format!(
+            "<p>Your OmegaTab account is being deleted. Here is a copy of your links and settings.</p>\
+             <pre style=\"white-space: pre-wrap;\">{}</pre>",
+            escape_html(export_json)
+        ),
+    );
+
+    Email {
+        to: to.to_string(),
+        subject,
+        html,
+        text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_markup_and_quotes() {
+        assert_eq!(
+            escape_html(r#"<a href="x" title='y'>&</a>"#),
+            "&lt;a href=&quot;x&quot; title=&#x27;y&#x27;&gt;&amp;&lt;/a&gt;"
+        );
+        assert_eq!(escape_html("plain text"), "plain text");
+    }
+
+    #[test]
+    fn feedback_escapes_html_but_not_text() {
+        let comment = r#"<script>alert("hi")</script> it's broken"#;
+        let email = feedback(
+            "support@example.com",
+            "user_1",
+            "a&b@example.com",
+            Some("<b>slow</b>"),
+            Some(comment),
+        );
+
+        assert!(!email.html.contains("<script>"));
+        assert!(email
+            .html
+            .contains("&lt;script&gt;alert(&quot;hi&quot;)&lt;/script&gt; it&#x27;s broken"));
+        assert!(email.html.contains("&lt;b&gt;slow&lt;/b&gt;"));
+        assert!(email.html.contains("a&amp;b@example.com"));
+
+        assert!(email.text.contains(comment));
+        assert!(email.text.contains("<b>slow</b>"));
+        assert_eq!(email.subject, "Feedback from: a&b@example.com");
+    }
+}
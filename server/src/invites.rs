@@ -0,0 +1,46 @@
+use crate::tokens;
+
+/// Instance setting admins use to override `REGISTRATION_MODE`
+pub const REGISTRATION_MODE_SETTING: &str = "registration_mode";
+
+/// Who can create an account with `/register` (`REGISTRATION_MODE`, default open)
+/// Invite codes can be redeemed to join an organization in any mode but closed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistrationMode {
+    Open,
+    InviteOnly,
+    Closed,
+}
+
+impl RegistrationMode {
+    pub fn parse(mode: &str) -> Option<Self> {
+        match mode.trim().to_ascii_lowercase().as_str() {
+            "open" => Some(RegistrationMode::Open),
+            "invite" | "invite-only" | "invite_only" => Some(RegistrationMode::InviteOnly),
+            "closed" => Some(RegistrationMode::Closed),
+            _ => None,
+        }
+    }
+
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mode = std::env::var("REGISTRATION_MODE").unwrap_or_default();
+        if mode.trim().is_empty() {
+            return Ok(RegistrationMode::Open);
+        }
+        RegistrationMode::parse(&mode)
+            .ok_or_else(|| anyhow::anyhow!("Unknown REGISTRATION_MODE {:?}", mode))
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RegistrationMode::Open => "open",
+            RegistrationMode::InviteOnly => "invite",
+            RegistrationMode::Closed => "closed",
+        }
+    }
+}
+
+/// New random invite code, shown to the admin once and stored as `tokens::hash`
+pub fn generate_code() -> String {
+    tokens::generate()
+}
@@ -0,0 +1,260 @@
+use anyhow::Result;
+use base64::prelude::*;
+use url::Url;
+
+/// Schemes users can pin when `ALLOWED_LINK_SCHEMES` isn't set
+const DEFAULT_ALLOWED_SCHEMES: [&str; 14] = [
+    "http",
+    "https",
+    "file",
+    "ftp",
+    "sftp",
+    "ssh",
+    "smb",
+    "mailto",
+    "tel",
+    "vscode",
+    "vscode-insiders",
+    "obsidian",
+    "notion",
+    "slack",
+];
+
+/// Icons bundled for links we can't fetch a favicon for
+const FILE_ICON: &str = include_str!("../assets/scheme_icons/file.svg");
+const TERMINAL_ICON: &str = include_str!("../assets/scheme_icons/terminal.svg");
+const VSCODE_ICON: &str = include_str!("../assets/scheme_icons/vscode.svg");
+const OBSIDIAN_ICON: &str = include_str!("../assets/scheme_icons/obsidian.svg");
+const MAIL_ICON: &str = include_str!("../assets/scheme_icons/mail.svg");
+const LINK_ICON: &str = include_str!("../assets/scheme_icons/link.svg");
+
+/// Query parameters that only exist for tracking and never change what a page shows
+const TRACKING_PARAMS: [&str; 8] = [
+    "fbclid", "gclid", "dclid", "msclkid", "mc_cid", "mc_eid", "igshid", "ref_src",
+];
+
+/// Schemes links may use, from the comma-separated `ALLOWED_LINK_SCHEMES` or the defaults
+pub fn allowed_schemes() -> Vec<String> {
+    match std::env::var("ALLOWED_LINK_SCHEMES") {
+        Ok(schemes) if !schemes.trim().is_empty() => schemes
+            .split(',')
+            .map(|scheme| scheme.trim().to_lowercase())
+            .filter(|scheme| !scheme.is_empty())
+            .collect(),
+        _ => DEFAULT_ALLOWED_SCHEMES
+            .iter()
+            .map(|scheme| scheme.to_string())
+            .collect(),
+    }
+}
+
+/// Pull a leading `scheme:` off a raw URL, if it looks like one
+fn explicit_scheme(raw: &str) -> Option<String> {
+    let (candidate, _) = raw.split_once(':')?;
+    let mut chars = candidate.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.');
+
+    if valid {
+        Some(candidate.to_lowercase())
+    } else {
+        None
+    }
+}
+
+/// Add `https://` to a user-entered URL that doesn't already carry a scheme
+/// Anything with an explicit scheme (`http://intranet`, `mailto:me@example.com`) is left untouched,
+/// while `host:port` forms like `intranet:8080` still get the default
+pub fn with_default_scheme(raw: &str) -> String {
+    let trimmed = raw.trim();
+    let has_scheme = match explicit_scheme(trimmed) {
+        Some(scheme) => {
+            trimmed[scheme.len()..].starts_with("://") || allowed_schemes().contains(&scheme)
+        }
+        None => false,
+    };
+
+    if has_scheme {
+        trimmed.to_string()
+    } else {
+        format!("https://{}", trimmed)
+    }
+}
+
+/// Parse a URL and make sure its scheme is on the allowlist, returning the scheme
+pub fn check_scheme(url: &str) -> Result<String> {
+    let scheme = Url::parse(url)?.scheme().to_string();
+    if !allowed_schemes().contains(&scheme) {
+        return Err(anyhow::anyhow!("Scheme not allowed: {}", scheme));
+    }
+    Ok(scheme)
+}
+
+/// Only http(s) links have pages we can fetch metadata and favicons from
+pub fn is_http(url: &str) -> bool {
+    Url::parse(url)
+        .map(|parsed| matches!(parsed.scheme(), "http" | "https"))
+        .unwrap_or(false)
+}
+
+/// Bundled icon for a non-HTTP scheme, as a data URI ready to store on the link
+pub fn default_icon(scheme: &str) -> String {
+    let svg = match scheme {
+        "file" | "smb" | "ftp" | "sftp" => FILE_ICON,
+        "ssh" => TERMINAL_ICON,
+        "vscode" | "vscode-insiders" => VSCODE_ICON,
+        "obsidian" => OBSIDIAN_ICON,
+        "mailto" => MAIL_ICON,
+        _ => LINK_ICON,
+    };
+    format!("data:image/svg+xml;base64,{}", BASE64_STANDARD.encode(svg))
+}
+
+/// Whether tracking parameters are stripped from canonical URLs (`STRIP_TRACKING_PARAMS`, default on)
+pub fn strip_tracking_enabled() -> bool {
+    std::env::var("STRIP_TRACKING_PARAMS")
+        .map(|v| v.to_lowercase() != "false")
+        .unwrap_or(true)
+}
+
+fn is_tracking_param(key: &str) -> bool {
+    let key = key.to_ascii_lowercase();
+    key.starts_with("utm_") || TRACKING_PARAMS.contains(&key.as_str())
+}
+
+/// Build the canonical form of a URL used for duplicate detection
+/// Keeps the scheme, lowercases the host, drops default ports and trailing slashes,
+/// and optionally removes tracking parameters like `utm_*`
+pub fn normalize(raw: &str, strip_tracking: bool) -> Result<String> {
+    let mut parsed = Url::parse(&with_default_scheme(raw))?;
+
+    // Url already lowercases hosts for http(s), but not for custom schemes
+    if let Some(host) = parsed.host_str() {
+        let lowered = host.to_lowercase();
+        if lowered != host {
+            parsed.set_host(Some(&lowered))?;
+        }
+    }
+
+    // Url only strips default ports it knows about, so handle the common ones ourselves
+    if parsed.port().is_some() && parsed.port() == default_port(parsed.scheme()) {
+        let _ = parsed.set_port(None);
+    }
+
+    if strip_tracking && parsed.query().is_some() {
+        let kept: Vec<(String, String)> = parsed
+            .query_pairs()
+            .filter(|(key, _)| !is_tracking_param(key))
+            .map(|(key, value)| (key.into_owned(), value.into_owned()))
+            .collect();
+
+        if kept.is_empty() {
+            parsed.set_query(None);
+        } else {
+            parsed.query_pairs_mut().clear().extend_pairs(kept);
+        }
+    }
+
+    let path = parsed.path().trim_end_matches('/').to_string();
+    parsed.set_path(&path);
+
+    let mut canonical = parsed.to_string();
+
+    // Special schemes always serialize an empty path as "/", trim it back off
+    if path.is_empty() && parsed.query().is_none() && parsed.fragment().is_none() {
+        canonical = canonical.trim_end_matches('/').to_string();
+    }
+
+    Ok(canonical)
+}
+
+fn default_port(scheme: &str) -> Option<u16> {
+    match scheme {
+        "http" | "ws" => Some(80),
+        "https" | "wss" => Some(443),
+        "ftp" => Some(21),
+        "ssh" => Some(22),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_scheme_only_fills_in_a_missing_one() {
+        let cases = [
+            ("example.com", "https://example.com"),
+            ("  example.com/a ", "https://example.com/a"),
+            ("intranet:8080", "https://intranet:8080"),
+            ("http://intranet", "http://intranet"),
+            ("mailto:me@example.com", "mailto:me@example.com"),
+            ("obsidian://open?vault=notes", "obsidian://open?vault=notes"),
+        ];
+        for (raw, expected) in cases {
+            assert_eq!(with_default_scheme(raw), expected, "{}", raw);
+        }
+    }
+
+    #[test]
+    fn explicit_scheme_needs_a_valid_name() {
+        assert_eq!(explicit_scheme("HTTP://x"), Some("http".to_string()));
+        assert_eq!(
+            explicit_scheme("vscode-insiders:x"),
+            Some("vscode-insiders".to_string())
+        );
+        assert_eq!(explicit_scheme("1abc:x"), None);
+        assert_eq!(explicit_scheme("no scheme"), None);
+    }
+
+    #[test]
+    fn normalizes_to_a_canonical_form() {
+        let cases = [
+            ("http://intranet", "http://intranet"),
+            ("HTTPS://Ex.com:443/a/", "https://ex.com/a"),
+            ("http://ex.com:80/", "http://ex.com"),
+            ("https://ex.com:8443/", "https://ex.com:8443"),
+            ("ssh://Host.Example:22", "ssh://host.example"),
+            ("ex.com/a/b/", "https://ex.com/a/b"),
+            ("https://ex.com/?q=1#top", "https://ex.com/?q=1#top"),
+        ];
+        for (raw, expected) in cases {
+            assert_eq!(normalize(raw, false).unwrap(), expected, "{}", raw);
+        }
+    }
+
+    #[test]
+    fn strips_tracking_params_only_when_enabled() {
+        let raw = "https://ex.com/a?utm_source=news&id=7&UTM_Medium=mail&fbclid=abc";
+        assert_eq!(normalize(raw, true).unwrap(), "https://ex.com/a?id=7");
+        assert_eq!(
+            normalize(raw, false).unwrap(),
+            "https://ex.com/a?utm_source=news&id=7&UTM_Medium=mail&fbclid=abc"
+        );
+        assert_eq!(
+            normalize("https://ex.com/?utm_source=x", true).unwrap(),
+            "https://ex.com"
+        );
+    }
+
+    #[test]
+    fn default_ports_by_scheme() {
+        assert_eq!(default_port("https"), Some(443));
+        assert_eq!(default_port("http"), Some(80));
+        assert_eq!(default_port("ssh"), Some(22));
+        assert_eq!(default_port("smb"), None);
+    }
+
+    #[test]
+    fn only_allowed_schemes_pass() {
+        for allowed in ["https://ex.com", "mailto:me@example.com", "file:///tmp/a"] {
+            assert!(check_scheme(allowed).is_ok(), "{}", allowed);
+        }
+        for rejected in ["javascript:alert(1)", "data:text/html,hi", "not a url"] {
+            assert!(check_scheme(rejected).is_err(), "{}", rejected);
+        }
+        assert!(is_http("http://ex.com"));
+        assert!(!is_http("ssh://host"));
+    }
+}
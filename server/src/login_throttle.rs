@@ -0,0 +1,281 @@
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::{Duration, Utc};
+use std::net::IpAddr;
+
+use crate::database::Database;
+use crate::password_policy::PasswordError;
+
+/// How many failures a key gets within `window` before it's locked out,
+/// and how long the first lockout lasts before doubling with each further failure
+#[derive(Debug, Clone, Copy)]
+pub struct Policy {
+    max_failures: i64,
+    window: Duration,
+    base_lockout: Duration,
+    max_lockout: Duration,
+}
+
+fn env_i64(name: &str, default: i64) -> i64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse::<i64>().ok())
+        .filter(|value| *value > 0)
+        .unwrap_or(default)
+}
+
+impl Policy {
+    fn new(max_failures: i64) -> Self {
+        Policy {
+            max_failures,
+            window: Duration::minutes(15),
+            base_lockout: Duration::seconds(env_i64("LOGIN_LOCKOUT_SECONDS", 30)),
+            max_lockout: Duration::hours(1),
+        }
+    }
+
+    /// Failed sign-ins for one account (`LOGIN_MAX_FAILURES`, default 5)
+    pub fn account() -> Self {
+        Policy::new(env_i64("LOGIN_MAX_FAILURES", 5))
+    }
+
+    /// Failed sign-ins from one IP across all accounts (`LOGIN_MAX_FAILURES_PER_IP`, default 20)
+    pub fn ip() -> Self {
+        Policy::new(env_i64("LOGIN_MAX_FAILURES_PER_IP", 20))
+    }
+
+    /// Registrations from one IP, successful or not (`REGISTER_MAX_ATTEMPTS_PER_IP`, default 10)
+    pub fn register() -> Self {
+        Policy::new(env_i64("REGISTER_MAX_ATTEMPTS_PER_IP", 10))
+    }
+
+    /// Lockout after the given number of failures, if it's past the limit
+    fn lockout(&self, failures: i64) -> Option<Duration> {
+        let over = failures - self.max_failures;
+        if over < 0 {
+            return None;
+        }
+        let lockout = self.base_lockout * 2i32.pow(over.min(16) as u32);
+        Some(lockout.min(self.max_lockout))
+    }
+}
+
+pub fn account_key(email: &str) -> String {
+    format!("account:{}", email.trim().to_lowercase())
+}
+
+pub fn ip_key(ip: &IpAddr) -> String {
+    format!("ip:{}", ip)
+}
+
+pub fn register_key(ip: &IpAddr) -> String {
+    format!("register:{}", ip)
+}
+
+/// Error for credential endpoints, which can answer 429 with a `Retry-After`
+/// or refuse a new password with a code the client can show
+#[derive(Debug)]
+pub enum LoginError {
+    Status(StatusCode),
+    TooManyAttempts { retry_after: i64 },
+    Password(PasswordError),
+}
+
+impl From<StatusCode> for LoginError {
+    fn from(status: StatusCode) -> Self {
+        LoginError::Status(status)
+    }
+}
+
+impl From<PasswordError> for LoginError {
+    fn from(error: PasswordError) -> Self {
+        LoginError::Password(error)
+    }
+}
+
+impl IntoResponse for LoginError {
+    fn into_response(self) -> Response {
+        match self {
+            LoginError::Status(status) => status.into_response(),
+            LoginError::TooManyAttempts { retry_after } => {
+                let mut response = StatusCode::TOO_MANY_REQUESTS.into_response();
+                response
+                    .headers_mut()
+                    .insert(header::RETRY_AFTER, HeaderValue::from(retry_after.max(1)));
+                response
+            }
+            LoginError::Password(error) => error.into_response(),
+        }
+    }
+}
+
+/// Refuse the attempt if any of the keys is locked out
+pub async fn check(database: &Database, keys: &[&str]) -> Result<(), LoginError> {
+    let mut retry_after = 0;
+    for key in keys {
+        let locked_until = database.get_login_lockout(key).await.map_err(|e| {
+            tracing::error!("Failed to check login lockout: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        if let Some(locked_until) = locked_until {
+            retry_after = retry_after.max((locked_until - Utc::now()).num_seconds() + 1);
+        }
+    }
+
+    if retry_after > 0 {
+        tracing::warn!("Rejected attempt for locked out {:?}", keys);
+        return Err(LoginError::TooManyAttempts { retry_after });
+    }
+    Ok(())
+}
+
+/// Count a failed attempt against the key, locking it out once it's over the policy's limit
+pub async fn record_failure(database: &Database, key: &str, policy: Policy) {
+    let now = Utc::now();
+    let failures = match database
+        .record_login_failure(key, &(now + policy.window))
+        .await
+    {
+        Ok(failures) => failures,
+        Err(e) => {
+            tracing::error!("Failed to record login failure: {:?}", e);
+            return;
+        }
+    };
+
+    if let Some(lockout) = policy.lockout(failures) {
+        let locked_until = now + lockout;
+        tracing::warn!(
+            "Locking out {} for {}s after {} failures",
+            key,
+            lockout.num_seconds(),
+            failures
+        );
+        // Failures keep counting for a while after the lockout, so the next one backs off further
+        if let Err(e) = database
+            .lock_out_login(key, &locked_until, &(locked_until + policy.window))
+            .await
+        {
+            tracing::error!("Failed to lock out login: {:?}", e);
+        }
+    }
+}
+
+/// Forget an account's failures after it signs in successfully
+pub async fn clear(database: &Database, key: &str) {
+    if let Err(e) = database.clear_login_attempts(key).await {
+        tracing::error!("Failed to clear login attempts: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_failures: i64) -> Policy {
+        Policy {
+            max_failures,
+            window: Duration::minutes(15),
+            base_lockout: Duration::seconds(30),
+            max_lockout: Duration::hours(1),
+        }
+    }
+
+    async fn is_locked_out(database: &Database, key: &str) -> bool {
+        matches!(
+            check(database, &[key]).await,
+            Err(LoginError::TooManyAttempts { .. })
+        )
+    }
+
+    #[test]
+    fn lockout_doubles_up_to_the_limit() {
+        let policy = policy(3);
+        assert_eq!(policy.lockout(2), None);
+        assert_eq!(policy.lockout(3), Some(Duration::seconds(30)));
+        assert_eq!(policy.lockout(4), Some(Duration::seconds(60)));
+        assert_eq!(policy.lockout(5), Some(Duration::seconds(120)));
+        assert_eq!(policy.lockout(100), Some(Duration::hours(1)));
+    }
+
+    #[test]
+    fn account_keys_ignore_case_and_whitespace() {
+        assert_eq!(
+            account_key(" Alice@Example.com"),
+            account_key("alice@example.com")
+        );
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        assert_ne!(ip_key(&ip), register_key(&ip));
+    }
+
+    #[tokio::test]
+    async fn locks_out_after_too_many_failures() {
+        let database = Database::in_memory().await.unwrap();
+        let key = account_key("alice@example.com");
+
+        for _ in 0..2 {
+            record_failure(&database, &key, policy(3)).await;
+            assert!(!is_locked_out(&database, &key).await);
+        }
+        record_failure(&database, &key, policy(3)).await;
+
+        match check(&database, &[&key]).await {
+            Err(LoginError::TooManyAttempts { retry_after }) => {
+                assert!((1..=31).contains(&retry_after), "{}", retry_after)
+            }
+            other => panic!("expected a lockout, got {:?}", other),
+        }
+        let response = check(&database, &[&key]).await.unwrap_err().into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key(header::RETRY_AFTER));
+    }
+
+    #[tokio::test]
+    async fn success_clears_the_failures() {
+        let database = Database::in_memory().await.unwrap();
+        let key = account_key("alice@example.com");
+
+        for _ in 0..2 {
+            record_failure(&database, &key, policy(3)).await;
+        }
+        clear(&database, &key).await;
+
+        // Back to a full allowance, so two more failures don't lock the account
+        for _ in 0..2 {
+            record_failure(&database, &key, policy(3)).await;
+        }
+        assert!(!is_locked_out(&database, &key).await);
+    }
+
+    #[tokio::test]
+    async fn ip_failures_count_across_accounts() {
+        let database = Database::in_memory().await.unwrap();
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        let shared_key = ip_key(&ip);
+
+        let emails = ["a@example.com", "b@example.com", "c@example.com"];
+        for email in emails {
+            record_failure(&database, &account_key(email), policy(3)).await;
+            record_failure(&database, &shared_key, policy(3)).await;
+        }
+
+        // One failure each doesn't lock any account, but the IP has hit its limit
+        for email in emails {
+            assert!(!is_locked_out(&database, &account_key(email)).await);
+        }
+        assert!(is_locked_out(&database, &shared_key).await);
+        // A fresh account is still refused from that IP
+        let fresh = account_key("d@example.com");
+        assert!(!is_locked_out(&database, &fresh).await);
+        assert!(check(&database, &[&fresh, &shared_key]).await.is_err());
+
+        // Signing in clears the account, not the IP
+        clear(&database, &account_key("a@example.com")).await;
+        assert!(is_locked_out(&database, &shared_key).await);
+
+        let other_ip: IpAddr = "198.51.100.1".parse().unwrap();
+        assert!(!is_locked_out(&database, &ip_key(&other_ip)).await);
+    }
+}
@@ -0,0 +1,212 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use lettre::message::{Mailbox, MultiPart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::database;
+use crate::resend::ResendClient;
+
+/// Sender used when `MAIL_FROM` isn't set
+const DEFAULT_FROM: &str = "OmegaTab <no-reply@localhost>";
+
+/// Resend only accepts senders on a verified domain, so it keeps the address it always sent from
+const RESEND_DEFAULT_FROM: &str = "evan@updates.omega-tab.evanrobertson.dev";
+
+/// An outgoing email with both HTML and plain-text bodies
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub html: String,
+    pub text: String,
+}
+
+/// Delivers email, so handlers don't care whether it goes through Resend, SMTP or nowhere at all
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: &Email) -> Result<()>;
+}
+
+/// Build a multipart/alternative message with the text and HTML bodies
+fn build_message(from: &Mailbox, email: &Email) -> Result<Message> {
+    let message = Message::builder()
+        .from(from.clone())
+        .to(email.to.parse()?)
+        .subject(&email.subject)
+        .multipart(MultiPart::alternative_plain_html(
+            email.text.clone(),
+            email.html.clone(),
+        ))?;
+    Ok(message)
+}
+
+#[async_trait]
+impl Mailer for ResendClient {
+    async fn send(&self, email: &Email) -> Result<()> {
+        self.send_email(&email.to, &email.subject, &email.html, &email.text)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to send email through Resend: {:?}", e))
+    }
+}
+
+/// Sends through an SMTP relay, upgrading the connection with STARTTLS
+pub struct SmtpMailer {
+    from: Mailbox,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpMailer {
+    /// Configure from `SMTP_HOST`, `SMTP_PORT` (default 587), `SMTP_USERNAME` and `SMTP_PASSWORD`
+    pub fn from_env(from: Mailbox) -> Result<Self> {
+        let host = std::env::var("SMTP_HOST")
+            .map_err(|_| anyhow::anyhow!("SMTP_HOST must be set to use the SMTP mailer"))?;
+        let port = std::env::var("SMTP_PORT")
+            .ok()
+            .and_then(|port| port.parse::<u16>().ok())
+            .unwrap_or(587);
+
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host)?.port(port);
+        if let Ok(username) = std::env::var("SMTP_USERNAME") {
+            let password = std::env::var("SMTP_PASSWORD").unwrap_or_default();
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        tracing::info!("Using SMTP mailer through {}:{}", host, port);
+        Ok(SmtpMailer {
+            from,
+            transport: builder.build(),
+        })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: &Email) -> Result<()> {
+        tracing::info!("Sending email over SMTP to: {}", email.to);
+        let message = build_message(&self.from, email)?;
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
+
+/// Writes each email to a `.eml` file instead of sending it, for development and local installs
+pub struct SpoolMailer {
+    from: Mailbox,
+    dir: PathBuf,
+}
+
+impl SpoolMailer {
+    /// Spool into `MAIL_SPOOL_DIR`, or a `mail` folder next to the database
+    pub fn from_env(from: Mailbox) -> Result<Self> {
+        let dir = std::env::var("MAIL_SPOOL_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| database::get_data_dir().join("mail"));
+        std::fs::create_dir_all(&dir)?;
+
+        tracing::info!(
+            "Using spool mailer, emails will be written to {}",
+            dir.display()
+        );
+        Ok(SpoolMailer { from, dir })
+    }
+}
+
+#[async_trait]
+impl Mailer for SpoolMailer {
+    async fn send(&self, email: &Email) -> Result<()> {
+        let message = build_message(&self.from, email)?;
+        let path = self.dir.join(format!(
+            "{}-{}.eml",
+            chrono::Utc::now().format("%Y%m%dT%H%M%S"),
+            uuid::Uuid::new_v4()
+        ));
+
+        tokio::fs::write(&path, message.formatted()).await?;
+        tracing::info!("Wrote email to {} into {}", email.to, path.display());
+        Ok(())
+    }
+}
+
+/// Writes emails to the log instead of sending them
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, email: &Email) -> Result<()> {
+        tracing::info!(
+            "Email to {} with subject {:?}:\n{}",
+            email.to,
+            email.subject,
+            email.text
+        );
+        Ok(())
+    }
+}
+
+/// Pick a mailer from `MAILER` (`resend`, `smtp`, `spool` or `log`), sending as `MAIL_FROM`
+/// Defaults to Resend when `RESEND_API_KEY` is set and the log mailer otherwise
+/// A mailer that's asked for but can't be set up is an error rather than a fallback to the log,
+/// which would put reset and verification links in production logs
+pub fn from_env() -> Result<Arc<dyn Mailer>> {
+    let backend = std::env::var("MAILER")
+        .unwrap_or_else(|_| {
+            if std::env::var("RESEND_API_KEY").is_ok() {
+                "resend".to_string()
+            } else {
+                "log".to_string()
+            }
+        })
+        .to_lowercase();
+
+    let default_from = if backend == "resend" {
+        RESEND_DEFAULT_FROM
+    } else {
+        DEFAULT_FROM
+    };
+    let from_address = std::env::var("MAIL_FROM")
+        .ok()
+        .filter(|from| !from.trim().is_empty())
+        .unwrap_or_else(|| default_from.to_string());
+    let from: Mailbox = match from_address.parse() {
+        Ok(from) => from,
+        Err(e) => {
+            tracing::warn!("Invalid MAIL_FROM {:?}: {:?}", from_address, e);
+            default_from
+                .parse()
+                .expect("default sender is a valid mailbox")
+        }
+    };
+
+    for_backend(&backend, from)
+}
+
+fn for_backend(backend: &str, from: Mailbox) -> Result<Arc<dyn Mailer>> {
+    match backend {
+        "resend" => Ok(Arc::new(ResendClient::new(&from.to_string()))),
+        "smtp" => SmtpMailer::from_env(from).map(|mailer| Arc::new(mailer) as Arc<dyn Mailer>),
+        "spool" => SpoolMailer::from_env(from).map(|mailer| Arc::new(mailer) as Arc<dyn Mailer>),
+        "log" => Ok(Arc::new(LogMailer)),
+        other => Err(anyhow::anyhow!("Unknown MAILER {:?}", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from() -> Mailbox {
+        DEFAULT_FROM.parse().unwrap()
+    }
+
+    #[test]
+    fn misconfigured_backends_are_errors() {
+        assert!(for_backend("carrier-pigeon", from()).is_err());
+        if std::env::var("SMTP_HOST").is_err() {
+            assert!(for_backend("smtp", from()).is_err());
+        }
+        assert!(for_backend("log", from()).is_ok());
+    }
+}
@@ -1,18 +1,34 @@
 // Hide console window on Windows in release builds
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+mod api_tokens;
 mod assets;
+mod auth;
 mod brave;
 mod database;
-mod middleware;
+mod email_templates;
+mod invites;
+mod link_url;
+mod login_throttle;
+mod mailer;
+mod oidc;
+mod passkeys;
+mod password_policy;
+mod proxy_auth;
+mod rate_limit;
 mod resend;
+mod tokens;
+mod totp;
 mod tray;
 mod user_jwt;
+mod workspaces;
 
 use axum::{
-    extract::{Extension, Json, Path, State},
+    extract::{ConnectInfo, DefaultBodyLimit, Extension, Json, Path, Query, State},
     http::{HeaderMap, HeaderValue, Method, StatusCode},
-    routing::{delete, get, post},
+    middleware,
+    response::Redirect,
+    routing::{delete, get, post, put},
     Router,
 };
 use base64::prelude::*;
@@ -20,21 +36,32 @@ use brave::Brave;
 use chrono::Utc;
 use database::Database;
 use dotenv::dotenv;
-use middleware::{authenticate_user, UserContext};
-use resend::ResendClient;
+use auth::{AdminUser, AuthUser};
+use invites::RegistrationMode;
+use login_throttle::LoginError;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::{collections::HashMap, env, sync::mpsc, thread};
-use tower_http::cors::{Any, CorsLayer};
+use std::{
+    collections::HashMap,
+    env,
+    net::{IpAddr, SocketAddr},
+    sync::mpsc,
+    sync::Arc,
+    thread,
+};
+use tower_http::{
+    cors::{Any, CorsLayer},
+    limit::RequestBodyLimitLayer,
+    timeout::TimeoutLayer,
+    trace::TraceLayer,
+};
 use tray::TrayMessage;
+use workspaces::{Kind, Role};
 use tracing_subscriber::prelude::*;
 use url::Url;
-
-#[derive(Deserialize)]
-pub struct CreateUserRequest {
-    user_id: String,
-    email: String,
-}
+use webauthn_rs::prelude::{
+    Passkey, PublicKeyCredential, RegisterPublicKeyCredential, RequestChallengeResponse, Webauthn,
+};
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct CreateLinkRequest {
@@ -45,6 +72,8 @@ pub struct CreateLinkRequest {
     owner_type: String,
     owner_id: String,
     column_type: String,
+    #[serde(default)]
+    allow_duplicate: bool,
 }
 
 #[derive(Deserialize)]
@@ -55,6 +84,8 @@ pub struct UpdateLinkRequest {
     title: Option<String>,
     icon: Option<String>,
     column_type: Option<String>,
+    #[serde(default)]
+    allow_duplicate: bool,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
@@ -99,20 +130,284 @@ pub struct UserDataResponse {
 pub struct LoginRequest {
     email: String,
     password: String,
+    device_label: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct RegisterRequest {
     email: String,
     password: String,
+    device_label: Option<String>,
+    /// Required when registration is invite-only, and joins the invite's organization
+    invite_code: Option<String>,
 }
 
 #[derive(Serialize)]
 pub struct AuthResponse {
     token: String,
+    refresh_token: String,
     user: database::User,
 }
 
+/// Login either completes, or asks for a second factor when the account has 2FA on
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum LoginResponse {
+    Authenticated(AuthResponse),
+    TwoFactorRequired {
+        two_factor_required: bool,
+        challenge_token: String,
+    },
+}
+
+#[derive(Deserialize)]
+pub struct TwoFactorLoginRequest {
+    challenge_token: String,
+    code: Option<String>,
+    recovery_code: Option<String>,
+    device_label: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct TwoFactorStatusResponse {
+    enabled: bool,
+    recovery_codes_remaining: i64,
+}
+
+#[derive(Serialize)]
+pub struct TotpEnrollmentResponse {
+    secret: String,
+    otpauth_uri: String,
+}
+
+#[derive(Deserialize)]
+pub struct TotpCodeRequest {
+    code: String,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryCodesResponse {
+    recovery_codes: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct PasskeyRegistrationStartResponse {
+    ceremony_id: String,
+    options: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+pub struct PasskeyRegistrationFinishRequest {
+    ceremony_id: String,
+    name: Option<String>,
+    credential: RegisterPublicKeyCredential,
+}
+
+#[derive(Serialize)]
+pub struct PasskeyLoginStartResponse {
+    ceremony_id: String,
+    options: RequestChallengeResponse,
+}
+
+#[derive(Deserialize)]
+pub struct PasskeyLoginFinishRequest {
+    ceremony_id: String,
+    credential: PublicKeyCredential,
+    device_label: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateApiTokenRequest {
+    name: String,
+    scopes: Vec<String>,
+    expires_in_days: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct CreateApiTokenResponse {
+    /// Only ever returned here, the server keeps a hash
+    token: String,
+    api_token: database::ApiToken,
+}
+
+#[derive(Serialize)]
+pub struct AuthConfigResponse {
+    mode: &'static str,
+    registration: &'static str,
+}
+
+#[derive(Serialize)]
+pub struct OidcConfigResponse {
+    enabled: bool,
+    provider_name: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct OidcLinkResponse {
+    url: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct OidcCallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct DisableTwoFactorRequest {
+    password: String,
+    code: Option<String>,
+    recovery_code: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RefreshTokenRequest {
+    refresh_token: String,
+}
+
+#[derive(Serialize)]
+pub struct RefreshTokenResponse {
+    token: String,
+    refresh_token: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ConfirmEmailRequest {
+    token: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PasswordResetRequest {
+    email: String,
+}
+
+#[derive(Deserialize)]
+pub struct ConfirmPasswordResetRequest {
+    token: String,
+    new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    current_password: String,
+    new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChangeEmailRequest {
+    current_password: String,
+    new_email: String,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteAccountRequest {
+    /// Required for accounts with a password, passwordless ones need a recent sign-in instead
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    email_export: bool,
+}
+
+#[derive(Serialize)]
+pub struct SessionResponse {
+    #[serde(flatten)]
+    session: database::Session,
+    current: bool,
+}
+
+#[derive(Deserialize)]
+pub struct CreateInviteRequest {
+    /// Instance admins can leave this out for an invite that doesn't join an organization
+    organization_id: Option<String>,
+    /// Only this address can register with the invite
+    email: Option<String>,
+    role: Option<String>,
+    max_uses: Option<i64>,
+    expires_in_days: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct CreateInviteResponse {
+    /// Only ever returned here, the server keeps a hash
+    code: String,
+    invite: database::Invite,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct InvitesQuery {
+    organization_id: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AdminUsersQuery {
+    /// Part of an email to search for
+    query: Option<String>,
+    page: Option<i64>,
+    per_page: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct AdminUsersResponse {
+    users: Vec<database::User>,
+    page: i64,
+    per_page: i64,
+    total: i64,
+}
+
+#[derive(Serialize)]
+pub struct RegistrationSettings {
+    mode: &'static str,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateRegistrationRequest {
+    mode: String,
+}
+
+#[derive(Deserialize)]
+pub struct CreateWorkspaceRequest {
+    name: String,
+    /// Organization a new team belongs to
+    organization_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RenameWorkspaceRequest {
+    name: String,
+}
+
+#[derive(Deserialize)]
+pub struct AddMemberRequest {
+    email: String,
+    role: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateMemberRequest {
+    role: String,
+}
+
+#[derive(Deserialize)]
+pub struct TransferWorkspaceRequest {
+    user_id: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AuditQuery {
+    organization_id: Option<String>,
+    page: Option<i64>,
+    per_page: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct AuditResponse {
+    events: Vec<database::AuditEvent>,
+    page: i64,
+    per_page: i64,
+    total: i64,
+}
+
 // New struct for staging login request
 #[derive(Deserialize, Debug)]
 pub struct StagingLoginRequest {
@@ -123,6 +418,21 @@ pub struct StagingLoginRequest {
 pub struct AppState {
     pub client: reqwest::Client,
     pub database: Database,
+    pub mailer: Arc<dyn mailer::Mailer>,
+    /// None when the relying party couldn't be configured, which leaves passkeys off
+    pub webauthn: Option<Arc<Webauthn>>,
+    /// Search suggestions, None when the Brave API isn't configured
+    pub brave: Option<Arc<Brave>>,
+    pub oidc: Option<Arc<oidc::OidcConfig>>,
+    pub proxy_auth: Option<Arc<proxy_auth::ProxyAuthConfig>>,
+    pub trusted_proxies: Arc<proxy_auth::TrustedProxies>,
+    pub jwt_keys: Arc<user_jwt::JwtKeys>,
+    pub rate_limiter: Arc<rate_limit::RateLimiter>,
+    pub password_policy: Arc<password_policy::PasswordPolicy>,
+    /// Default from `REGISTRATION_MODE`, admins can override it at runtime
+    pub registration_mode: RegistrationMode,
+    /// The only user in single-user local mode, injected into every request
+    pub local_user: Option<AuthUser>,
 }
 
 fn main() {
@@ -261,7 +571,8 @@ async fn runtime(shutdown_rx: mpsc::Receiver<()>) {
                     .allow_headers(Any)
             }
         }
-    };
+    }
+    .expose_headers(rate_limit::EXPOSED_HEADERS);
 
     let client = reqwest::Client::new();
 
@@ -276,18 +587,198 @@ async fn runtime(shutdown_rx: mpsc::Receiver<()>) {
         }
     };
 
-    let app_state = AppState { client, database };
+    spawn_trash_retention_job(database.clone());
+    spawn_session_cleanup_job(database.clone());
+
+    // A bad WebAuthn setting only costs passkeys, so the rest of the app still starts
+    let webauthn = match passkeys::from_env() {
+        Ok(webauthn) => Some(Arc::new(webauthn)),
+        Err(e) => {
+            tracing::error!("Error configuring WebAuthn, passkeys are disabled: {:?}", e);
+            eprintln!("Error configuring WebAuthn, passkeys are disabled: {:?}", e);
+            None
+        }
+    };
+
+    let brave = Brave::from_env().map(Arc::new);
+    if brave.is_none() {
+        tracing::warn!(
+            "BRAVE_SUGGEST_URL or BRAVE_API_KEY is not set, search suggestions are disabled"
+        );
+    }
+
+    let proxy_auth = match proxy_auth::ProxyAuthConfig::from_env() {
+        Ok(proxy_auth) => proxy_auth.map(Arc::new),
+        Err(e) => {
+            tracing::error!("Error configuring proxy authentication: {:?}", e);
+            eprintln!("Error configuring proxy authentication: {:?}", e);
+            return;
+        }
+    };
+
+    let trusted_proxies = match proxy_auth::TrustedProxies::from_env(proxy_auth.as_deref()) {
+        Ok(trusted_proxies) => Arc::new(trusted_proxies),
+        Err(e) => {
+            tracing::error!("Error configuring trusted proxies: {:?}", e);
+            eprintln!("Error configuring trusted proxies: {:?}", e);
+            return;
+        }
+    };
+
+    let mailer = match mailer::from_env() {
+        Ok(mailer) => mailer,
+        Err(e) => {
+            tracing::error!("Error configuring the mailer: {:?}", e);
+            eprintln!("Error configuring the mailer: {:?}", e);
+            return;
+        }
+    };
+
+    let local_mode = env::var("AUTH_MODE")
+        .map(|mode| mode.eq_ignore_ascii_case("local"))
+        .unwrap_or(false);
+
+    let password_policy = match password_policy::PasswordPolicy::from_env() {
+        Ok(password_policy) => Arc::new(password_policy),
+        Err(e) => {
+            tracing::error!("Error configuring password policy: {:?}", e);
+            eprintln!("Error configuring password policy: {:?}", e);
+            return;
+        }
+    };
+
+    let registration_mode = match RegistrationMode::from_env() {
+        Ok(registration_mode) => registration_mode,
+        Err(e) => {
+            tracing::error!("Error configuring registration: {:?}", e);
+            eprintln!("Error configuring registration: {:?}", e);
+            return;
+        }
+    };
+
+    let jwt_keys = match user_jwt::JwtKeys::load(&database::get_data_dir()) {
+        Ok(jwt_keys) => Arc::new(jwt_keys),
+        Err(e) => {
+            tracing::error!("Error loading JWT signing keys: {:?}", e);
+            eprintln!("Error loading JWT signing keys: {:?}", e);
+            return;
+        }
+    };
+
+    let mut app_state = AppState {
+        client,
+        database,
+        mailer,
+        webauthn,
+        brave,
+        oidc: oidc::OidcConfig::from_env().map(Arc::new),
+        proxy_auth,
+        trusted_proxies,
+        jwt_keys,
+        rate_limiter: Arc::new(rate_limit::RateLimiter::from_env()),
+        password_policy,
+        registration_mode,
+        local_user: None,
+    };
+
+    if !local_mode && app_state.proxy_auth.is_none() {
+        prompt_former_local_user(&app_state).await;
+    }
+
+    if local_mode {
+        match ensure_local_user(&app_state).await {
+            Ok(user) => app_state.local_user = Some(user),
+            Err(status) => {
+                tracing::error!("Error setting up the local user: {}", status);
+                eprintln!("Error setting up the local user: {}", status);
+                return;
+            }
+        }
+    }
 
     // Build API router with /api prefix
-    let api_routes = Router::new()
-        // Authentication routes (public - no middleware)
+    // Routes that sign users in or hand out JWTs
+    // They don't exist when a trusted proxy or single-user local mode signs users in
+    let token_routes = Router::new()
         .route("/register", post(register_handler))
         .route("/login", post(login_handler))
+        .route("/login/2fa", post(login_two_factor_handler))
+        .route("/oidc/login", get(oidc_login_handler))
+        .route("/oidc/link", post(oidc_link_handler))
+        .route("/oidc/callback", get(oidc_callback_handler))
+        .route("/passkeys/login/start", post(passkey_login_start_handler))
+        .route("/passkeys/login/finish", post(passkey_login_finish_handler))
+        .route("/token/refresh", post(refresh_token_handler))
+        .route("/logout", post(logout_handler))
+        .route("/email/verify/confirm", post(confirm_email_handler))
+        .route(
+            "/password/reset/request",
+            post(request_password_reset_handler),
+        )
+        .route(
+            "/password/reset/confirm",
+            post(confirm_password_reset_handler),
+        )
+        // Add staging login route - doesn't need authentication
+        .route("/staging_login", post(staging_login_handler));
+    let token_routes = if app_state.local_user.is_none() && app_state.proxy_auth.is_none() {
+        token_routes
+    } else {
+        Router::new()
+    };
+
+    // Handlers that take an AuthUser require a signed-in user, the rest are public
+    let api_routes = Router::new()
+        .merge(token_routes)
+        .route("/auth/config", get(auth_config_handler))
+        .route("/oidc/config", get(oidc_config_handler))
+        .route("/logout/all", post(logout_all_handler))
+        // Email verification
+        .route(
+            "/email/verify/request",
+            post(request_email_verification_handler),
+        )
+        // Passkeys
+        .route("/passkeys", get(passkeys_handler))
+        .route("/passkeys/{passkey_id}", delete(delete_passkey_handler))
+        .route(
+            "/passkeys/register/start",
+            post(passkey_register_start_handler),
+        )
+        .route(
+            "/passkeys/register/finish",
+            post(passkey_register_finish_handler),
+        )
+        // Personal API tokens
+        .route(
+            "/api_tokens",
+            get(api_tokens_handler).post(create_api_token_handler),
+        )
+        .route("/api_tokens/{token_id}", delete(delete_api_token_handler))
+        // Invites to register and join an organization
+        .route("/invites", get(invites_handler).post(create_invite_handler))
+        .route("/invites/{invite_id}", delete(delete_invite_handler))
+        // Two-factor authentication
+        .route("/2fa", get(two_factor_status_handler))
+        .route("/2fa/enroll", post(totp_enroll_handler))
+        .route("/2fa/enable", post(totp_enable_handler))
+        .route("/2fa/disable", post(two_factor_disable_handler))
+        // Account credentials
+        .route("/user/password", put(change_password_handler))
+        .route("/user/email", post(request_email_change_handler))
+        .route("/user/email/confirm", post(confirm_email_change_handler))
+        // active sessions across devices
+        .route("/sessions", get(sessions_handler))
+        .route("/sessions/{session_id}", delete(revoke_session_handler))
         .route("/health", get(health_check))
         // create and update links
         .route("/link", post(create_link).put(update_link))
         // read links
         .route("/user/links", get(links_handler))
+        // list links that share a canonical URL
+        .route("/user/links/duplicates", get(duplicate_links_handler))
+        // list links in the trash
+        .route("/user/links/trash", get(trashed_links_handler))
         // delete link
         .route(
             "/link/{link_id}",
@@ -295,25 +786,77 @@ async fn runtime(shutdown_rx: mpsc::Receiver<()>) {
                 delete_link(state, path, user_context)
             }),
         )
+        // restore a link from the trash
+        .route("/link/{link_id}/restore", post(restore_link))
+        // permanently delete a link from the trash
+        .route("/link/{link_id}/purge", delete(purge_link))
+        // link version history
+        .route("/link/{link_id}/revisions", get(link_revisions_handler))
+        .route(
+            "/link/{link_id}/revisions/{revision_id}/revert",
+            post(revert_link),
+        )
         // create user
-        .route("/create_user", post(create_user_handler))
         // get user
-        .route("/user", get(get_user_handler))
-        // get suggestion
         .route(
-            "/suggest/{query}",
-            get(move |path, user_context, headers| suggest_handler(path, user_context, headers)),
+            "/user",
+            get(get_user_handler).delete(delete_account_handler),
         )
+        // get suggestion
+        .route("/suggest/{query}", get(suggest_handler))
         .route("/feedback", post(feedback_handler))
         .route(
             "/settings",
             post(create_settings).put(update_settings).get(get_settings),
         )
         .route("/user_data", get(get_user_data_handler))
-        // Add staging login route - doesn't need authentication
-        .route("/staging_login", post(staging_login_handler))
-        .with_state(app_state)
-        .layer(axum::middleware::from_fn(authenticate_user));
+        .route("/audit", get(audit_handler))
+        // Instance administration
+        .route("/admin/users", get(admin_users_handler))
+        .route("/admin/users/{user_id}", delete(admin_delete_user_handler))
+        .route(
+            "/admin/users/{user_id}/disable",
+            post(admin_disable_user_handler),
+        )
+        .route(
+            "/admin/users/{user_id}/enable",
+            post(admin_enable_user_handler),
+        )
+        .route(
+            "/admin/users/{user_id}/password_reset",
+            post(admin_reset_password_handler),
+        )
+        .route("/admin/stats", get(admin_stats_handler))
+        .route(
+            "/admin/registration",
+            get(admin_registration_handler).put(update_registration_handler),
+        )
+        .nest("/organizations", workspace_routes(Kind::Organization))
+        .nest("/teams", workspace_routes(Kind::Team))
+        .with_state(app_state.clone())
+        // Reject oversized bodies up front, instead of each extractor applying its own limit
+        .layer(DefaultBodyLimit::disable())
+        .layer(RequestBodyLimitLayer::new(max_request_body_bytes()))
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            rate_limit::limit_requests,
+        ))
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            auth::require_local_host,
+        ))
+        .layer(TimeoutLayer::new(request_timeout()))
+        // Request spans, AuthUser fills in the user once it's known
+        .layer(
+            TraceLayer::new_for_http().make_span_with(|req: &axum::http::Request<_>| {
+                tracing::info_span!(
+                    "request",
+                    method = %req.method(),
+                    path = %req.uri().path(),
+                    user_id = tracing::field::Empty,
+                )
+            }),
+        );
 
     // Main router with API routes nested under /api and static file fallback
     let app = Router::new()
@@ -328,8 +871,11 @@ async fn runtime(shutdown_rx: mpsc::Receiver<()>) {
     println!("Server running on http://127.0.0.1:3000");
     tracing::info!("Server running on http://127.0.0.1:3000");
 
-    // Serve with graceful shutdown
-    axum::serve(listener, app)
+    // Serve with graceful shutdown, keeping peer addresses for the sessions list
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
         .with_graceful_shutdown(async move {
             // Wait for shutdown signal in a tokio-compatible way
             tokio::task::spawn_blocking(move || {
@@ -342,450 +888,3965 @@ async fn runtime(shutdown_rx: mpsc::Receiver<()>) {
         .unwrap();
 }
 
-// Health check endpoint
-async fn health_check() -> StatusCode {
-    StatusCode::OK
+/// Largest request body accepted (`MAX_REQUEST_BODY_BYTES`, default 1 MiB)
+fn max_request_body_bytes() -> usize {
+    env::var("MAX_REQUEST_BODY_BYTES")
+        .ok()
+        .and_then(|bytes| bytes.parse::<usize>().ok())
+        .unwrap_or(1024 * 1024)
 }
 
-// Register handler
-async fn register_handler(
-    State(app_state): State<AppState>,
-    Json(payload): Json<RegisterRequest>,
-) -> Result<Json<AuthResponse>, StatusCode> {
-    tracing::info!("Processing registration request for: {}", payload.email);
-
-    let database = &app_state.database;
+/// How long a request may take before it's answered with 408 (`REQUEST_TIMEOUT_SECONDS`, default 30)
+fn request_timeout() -> std::time::Duration {
+    let seconds = env::var("REQUEST_TIMEOUT_SECONDS")
+        .ok()
+        .and_then(|seconds| seconds.parse::<u64>().ok())
+        .unwrap_or(30);
+    std::time::Duration::from_secs(seconds)
+}
 
-    // Register the user (this will hash the password)
-    let mut user = database
-        .register_user(&payload.email, &payload.password)
-        .await
-        .map_err(|e| {
-            tracing::error!("Registration failed: {:?}", e);
-            if e.to_string().contains("already exists") {
-                StatusCode::CONFLICT
-            } else {
-                StatusCode::INTERNAL_SERVER_ERROR
+/// Periodically empty links that have sat in the trash longer than `TRASH_RETENTION_DAYS`
+fn spawn_trash_retention_job(database: Database) {
+    let retention_days = std::env::var("TRASH_RETENTION_DAYS")
+        .ok()
+        .and_then(|days| days.parse::<i64>().ok())
+        .unwrap_or(30);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            let cutoff = Utc::now() - chrono::Duration::days(retention_days);
+            if let Err(e) = database.purge_trash_before(&cutoff).await {
+                tracing::error!("Failed to empty trash: {:?}", e);
             }
-        })?;
-
-    // Generate JWT token
-    let token = user_jwt::generate_jwt(&user.id, &user.email).map_err(|e| {
-        tracing::error!("Failed to generate JWT: {:?}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
-
-    // Set auth_token in user object
-    user.auth_token = Some(token.clone());
-
-    tracing::info!("Successfully registered user: {}", user.email);
+        }
+    });
+}
 
-    Ok(Json(AuthResponse { token, user }))
+/// Append an event to the audit log
+/// Failing to record is logged but never fails the request that made the change
+async fn record_audit(database: &Database, event: database::AuditEvent) {
+    if let Err(e) = database.record_audit_event(&event).await {
+        tracing::error!(
+            "Failed to record audit event {} on {} {}: {:?}",
+            event.action,
+            event.entity_type,
+            event.entity_id,
+            e
+        );
+    }
+}
+
+/// Links owned by an organization, or by one of its teams, are audited under that organization
+async fn link_organization(database: &Database, link: &database::Link) -> Option<String> {
+    match link.owner_type.as_str() {
+        "organization" => Some(link.owner_id.clone()),
+        "team" => match database.get_workspace("team", &link.owner_id).await {
+            Ok(team) => team.organization_id,
+            Err(e) => {
+                tracing::error!("Failed to fetch team {}: {:?}", link.owner_id, e);
+                None
+            }
+        },
+        _ => None,
+    }
+}
+
+/// Check the user may use a link list at `min_role`: their own list, or an organization's
+/// or team's they're in. Viewers of a workspace can read its links, members can change them
+async fn require_link_owner_role(
+    database: &Database,
+    user_id: &str,
+    owner_type: &str,
+    owner_id: &str,
+    min_role: Role,
+) -> Result<(), StatusCode> {
+    let kind = match owner_type {
+        "user" if owner_id == user_id => return Ok(()),
+        "user" => {
+            tracing::warn!(
+                "User {} tried to use the links of user {}",
+                user_id,
+                owner_id
+            );
+            return Err(StatusCode::NOT_FOUND);
+        }
+        "organization" => Kind::Organization,
+        "team" => Kind::Team,
+        _ => return Err(StatusCode::BAD_REQUEST),
+    };
+    require_workspace_role(database, user_id, kind, owner_id, min_role).await?;
+    Ok(())
+}
+
+/// Fetch a link the user may use at `min_role`, as not found when they can't see it at all
+async fn get_permitted_link(
+    database: &Database,
+    user_id: &str,
+    link_id: &str,
+    min_role: Role,
+) -> Result<database::Link, StatusCode> {
+    let link =
+        database
+            .get_link_by_id(link_id)
+            .await
+            .map_err(|e| match e.to_string().as_str() {
+                "404" => StatusCode::NOT_FOUND,
+                _ => {
+                    tracing::error!("Failed to fetch link {}: {:?}", link_id, e);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+            })?;
+    require_link_owner_role(
+        database,
+        user_id,
+        &link.owner_type,
+        &link.owner_id,
+        min_role,
+    )
+    .await?;
+    Ok(link)
+}
+
+/// Base URL of the web app, used for links in emails (`APP_URL`)
+fn app_url() -> String {
+    std::env::var("APP_URL")
+        .unwrap_or_else(|_| "http://localhost:3000".to_string())
+        .trim_end_matches('/')
+        .to_string()
+}
+
+/// Issue a single-use token for the user and email them a link to redeem it
+async fn send_auth_token_email(
+    app_state: &AppState,
+    user: &database::User,
+    purpose: &str,
+    new_email: Option<&str>,
+) -> anyhow::Result<()> {
+    let token = tokens::generate();
+    let (ttl, path, subject, action) = match purpose {
+        database::TOKEN_PURPOSE_VERIFY_EMAIL => (
+            chrono::Duration::hours(24),
+            "verify-email",
+            "Verify your email",
+            "verify your email address",
+        ),
+        database::TOKEN_PURPOSE_CHANGE_EMAIL => (
+            chrono::Duration::hours(24),
+            "confirm-email-change",
+            "Confirm your new email",
+            "confirm your new email address",
+        ),
+        _ => (
+            chrono::Duration::hours(1),
+            "reset-password",
+            "Reset your password",
+            "reset your password",
+        ),
+    };
+
+    app_state
+        .database
+        .create_auth_token(
+            &user.id,
+            purpose,
+            &tokens::hash(&token),
+            &(Utc::now() + ttl),
+            new_email,
+        )
+        .await?;
+
+    // Email changes are confirmed from the new address
+    let to = new_email.unwrap_or(&user.email);
+    let link = format!("{}/{}?token={}", app_url(), path, token);
+    let email = email_templates::action_link(to, subject, action, &link, ttl.num_hours());
+
+    app_state.mailer.send(&email).await
+}
+
+/// Periodically drop expired sessions and revoked tokens
+fn spawn_session_cleanup_job(database: Database) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            if let Err(e) = database.purge_expired_sessions().await {
+                tracing::error!("Failed to purge expired sessions: {:?}", e);
+            }
+        }
+    });
+}
+
+/// Lifetime of a refresh token (`REFRESH_TOKEN_TTL_DAYS`, default 30 days)
+fn refresh_token_ttl() -> chrono::Duration {
+    let days = std::env::var("REFRESH_TOKEN_TTL_DAYS")
+        .ok()
+        .and_then(|days| days.parse::<i64>().ok())
+        .unwrap_or(30);
+    chrono::Duration::days(days)
+}
+
+/// Short human-readable description of a user agent, like "Firefox on Linux"
+fn describe_user_agent(user_agent: &str) -> String {
+    let browser = if user_agent.contains("Edg/") {
+        "Edge"
+    } else if user_agent.contains("OPR/") {
+        "Opera"
+    } else if user_agent.contains("Firefox/") {
+        "Firefox"
+    } else if user_agent.contains("Chrome/") {
+        "Chrome"
+    } else if user_agent.contains("Safari/") {
+        "Safari"
+    } else {
+        "Unknown browser"
+    };
+
+    let os = if user_agent.contains("Windows") {
+        "Windows"
+    } else if user_agent.contains("iPhone") || user_agent.contains("iPad") {
+        "iOS"
+    } else if user_agent.contains("Mac OS X") {
+        "macOS"
+    } else if user_agent.contains("Android") {
+        "Android"
+    } else if user_agent.contains("CrOS") {
+        "ChromeOS"
+    } else if user_agent.contains("Linux") {
+        "Linux"
+    } else {
+        "unknown OS"
+    };
+
+    format!("{} on {}", browser, os)
+}
+
+/// Describe the device a session is being started from, and its address through any trusted proxies
+fn session_device(
+    app_state: &AppState,
+    headers: &HeaderMap,
+    addr: &SocketAddr,
+    device_label: Option<String>,
+) -> database::SessionDevice {
+    let user_agent = headers
+        .get("User-Agent")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string());
+
+    let device_label = device_label
+        .map(|label| label.trim().chars().take(100).collect::<String>())
+        .filter(|label| !label.is_empty())
+        .or_else(|| user_agent.as_deref().map(describe_user_agent));
+
+    database::SessionDevice {
+        device_label,
+        user_agent,
+        ip_address: Some(
+            app_state
+                .trusted_proxies
+                .client_ip(addr.ip(), headers)
+                .to_string(),
+        ),
+    }
+}
+
+/// Start a new session for the user, returning an access token and a refresh token
+async fn start_session(
+    app_state: &AppState,
+    user: &database::User,
+    device: &database::SessionDevice,
+) -> Result<(String, String), StatusCode> {
+    let database = &app_state.database;
+    if user.disabled_at.is_some() {
+        tracing::warn!("Rejected sign-in for disabled user {}", user.id);
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let refresh_token = tokens::generate();
+
+    let session = database
+        .create_session(
+            &user.id,
+            &tokens::hash(&refresh_token),
+            &(Utc::now() + refresh_token_ttl()),
+            device,
+        )
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to create session: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let token = app_state
+        .jwt_keys
+        .generate_jwt(&user.id, &user.email, &session.id)
+        .map_err(|e| {
+            tracing::error!("Failed to generate JWT: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok((token, refresh_token))
+}
+
+// Health check endpoint
+async fn health_check() -> StatusCode {
+    StatusCode::OK
+}
+
+// Register handler
+async fn register_handler(
+    State(app_state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<RegisterRequest>,
+) -> Result<Json<AuthResponse>, LoginError> {
+    tracing::info!("Processing registration request for: {}", payload.email);
+
+    let database = &app_state.database;
+
+    // Every registration counts against the IP, successful or not, since each one costs a bcrypt hash
+    if let Some(ip) = throttled_ip(&app_state, &addr, &headers) {
+        let register_key = login_throttle::register_key(&ip);
+        login_throttle::check(database, &[&register_key]).await?;
+        login_throttle::record_failure(database, &register_key, login_throttle::Policy::register())
+            .await;
+    }
+
+    // The first account can always be created, so a fresh instance has someone to send invites
+    let registration_mode = current_registration_mode(&app_state).await;
+    let first_user = registration_mode != RegistrationMode::Open
+        && database.count_users().await.map_err(|e| {
+            tracing::error!("Failed to count users: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })? == 0;
+    if registration_mode == RegistrationMode::Closed && !first_user {
+        tracing::warn!(
+            "Rejected registration for {}, registration is closed",
+            payload.email
+        );
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+
+    app_state
+        .password_policy
+        .check(&payload.password, &payload.email)
+        .await?;
+
+    // Claim the invite before creating the account, so two registrations can't share its last use
+    let invite = match payload
+        .invite_code
+        .as_deref()
+        .map(str::trim)
+        .filter(|code| !code.is_empty())
+    {
+        Some(code) => {
+            let invite = database
+                .claim_invite(&tokens::hash(code), &payload.email)
+                .await
+                .map_err(|e| {
+                    tracing::error!("Failed to claim invite: {:?}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })?;
+            if invite.is_none() {
+                tracing::warn!("Invalid or used up invite code from {}", payload.email);
+                return Err(StatusCode::FORBIDDEN.into());
+            }
+            invite
+        }
+        None => None,
+    };
+    if registration_mode == RegistrationMode::InviteOnly && invite.is_none() && !first_user {
+        tracing::warn!(
+            "Rejected registration for {} without an invite",
+            payload.email
+        );
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+    // Let in only because no one has registered yet, which has to still hold at the insert
+    let only_first = registration_mode == RegistrationMode::Closed
+        || (registration_mode == RegistrationMode::InviteOnly && invite.is_none());
+
+    // Register the user (this will hash the password)
+    let registered = if only_first {
+        database
+            .register_first_user(&payload.email, &payload.password)
+            .await
+    } else {
+        database
+            .register_user(&payload.email, &payload.password)
+            .await
+            .map(Some)
+    };
+    let mut user = match registered {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            tracing::warn!(
+                "Rejected registration for {}, another account was created first",
+                payload.email
+            );
+            return Err(StatusCode::FORBIDDEN.into());
+        }
+        Err(e) => {
+            tracing::error!("Registration failed: {:?}", e);
+            if let Some(invite) = &invite {
+                if let Err(e) = database.release_invite(&invite.id).await {
+                    tracing::error!("Failed to release invite: {:?}", e);
+                }
+            }
+            if e.to_string().contains("already exists") {
+                return Err(StatusCode::CONFLICT.into());
+            }
+            return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+        }
+    };
+
+    record_audit(
+        database,
+        database::AuditEvent::new(&user.id, "user", &user.id, "register").after(&user),
+    )
+    .await;
+
+    if let Some(invite) = &invite {
+        join_invited_organization(database, &user, invite).await;
+    }
+
+    // Registration still succeeds if the verification email can't be sent, it can be re-requested
+    if let Err(e) = send_auth_token_email(
+        &app_state,
+        &user,
+        database::TOKEN_PURPOSE_VERIFY_EMAIL,
+        None,
+    )
+    .await
+    {
+        tracing::error!("Failed to send verification email: {:?}", e);
+    }
+
+    // Start a session and issue its tokens
+    let device = session_device(&app_state, &headers, &addr, payload.device_label.clone());
+    let (token, refresh_token) = start_session(&app_state, &user, &device).await?;
+
+    // Set auth_token in user object
+    user.auth_token = Some(token.clone());
+
+    tracing::info!("Successfully registered user: {}", user.email);
+
+    Ok(Json(AuthResponse {
+        token,
+        refresh_token,
+        user,
+    }))
 }
 
 // Login handler
 async fn login_handler(
     State(app_state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Json(payload): Json<LoginRequest>,
-) -> Result<Json<AuthResponse>, StatusCode> {
+) -> Result<Json<LoginResponse>, LoginError> {
     tracing::info!("Processing login request for: {}", payload.email);
 
     let database = &app_state.database;
 
+    // Back off guesses against the account, and from the IP across accounts
+    let account_key = login_throttle::account_key(&payload.email);
+    let ip_key = throttled_ip(&app_state, &addr, &headers).map(|ip| login_throttle::ip_key(&ip));
+    login_throttle::check(database, &throttle_keys(&account_key, &ip_key)).await?;
+
     // Verify password
-    let mut user = database
+    let mut user = match database
         .verify_password(&payload.email, &payload.password)
         .await
-        .map_err(|e| {
+    {
+        Ok(user) => user,
+        Err(e) => {
             tracing::warn!("Login failed for {}: {:?}", payload.email, e);
-            StatusCode::UNAUTHORIZED
+            record_login_failure(database, &account_key, ip_key.as_deref()).await;
+            return Err(StatusCode::UNAUTHORIZED.into());
+        }
+    };
+
+    // A disabled account gets no challenge, no cleared failures and no login in its history
+    if user.disabled_at.is_some() {
+        tracing::warn!("Rejected sign-in for disabled user {}", user.id);
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+
+    // With 2FA on, the password only earns a short-lived challenge to redeem at /login/2fa
+    let totp = database.get_user_totp(&user.id).await.map_err(|e| {
+        tracing::error!("Failed to check two-factor status: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if totp.is_some_and(|totp| totp.enabled_at.is_some()) {
+        let challenge_token = tokens::generate();
+        database
+            .create_auth_token(
+                &user.id,
+                database::TOKEN_PURPOSE_TWO_FACTOR_LOGIN,
+                &tokens::hash(&challenge_token),
+                &(Utc::now() + chrono::Duration::minutes(5)),
+                None,
+            )
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to create two-factor challenge: {:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+
+        tracing::info!("Two-factor required for user: {}", user.email);
+        return Ok(Json(LoginResponse::TwoFactorRequired {
+            two_factor_required: true,
+            challenge_token,
+        }));
+    }
+
+    login_throttle::clear(database, &account_key).await;
+    record_audit(
+        database,
+        database::AuditEvent::new(&user.id, "user", &user.id, "login"),
+    )
+    .await;
+
+    // Start a session and issue its tokens
+    let device = session_device(&app_state, &headers, &addr, payload.device_label.clone());
+    let (token, refresh_token) = start_session(&app_state, &user, &device).await?;
+
+    // Set auth_token in user object
+    user.auth_token = Some(token.clone());
+
+    tracing::info!("Successfully logged in user: {}", user.email);
+
+    Ok(Json(LoginResponse::Authenticated(AuthResponse {
+        token,
+        refresh_token,
+        user,
+    })))
+}
+
+/// The client's address for per-IP throttling, read through any trusted proxies
+/// None for loopback clients, a desktop install or a proxy on the same host that isn't in
+/// `TRUSTED_PROXIES`, where everyone would share one lockout
+fn throttled_ip(app_state: &AppState, addr: &SocketAddr, headers: &HeaderMap) -> Option<IpAddr> {
+    Some(app_state.trusted_proxies.client_ip(addr.ip(), headers)).filter(|ip| !ip.is_loopback())
+}
+
+fn throttle_keys<'a>(account_key: &'a str, ip_key: &'a Option<String>) -> Vec<&'a str> {
+    std::iter::once(account_key)
+        .chain(ip_key.as_deref())
+        .collect()
+}
+
+/// Count a failed sign-in against both the account and the IP it came from
+async fn record_login_failure(database: &Database, account_key: &str, ip_key: Option<&str>) {
+    login_throttle::record_failure(database, account_key, login_throttle::Policy::account()).await;
+    if let Some(ip_key) = ip_key {
+        login_throttle::record_failure(database, ip_key, login_throttle::Policy::ip()).await;
+    }
+}
+
+// Finish a login that needed a second factor
+async fn login_two_factor_handler(
+    State(app_state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<TwoFactorLoginRequest>,
+) -> Result<Json<AuthResponse>, LoginError> {
+    tracing::info!("Processing two-factor login");
+
+    let database = &app_state.database;
+    let challenge_hash = tokens::hash(&payload.challenge_token);
+
+    let user_id = database
+        .peek_auth_token(database::TOKEN_PURPOSE_TWO_FACTOR_LOGIN, &challenge_hash)
+        .await
+        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+    let mut user = database.get_user(&user_id).await.map_err(|e| {
+        tracing::error!("Failed to fetch user: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Disabled while the challenge was outstanding
+    if user.disabled_at.is_some() {
+        tracing::warn!("Rejected sign-in for disabled user {}", user.id);
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+
+    // Code guesses count against the account just like password guesses
+    let account_key = login_throttle::account_key(&user.email);
+    let ip_key = throttled_ip(&app_state, &addr, &headers).map(|ip| login_throttle::ip_key(&ip));
+    login_throttle::check(database, &throttle_keys(&account_key, &ip_key)).await?;
+
+    let method = match verify_second_factor(
+        database,
+        &user_id,
+        payload.code.as_deref(),
+        payload.recovery_code.as_deref(),
+    )
+    .await
+    {
+        Ok(method) => method,
+        Err(status) => {
+            if status == StatusCode::UNAUTHORIZED {
+                record_login_failure(database, &account_key, ip_key.as_deref()).await;
+            }
+            return Err(status.into());
+        }
+    };
+
+    // The challenge is only used up once a valid code comes with it
+    database
+        .consume_auth_token(database::TOKEN_PURPOSE_TWO_FACTOR_LOGIN, &challenge_hash)
+        .await
+        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+    login_throttle::clear(database, &account_key).await;
+
+    record_audit(
+        database,
+        database::AuditEvent::new(&user.id, "user", &user.id, "login")
+            .after(&json!({ "second_factor": method })),
+    )
+    .await;
+
+    // Start a session and issue its tokens
+    let device = session_device(&app_state, &headers, &addr, payload.device_label.clone());
+    let (token, refresh_token) = start_session(&app_state, &user, &device).await?;
+
+    user.auth_token = Some(token.clone());
+
+    tracing::info!(
+        "Successfully logged in user with two-factor: {}",
+        user.email
+    );
+
+    Ok(Json(AuthResponse {
+        token,
+        refresh_token,
+        user,
+    }))
+}
+
+// How the web app should authenticate: "password" for the login screen,
+// "proxy" when a reverse proxy signs users in and "local" for a single-user install
+async fn auth_config_handler(State(app_state): State<AppState>) -> Json<AuthConfigResponse> {
+    let mode = if app_state.local_user.is_some() {
+        "local"
+    } else if app_state.proxy_auth.is_some() {
+        "proxy"
+    } else {
+        "password"
+    };
+    Json(AuthConfigResponse {
+        mode,
+        registration: current_registration_mode(&app_state).await.as_str(),
+    })
+}
+
+/// The registration mode an admin has set, falling back to `REGISTRATION_MODE`
+async fn current_registration_mode(app_state: &AppState) -> RegistrationMode {
+    match app_state
+        .database
+        .get_instance_setting(invites::REGISTRATION_MODE_SETTING)
+        .await
+    {
+        Ok(Some(mode)) => RegistrationMode::parse(&mode).unwrap_or(app_state.registration_mode),
+        Ok(None) => app_state.registration_mode,
+        Err(e) => {
+            tracing::error!("Failed to read registration mode: {:?}", e);
+            app_state.registration_mode
+        }
+    }
+}
+
+// Whether single sign-on is configured, so the login page knows to offer it
+async fn oidc_config_handler(State(app_state): State<AppState>) -> Json<OidcConfigResponse> {
+    Json(OidcConfigResponse {
+        enabled: app_state.oidc.is_some(),
+        provider_name: app_state
+            .oidc
+            .as_ref()
+            .map(|config| config.provider_name.clone()),
+    })
+}
+
+// Send the browser to the identity provider, with PKCE and a single-use state and nonce
+async fn oidc_login_handler(State(app_state): State<AppState>) -> Result<Redirect, StatusCode> {
+    let url = begin_oidc_login(&app_state, None).await?;
+    Ok(Redirect::to(&url))
+}
+
+// Start linking the signed-in user's account to the identity provider
+// Returns the URL instead of redirecting, since the web app calls this with its bearer token
+async fn oidc_link_handler(
+    State(app_state): State<AppState>,
+    user_context: AuthUser,
+) -> Result<Json<OidcLinkResponse>, StatusCode> {
+    let url = begin_oidc_login(&app_state, Some(&user_context.user_id)).await?;
+    Ok(Json(OidcLinkResponse { url }))
+}
+
+/// Store a pending login and build the URL that sends the browser to the identity provider
+async fn begin_oidc_login(
+    app_state: &AppState,
+    link_user_id: Option<&str>,
+) -> Result<String, StatusCode> {
+    let config = app_state.oidc.clone().ok_or(StatusCode::NOT_FOUND)?;
+    tracing::info!("Starting OIDC login with {}", config.issuer);
+
+    let metadata = oidc::discover(&app_state.client, &config)
+        .await
+        .map_err(|e| {
+            tracing::error!("OIDC discovery failed: {:?}", e);
+            StatusCode::BAD_GATEWAY
+        })?;
+
+    let state = tokens::generate();
+    let nonce = tokens::generate();
+    let code_verifier = tokens::generate();
+
+    app_state
+        .database
+        .create_oidc_login_state(
+            &tokens::hash(&state),
+            &code_verifier,
+            &nonce,
+            link_user_id,
+            &(Utc::now() + chrono::Duration::minutes(10)),
+        )
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to store OIDC login state: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    oidc::authorization_url(&metadata, &config, &state, &nonce, &code_verifier).map_err(|e| {
+        tracing::error!("Failed to build OIDC authorization URL: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+// The identity provider sends the browser back here with an authorization code
+// Tokens are handed to the web app in the URL fragment, which never reaches a server
+async fn oidc_callback_handler(
+    State(app_state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(query): Query<OidcCallbackQuery>,
+) -> Redirect {
+    let login_state = match &query.state {
+        Some(state) => app_state
+            .database
+            .take_oidc_login_state(&tokens::hash(state))
+            .await
+            .ok(),
+        None => None,
+    };
+    let Some(login_state) = login_state else {
+        tracing::warn!("OIDC login failed: invalid_state");
+        return Redirect::to(&format!("{}/login?sso_error=invalid_state", app_url()));
+    };
+
+    if let Some(user_id) = login_state.link_user_id.clone() {
+        return match link_oidc_identity(&app_state, &user_id, query, login_state).await {
+            Ok(()) => Redirect::to(&format!("{}/settings?sso_linked=true", app_url())),
+            Err(reason) => {
+                tracing::warn!("Linking OIDC identity failed: {}", reason);
+                Redirect::to(&format!("{}/settings?sso_error={}", app_url(), reason))
+            }
+        };
+    }
+
+    match complete_oidc_login(&app_state, &addr, &headers, query, login_state).await {
+        Ok((token, refresh_token)) => Redirect::to(&format!(
+            "{}/login/sso#token={}&refresh_token={}",
+            app_url(),
+            token,
+            refresh_token
+        )),
+        Err(reason) => {
+            tracing::warn!("OIDC login failed: {}", reason);
+            Redirect::to(&format!("{}/login?sso_error={}", app_url(), reason))
+        }
+    }
+}
+
+/// Exchange the callback's code and return the validated ID token claims
+/// Errors are short codes that are safe to show the user
+async fn verify_oidc_callback(
+    app_state: &AppState,
+    config: &oidc::OidcConfig,
+    query: OidcCallbackQuery,
+    login_state: &database::OidcLoginState,
+) -> Result<oidc::IdTokenClaims, &'static str> {
+    if let Some(error) = &query.error {
+        tracing::warn!("Identity provider returned an error: {}", error);
+        return Err("provider_error");
+    }
+    let code = query.code.ok_or("missing_code")?;
+
+    let metadata = oidc::discover(&app_state.client, config)
+        .await
+        .map_err(|e| {
+            tracing::error!("OIDC discovery failed: {:?}", e);
+            "provider_unavailable"
+        })?;
+    let id_token = oidc::exchange_code(
+        &app_state.client,
+        &metadata,
+        config,
+        &code,
+        &login_state.code_verifier,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("OIDC code exchange failed: {:?}", e);
+        "token_exchange_failed"
+    })?;
+    oidc::validate_id_token(
+        &app_state.client,
+        &metadata,
+        config,
+        &id_token,
+        &login_state.nonce,
+    )
+    .await
+    .map_err(|e| {
+        tracing::warn!("Invalid ID token: {:?}", e);
+        "invalid_id_token"
+    })
+}
+
+/// Validate the provider's response and sign the user in, provisioning them on first login
+/// An existing account is never taken over by email, its owner links the provider from settings
+async fn complete_oidc_login(
+    app_state: &AppState,
+    addr: &SocketAddr,
+    headers: &HeaderMap,
+    query: OidcCallbackQuery,
+    login_state: database::OidcLoginState,
+) -> Result<(String, String), &'static str> {
+    let config = app_state.oidc.clone().ok_or("not_configured")?;
+    let database = &app_state.database;
+
+    let claims = verify_oidc_callback(app_state, &config, query, &login_state).await?;
+
+    let user = match database
+        .get_user_by_identity(&config.issuer, &claims.sub)
+        .await
+        .map_err(|_| "server_error")?
+    {
+        Some(user) => user,
+        None => {
+            let email = claims.email.clone().ok_or("missing_email")?;
+            // The account is keyed by email, so the provider has to vouch for it
+            if claims.email_verified != Some(true) {
+                return Err("email_not_verified");
+            }
+            if database.get_user_by_email(&email).await.is_ok() {
+                return Err("account_exists");
+            }
+            provision_user(app_state, email)
+                .await
+                .map_err(|status| match status {
+                    StatusCode::FORBIDDEN => "registration_closed",
+                    _ => "server_error",
+                })?
+        }
+    };
+
+    let email_verified = claims.email_verified == Some(true)
+        && claims
+            .email
+            .as_deref()
+            .is_some_and(|email| email.eq_ignore_ascii_case(&user.email));
+    if email_verified && user.email_verified_at.is_none() {
+        if let Err(e) = database.mark_email_verified(&user.id).await {
+            tracing::warn!("Failed to mark email verified: {:?}", e);
+        }
+    }
+
+    database
+        .link_identity(
+            &config.issuer,
+            &claims.sub,
+            &user.id,
+            claims.email.as_deref(),
+        )
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to link identity: {:?}", e);
+            "server_error"
+        })?;
+
+    apply_group_mappings(database, &config, &user.id, &claims).await;
+
+    // The identity provider is responsible for any second factor
+    record_audit(
+        database,
+        database::AuditEvent::new(&user.id, "user", &user.id, "login")
+            .after(&json!({ "sso_issuer": config.issuer })),
+    )
+    .await;
+
+    let device = session_device(app_state, headers, addr, None);
+    start_session(app_state, &user, &device)
+        .await
+        .map_err(|_| "server_error")
+}
+
+/// Link the provider account to a user who started the login while signed in
+async fn link_oidc_identity(
+    app_state: &AppState,
+    user_id: &str,
+    query: OidcCallbackQuery,
+    login_state: database::OidcLoginState,
+) -> Result<(), &'static str> {
+    let config = app_state.oidc.clone().ok_or("not_configured")?;
+    let database = &app_state.database;
+
+    let claims = verify_oidc_callback(app_state, &config, query, &login_state).await?;
+
+    match database
+        .get_user_by_identity(&config.issuer, &claims.sub)
+        .await
+        .map_err(|_| "server_error")?
+    {
+        Some(user) if user.id != user_id => return Err("identity_in_use"),
+        _ => {}
+    }
+
+    database
+        .link_identity(
+            &config.issuer,
+            &claims.sub,
+            user_id,
+            claims.email.as_deref(),
+        )
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to link identity: {:?}", e);
+            "server_error"
+        })?;
+
+    record_audit(
+        database,
+        database::AuditEvent::new(user_id, "user", user_id, "link_identity").after(&json!({
+            "sso_issuer": config.issuer,
+            "sso_subject": claims.sub,
+        })),
+    )
+    .await;
+
+    Ok(())
+}
+
+/// Grant memberships for the user's mapped IdP groups
+/// A workspace's owner keeps their role, so a mapping can't demote them
+async fn apply_group_mappings(
+    database: &Database,
+    config: &oidc::OidcConfig,
+    user_id: &str,
+    claims: &oidc::IdTokenClaims,
+) {
+    let groups = claims.groups(&config.groups_claim);
+    for mapping in config
+        .group_mappings
+        .iter()
+        .filter(|mapping| groups.contains(&mapping.group))
+    {
+        let result = match database
+            .get_workspace(&mapping.entity_type, &mapping.entity_id)
+            .await
+        {
+            Ok(workspace) if workspace.owner_id.as_deref() == Some(user_id) => continue,
+            Ok(_) => match database.get_membership(user_id, &mapping.entity_id).await {
+                Ok(None) => {
+                    database
+                        .add_member(database::UserMembership {
+                            user_id: user_id.to_string(),
+                            entity_id: mapping.entity_id.clone(),
+                            entity_type: mapping.entity_type.clone(),
+                            role: mapping.role.as_str().to_string(),
+                            created_at: Utc::now().to_rfc3339(),
+                        })
+                        .await
+                }
+                Ok(Some(membership)) if membership.role != mapping.role.as_str() => database
+                    .update_member_role(user_id, &mapping.entity_id, mapping.role.as_str())
+                    .await
+                    .map(|_| ()),
+                Ok(Some(_)) => Ok(()),
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            tracing::error!(
+                "Failed to apply group {} membership for {}: {:?}",
+                mapping.group,
+                user_id,
+                e
+            );
+        }
+    }
+}
+
+/// Create an account for a user signed in by an identity provider or trusted proxy
+/// There's no invite to present that way, so only while registration is open,
+/// or for the instance's first account
+async fn provision_user(app_state: &AppState, email: String) -> Result<database::User, StatusCode> {
+    let only_first = current_registration_mode(app_state).await != RegistrationMode::Open;
+    create_passwordless_user(app_state, email, only_first).await
+}
+
+/// Same get-or-create as get_user_data_handler, these users don't have a password
+async fn create_passwordless_user(
+    app_state: &AppState,
+    email: String,
+    only_first: bool,
+) -> Result<database::User, StatusCode> {
+    tracing::info!("Provisioning user: {}", email);
+    let database = &app_state.database;
+    let new_user = database::User {
+        id: uuid::Uuid::new_v4().to_string(),
+        email,
+        created_at: Utc::now().to_rfc3339(),
+        auth_token: None,
+        password_hash: String::new(),
+        email_verified_at: None,
+        is_admin: false,
+        disabled_at: None,
+    };
+
+    let email = new_user.email.clone();
+    let created = if only_first {
+        database.create_first_user(new_user).await
+    } else {
+        database.create_user(new_user).await.map(Some)
+    };
+    let new_user = match created {
+        Ok(Some(user)) => user,
+        result => {
+            // Another request may have provisioned the same user first
+            if let Ok(existing) = database.get_user_by_email(&email).await {
+                return Ok(existing);
+            }
+            if let Err(e) = result {
+                tracing::error!("Failed to create user: {:?}", e);
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+            tracing::warn!("Not provisioning {}, registration isn't open", email);
+            return Err(StatusCode::FORBIDDEN);
+        }
+    };
+
+    if create_user_default_settings(app_state, &new_user)
+        .await
+        .is_err()
+    {
+        tracing::error!(
+            "Failed to create user settings for user: {}",
+            new_user.email
+        );
+    }
+    record_audit(
+        database,
+        database::AuditEvent::new(&new_user.id, "user", &new_user.id, "register").after(&new_user),
+    )
+    .await;
+
+    Ok(new_user)
+}
+
+/// Email of the single user in local mode (`LOCAL_USER_EMAIL`, default `local@localhost`)
+fn local_user_email() -> String {
+    env::var("LOCAL_USER_EMAIL").unwrap_or_else(|_| "local@localhost".to_string())
+}
+
+/// Find or create the single user for local mode
+/// It's an ordinary account, so switching to multi-user mode later keeps its links and settings
+async fn ensure_local_user(app_state: &AppState) -> Result<AuthUser, StatusCode> {
+    let email = local_user_email();
+    let user = match app_state.database.get_user_by_email(&email).await {
+        Ok(user) => user,
+        Err(_) => create_passwordless_user(app_state, email, false).await?,
+    };
+
+    tracing::info!("Single-user local mode, signed in as {}", user.email);
+    println!("Single-user local mode, signed in as {}", user.email);
+    Ok(AuthUser {
+        user_id: user.id,
+        email: user.email,
+        session_id: String::new(),
+        token_id: String::new(),
+        token_expires_at: 0,
+        api_token_id: None,
+    })
+}
+
+/// After leaving local mode its user has no password, and a reset email to `local@localhost`
+/// goes nowhere, so print a link to set one on each start until it has a password
+async fn prompt_former_local_user(app_state: &AppState) {
+    let database = &app_state.database;
+    let user = match database.get_user_by_email(&local_user_email()).await {
+        Ok(user) if user.password_hash.is_empty() => user,
+        _ => return,
+    };
+
+    let token = tokens::generate();
+    if let Err(e) = database
+        .create_auth_token(
+            &user.id,
+            database::TOKEN_PURPOSE_RESET_PASSWORD,
+            &tokens::hash(&token),
+            &(Utc::now() + chrono::Duration::hours(1)),
+            None,
+        )
+        .await
+    {
+        tracing::error!(
+            "Failed to create a password link for the local user: {:?}",
+            e
+        );
+        return;
+    }
+
+    // The link is a credential, so it only goes to the console and not the logs
+    tracing::warn!(
+        "The local mode user {} has no password, a link to set one was printed to the console",
+        user.email
+    );
+    println!(
+        "The local mode user {} has no password yet. Set one within the hour at {}/reset-password?token={} and sign in with that email",
+        user.email,
+        app_url(),
+        token
+    );
+}
+
+/// How long the browser has to answer a WebAuthn challenge
+fn webauthn_ceremony_expiry() -> chrono::DateTime<Utc> {
+    Utc::now() + chrono::Duration::minutes(5)
+}
+
+// List the user's passkeys
+async fn passkeys_handler(
+    State(app_state): State<AppState>,
+    user_context: AuthUser,
+) -> Result<Json<Vec<database::PasskeyCredential>>, StatusCode> {
+    let passkeys = app_state
+        .database
+        .get_passkeys(&user_context.user_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to fetch passkeys: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Json(passkeys))
+}
+
+async fn delete_passkey_handler(
+    State(app_state): State<AppState>,
+    user_context: AuthUser,
+    Path(passkey_id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let user_id = user_context.user_id.clone();
+    let database = &app_state.database;
+
+    database
+        .delete_passkey(&user_id, &passkey_id)
+        .await
+        .map_err(|e| {
+            if e.to_string() == "404" {
+                StatusCode::NOT_FOUND
+            } else {
+                tracing::error!("Failed to delete passkey: {:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+
+    record_audit(
+        database,
+        database::AuditEvent::new(&user_id, "passkey", &passkey_id, "delete"),
+    )
+    .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn api_tokens_handler(
+    State(app_state): State<AppState>,
+    user_context: AuthUser,
+) -> Result<Json<Vec<database::ApiToken>>, StatusCode> {
+    let api_tokens = app_state
+        .database
+        .get_api_tokens(&user_context.user_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to fetch API tokens: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Json(api_tokens))
+}
+
+// Create a personal API token for scripts and integrations
+async fn create_api_token_handler(
+    State(app_state): State<AppState>,
+    user_context: AuthUser,
+    Json(payload): Json<CreateApiTokenRequest>,
+) -> Result<(StatusCode, Json<CreateApiTokenResponse>), StatusCode> {
+    let user_id = user_context.user_id.clone();
+    let database = &app_state.database;
+
+    let name = payload.name.trim();
+    if name.is_empty() || name.len() > 100 {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let scopes = api_tokens::parse_scopes(&payload.scopes).ok_or_else(|| {
+        tracing::warn!("Rejected API token scopes: {:?}", payload.scopes);
+        StatusCode::BAD_REQUEST
+    })?;
+    let expires_at = match payload.expires_in_days {
+        Some(days) if (1..=3650).contains(&days) => Some(Utc::now() + chrono::Duration::days(days)),
+        Some(_) => return Err(StatusCode::BAD_REQUEST),
+        None => None,
+    };
+
+    let token = api_tokens::generate();
+    let api_token = database
+        .create_api_token(
+            &user_id,
+            name,
+            &tokens::hash(&token),
+            &scopes.join(" "),
+            expires_at.as_ref(),
+        )
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to create API token: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    record_audit(
+        database,
+        database::AuditEvent::new(&user_id, "api_token", &api_token.id, "create").after(&api_token),
+    )
+    .await;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(CreateApiTokenResponse { token, api_token }),
+    ))
+}
+
+/// Add a newly registered user to the organization their invite was for
+/// The account exists by now, so a failure here is logged rather than failing the registration
+async fn join_invited_organization(
+    database: &Database,
+    user: &database::User,
+    invite: &database::Invite,
+) {
+    let organization_id = match &invite.organization_id {
+        Some(organization_id) => organization_id,
+        None => return,
+    };
+
+    let membership = database::UserMembership {
+        user_id: user.id.clone(),
+        entity_id: organization_id.clone(),
+        entity_type: "organization".to_string(),
+        role: invite.role.clone(),
+        created_at: Utc::now().to_rfc3339(),
+    };
+    let event = database::AuditEvent::new(&user.id, "membership", &user.id, "join")
+        .after(&membership)
+        .in_organization(Some(organization_id));
+
+    if let Err(e) = database.add_member(membership).await {
+        tracing::error!("Failed to add invited user to organization: {:?}", e);
+        return;
+    }
+    record_audit(database, event).await;
+}
+
+/// Only instance admins can manage users and instance-wide settings
+pub async fn require_instance_admin(database: &Database, user_id: &str) -> Result<(), StatusCode> {
+    let user = database.get_user(user_id).await.map_err(|e| {
+        tracing::error!("Failed to fetch user: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if !user.is_admin {
+        tracing::warn!("User {} is not an instance admin", user_id);
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(())
+}
+
+/// Admins of the invite's organization manage it, or instance admins when it has none
+async fn require_invite_admin(
+    database: &Database,
+    user_id: &str,
+    organization_id: Option<&str>,
+) -> Result<(), StatusCode> {
+    match organization_id {
+        Some(organization_id) => {
+            require_organization_admin(database, user_id, organization_id).await
+        }
+        None => require_instance_admin(database, user_id).await,
+    }
+}
+
+/// Only an organization's admins can manage its invites and members
+async fn require_organization_admin(
+    database: &Database,
+    user_id: &str,
+    organization_id: &str,
+) -> Result<(), StatusCode> {
+    let membership = database
+        .get_membership(user_id, organization_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to fetch membership: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    if !membership.is_some_and(|m| m.entity_type == "organization" && m.role == "admin") {
+        tracing::warn!(
+            "User {} is not an admin of organization {}",
+            user_id,
+            organization_id
+        );
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(())
+}
+
+async fn invites_handler(
+    State(app_state): State<AppState>,
+    user_context: AuthUser,
+    Query(query): Query<InvitesQuery>,
+) -> Result<Json<Vec<database::Invite>>, StatusCode> {
+    let database = &app_state.database;
+    let organization_id = query.organization_id.as_deref();
+    require_invite_admin(database, &user_context.user_id, organization_id).await?;
+
+    let invites = database.get_invites(organization_id).await.map_err(|e| {
+        tracing::error!("Failed to fetch invites: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(Json(invites))
+}
+
+async fn create_invite_handler(
+    State(app_state): State<AppState>,
+    user_context: AuthUser,
+    Json(payload): Json<CreateInviteRequest>,
+) -> Result<(StatusCode, Json<CreateInviteResponse>), StatusCode> {
+    let user_id = user_context.user_id.clone();
+    let database = &app_state.database;
+    require_invite_admin(database, &user_id, payload.organization_id.as_deref()).await?;
+
+    let role = match payload.role.as_deref() {
+        Some(role) => Role::parse(role).ok_or(StatusCode::BAD_REQUEST)?,
+        None => Role::Member,
+    };
+    let email = payload
+        .email
+        .as_deref()
+        .map(str::trim)
+        .filter(|email| !email.is_empty());
+    if email.is_some_and(|email| !email.contains('@')) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let max_uses = match payload.max_uses {
+        Some(uses) if (1..=10_000).contains(&uses) => Some(uses),
+        Some(_) => return Err(StatusCode::BAD_REQUEST),
+        None => None,
+    };
+    // Invites expire after a week unless asked otherwise
+    let expires_in_days = payload.expires_in_days.unwrap_or(7);
+    if !(1..=365).contains(&expires_in_days) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let code = invites::generate_code();
+    let invite = database::Invite {
+        id: uuid::Uuid::new_v4().to_string(),
+        code_hash: tokens::hash(&code),
+        created_by: user_id.clone(),
+        organization_id: payload.organization_id.clone(),
+        role: role.as_str().to_string(),
+        email: email.map(str::to_string),
+        max_uses,
+        uses: 0,
+        created_at: Utc::now().to_rfc3339(),
+        expires_at: Some((Utc::now() + chrono::Duration::days(expires_in_days)).to_rfc3339()),
+    };
+    database.create_invite(&invite).await.map_err(|e| {
+        tracing::error!("Failed to create invite: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    record_audit(
+        database,
+        database::AuditEvent::new(&user_id, "invite", &invite.id, "create")
+            .after(&invite)
+            .in_organization(invite.organization_id.as_deref()),
+    )
+    .await;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(CreateInviteResponse { code, invite }),
+    ))
+}
+
+async fn delete_invite_handler(
+    State(app_state): State<AppState>,
+    user_context: AuthUser,
+    Path(invite_id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let user_id = user_context.user_id.clone();
+    let database = &app_state.database;
+
+    let invite = database.get_invite(&invite_id).await.map_err(|e| {
+        if e.to_string() == "404" {
+            StatusCode::NOT_FOUND
+        } else {
+            tracing::error!("Failed to fetch invite: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    })?;
+    require_invite_admin(database, &user_id, invite.organization_id.as_deref()).await?;
+
+    database.delete_invite(&invite_id).await.map_err(|e| {
+        if e.to_string() == "404" {
+            StatusCode::NOT_FOUND
+        } else {
+            tracing::error!("Failed to delete invite: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    })?;
+
+    record_audit(
+        database,
+        database::AuditEvent::new(&user_id, "invite", &invite_id, "revoke")
+            .before(&invite)
+            .in_organization(invite.organization_id.as_deref()),
+    )
+    .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn delete_api_token_handler(
+    State(app_state): State<AppState>,
+    user_context: AuthUser,
+    Path(token_id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let user_id = user_context.user_id.clone();
+    let database = &app_state.database;
+
+    database
+        .delete_api_token(&user_id, &token_id)
+        .await
+        .map_err(|e| {
+            if e.to_string() == "404" {
+                StatusCode::NOT_FOUND
+            } else {
+                tracing::error!("Failed to revoke API token: {:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+
+    record_audit(
+        database,
+        database::AuditEvent::new(&user_id, "api_token", &token_id, "revoke"),
+    )
+    .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Start registering a passkey for the signed-in user
+async fn passkey_register_start_handler(
+    State(app_state): State<AppState>,
+    user_context: AuthUser,
+) -> Result<Json<PasskeyRegistrationStartResponse>, StatusCode> {
+    let webauthn = app_state.webauthn.clone().ok_or(StatusCode::NOT_FOUND)?;
+    let user_id = user_context.user_id.clone();
+    tracing::info!("Starting passkey registration for user {}", user_id);
+
+    let database = &app_state.database;
+
+    // Don't let the same authenticator register twice
+    let existing = database.get_passkeys(&user_id).await.map_err(|e| {
+        tracing::error!("Failed to fetch passkeys: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let exclude_credentials = existing
+        .iter()
+        .filter_map(|passkey| serde_json::from_str::<Passkey>(&passkey.passkey_json).ok())
+        .map(|passkey| passkey.cred_id().clone())
+        .collect();
+
+    let (options, state) = webauthn
+        .start_passkey_registration(
+            passkeys::user_handle(&user_id),
+            &user_context.email,
+            &user_context.email,
+            Some(exclude_credentials),
+        )
+        .map_err(|e| {
+            tracing::error!("Failed to start passkey registration: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let state_json = serde_json::to_string(&state).map_err(|e| {
+        tracing::error!("Failed to serialize registration state: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let ceremony_id = database
+        .create_webauthn_ceremony(
+            Some(&user_id),
+            passkeys::REGISTRATION,
+            &state_json,
+            &webauthn_ceremony_expiry(),
+        )
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to store registration state: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let options = passkeys::require_resident_key(&options).map_err(|e| {
+        tracing::error!("Failed to build registration options: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(Json(PasskeyRegistrationStartResponse {
+        ceremony_id,
+        options,
+    }))
+}
+
+// Finish registering a passkey with the authenticator's response
+async fn passkey_register_finish_handler(
+    State(app_state): State<AppState>,
+    user_context: AuthUser,
+    Json(payload): Json<PasskeyRegistrationFinishRequest>,
+) -> Result<Json<database::PasskeyCredential>, StatusCode> {
+    let webauthn = app_state.webauthn.clone().ok_or(StatusCode::NOT_FOUND)?;
+    let user_id = user_context.user_id.clone();
+    tracing::info!("Finishing passkey registration for user {}", user_id);
+
+    let database = &app_state.database;
+
+    let (ceremony_user_id, state_json) = database
+        .take_webauthn_ceremony(&payload.ceremony_id, passkeys::REGISTRATION)
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    if ceremony_user_id.as_deref() != Some(user_id.as_str()) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let state = serde_json::from_str(&state_json).map_err(|e| {
+        tracing::error!("Failed to read registration state: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let passkey = webauthn
+        .finish_passkey_registration(&payload.credential, &state)
+        .map_err(|e| {
+            tracing::warn!("Passkey registration failed: {:?}", e);
+            StatusCode::BAD_REQUEST
+        })?;
+
+    let passkey_json = serde_json::to_string(&passkey).map_err(|e| {
+        tracing::error!("Failed to serialize passkey: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let name = payload
+        .name
+        .filter(|name| !name.trim().is_empty())
+        .unwrap_or_else(|| "Passkey".to_string());
+
+    let credential = database
+        .create_passkey(
+            &user_id,
+            &passkeys::encode_credential_id(passkey.cred_id()),
+            &name,
+            &passkey_json,
+        )
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to store passkey: {:?}", e);
+            if e.to_string().contains("already exists") {
+                StatusCode::CONFLICT
+            } else {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+
+    record_audit(
+        database,
+        database::AuditEvent::new(&user_id, "passkey", &credential.id, "create").after(&credential),
+    )
+    .await;
+
+    Ok(Json(credential))
+}
+
+// Start a passwordless sign-in, the browser offers whichever passkeys it has for this site
+async fn passkey_login_start_handler(
+    State(app_state): State<AppState>,
+) -> Result<Json<PasskeyLoginStartResponse>, StatusCode> {
+    let webauthn = app_state.webauthn.clone().ok_or(StatusCode::NOT_FOUND)?;
+    tracing::info!("Starting passkey login");
+
+    let (mut options, state) = webauthn.start_discoverable_authentication().map_err(|e| {
+        tracing::error!("Failed to start passkey login: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Started from a sign-in button rather than form autofill
+    options.mediation = None;
+
+    let state_json = serde_json::to_string(&state).map_err(|e| {
+        tracing::error!("Failed to serialize authentication state: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let ceremony_id = app_state
+        .database
+        .create_webauthn_ceremony(
+            None,
+            passkeys::AUTHENTICATION,
+            &state_json,
+            &webauthn_ceremony_expiry(),
+        )
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to store authentication state: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Json(PasskeyLoginStartResponse {
+        ceremony_id,
+        options,
+    }))
+}
+
+// Finish a passwordless sign-in and start a session
+// Passkeys require user verification, so they stand in for both password and 2FA
+async fn passkey_login_finish_handler(
+    State(app_state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<PasskeyLoginFinishRequest>,
+) -> Result<Json<AuthResponse>, StatusCode> {
+    let webauthn = app_state.webauthn.clone().ok_or(StatusCode::NOT_FOUND)?;
+    tracing::info!("Finishing passkey login");
+
+    let database = &app_state.database;
+
+    let (_, state_json) = database
+        .take_webauthn_ceremony(&payload.ceremony_id, passkeys::AUTHENTICATION)
+        .await
+        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+    let state = serde_json::from_str(&state_json).map_err(|e| {
+        tracing::error!("Failed to read authentication state: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let (_, credential_id) = webauthn
+        .identify_discoverable_authentication(&payload.credential)
+        .map_err(|e| {
+            tracing::warn!("Could not identify passkey: {:?}", e);
+            StatusCode::UNAUTHORIZED
+        })?;
+
+    let stored = database
+        .get_passkey_by_credential_id(&passkeys::encode_credential_id(credential_id))
+        .await
+        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+    let mut passkey: Passkey = serde_json::from_str(&stored.passkey_json).map_err(|e| {
+        tracing::error!("Failed to read stored passkey: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let result = webauthn
+        .finish_discoverable_authentication(&payload.credential, state, &[(&passkey).into()])
+        .map_err(|e| {
+            tracing::warn!("Passkey login failed: {:?}", e);
+            StatusCode::UNAUTHORIZED
+        })?;
+
+    passkey.update_credential(&result);
+    match serde_json::to_string(&passkey) {
+        Ok(passkey_json) => {
+            if let Err(e) = database
+                .update_passkey_after_login(&stored.id, &passkey_json)
+                .await
+            {
+                tracing::warn!("Failed to update passkey counter: {:?}", e);
+            }
+        }
+        Err(e) => tracing::warn!("Failed to serialize passkey: {:?}", e),
+    }
+
+    let mut user = database.get_user(&stored.user_id).await.map_err(|e| {
+        tracing::error!("Failed to fetch user: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    record_audit(
+        database,
+        database::AuditEvent::new(&user.id, "user", &user.id, "login")
+            .after(&json!({ "passkey_id": stored.id })),
+    )
+    .await;
+
+    // Start a session and issue its tokens
+    let device = session_device(&app_state, &headers, &addr, payload.device_label.clone());
+    let (token, refresh_token) = start_session(&app_state, &user, &device).await?;
+
+    user.auth_token = Some(token.clone());
+
+    tracing::info!("Successfully logged in user with passkey: {}", user.email);
+
+    Ok(Json(AuthResponse {
+        token,
+        refresh_token,
+        user,
+    }))
+}
+
+/// Check a TOTP or recovery code for a user with 2FA enabled, returning which one was used
+async fn verify_second_factor(
+    database: &Database,
+    user_id: &str,
+    code: Option<&str>,
+    recovery_code: Option<&str>,
+) -> Result<&'static str, StatusCode> {
+    let totp = database
+        .get_user_totp(user_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to fetch two-factor settings: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .filter(|totp| totp.enabled_at.is_some())
+        .ok_or(StatusCode::BAD_REQUEST)?;
+
+    if let Some(code) = code {
+        let step = totp::verify(&totp.secret, code, Utc::now().timestamp() as u64)
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+
+        let fresh = database
+            .record_totp_step(user_id, step)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to record TOTP step: {:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        if !fresh {
+            tracing::warn!("Rejected replayed TOTP code for user: {}", user_id);
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+
+        return Ok("totp");
+    }
+
+    if let Some(recovery_code) = recovery_code {
+        let code_hash = tokens::hash(&totp::normalize_recovery_code(recovery_code));
+        let valid = database
+            .consume_recovery_code(user_id, &code_hash)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to check recovery code: {:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        if !valid {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+
+        tracing::info!("Recovery code used by user: {}", user_id);
+        return Ok("recovery_code");
+    }
+
+    Err(StatusCode::BAD_REQUEST)
+}
+
+async fn two_factor_status_handler(
+    State(app_state): State<AppState>,
+    user_context: AuthUser,
+) -> Result<Json<TwoFactorStatusResponse>, StatusCode> {
+    let user_id = user_context.user_id.clone();
+    let database = &app_state.database;
+
+    let totp = database.get_user_totp(&user_id).await.map_err(|e| {
+        tracing::error!("Failed to fetch two-factor settings: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let recovery_codes_remaining = database
+        .count_unused_recovery_codes(&user_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to count recovery codes: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Json(TwoFactorStatusResponse {
+        enabled: totp.is_some_and(|totp| totp.enabled_at.is_some()),
+        recovery_codes_remaining,
+    }))
+}
+
+// Generate a TOTP secret to add to an authenticator app, 2FA stays off until it's confirmed
+async fn totp_enroll_handler(
+    State(app_state): State<AppState>,
+    user_context: AuthUser,
+) -> Result<Json<TotpEnrollmentResponse>, StatusCode> {
+    let user_id = user_context.user_id.clone();
+    tracing::info!("Starting TOTP enrollment for user {}", user_id);
+
+    let database = &app_state.database;
+
+    let existing = database.get_user_totp(&user_id).await.map_err(|e| {
+        tracing::error!("Failed to fetch two-factor settings: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if existing.is_some_and(|totp| totp.enabled_at.is_some()) {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let secret = totp::generate_secret();
+    database
+        .start_totp_enrollment(&user_id, &secret)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to store TOTP secret: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Json(TotpEnrollmentResponse {
+        otpauth_uri: totp::otpauth_uri(&secret, &user_context.email),
+        secret,
+    }))
+}
+
+// Confirm enrollment with a first code, turning 2FA on and handing out recovery codes
+async fn totp_enable_handler(
+    State(app_state): State<AppState>,
+    user_context: AuthUser,
+    Json(payload): Json<TotpCodeRequest>,
+) -> Result<Json<RecoveryCodesResponse>, StatusCode> {
+    let user_id = user_context.user_id.clone();
+    tracing::info!("Enabling TOTP for user {}", user_id);
+
+    let database = &app_state.database;
+
+    let totp = database
+        .get_user_totp(&user_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to fetch two-factor settings: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    if totp.enabled_at.is_some() {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let step = totp::verify(&totp.secret, &payload.code, Utc::now().timestamp() as u64)
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    let recovery_codes = totp::generate_recovery_codes();
+    let recovery_code_hashes: Vec<String> = recovery_codes
+        .iter()
+        .map(|code| tokens::hash(&totp::normalize_recovery_code(code)))
+        .collect();
+
+    database
+        .enable_totp(&user_id, step, &recovery_code_hashes)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to enable TOTP: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    record_audit(
+        database,
+        database::AuditEvent::new(&user_id, "user", &user_id, "enable_2fa"),
+    )
+    .await;
+
+    Ok(Json(RecoveryCodesResponse { recovery_codes }))
+}
+
+// Turn 2FA off, which takes the password and a current code or recovery code
+async fn two_factor_disable_handler(
+    State(app_state): State<AppState>,
+    user_context: AuthUser,
+    Json(payload): Json<DisableTwoFactorRequest>,
+) -> Result<StatusCode, StatusCode> {
+    let user_id = user_context.user_id.clone();
+    tracing::info!("Disabling two-factor for user {}", user_id);
+
+    let database = &app_state.database;
+
+    database
+        .verify_password(&user_context.email, &payload.password)
+        .await
+        .map_err(|e| {
+            tracing::warn!("Password check failed for {}: {:?}", user_id, e);
+            StatusCode::FORBIDDEN
+        })?;
+
+    verify_second_factor(
+        database,
+        &user_id,
+        payload.code.as_deref(),
+        payload.recovery_code.as_deref(),
+    )
+    .await
+    .map_err(|status| {
+        if status == StatusCode::UNAUTHORIZED {
+            StatusCode::FORBIDDEN
+        } else {
+            status
+        }
+    })?;
+
+    database.disable_totp(&user_id).await.map_err(|e| {
+        tracing::error!("Failed to disable two-factor: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    record_audit(
+        database,
+        database::AuditEvent::new(&user_id, "user", &user_id, "disable_2fa"),
+    )
+    .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Exchange a refresh token for a new access token, rotating the refresh token
+async fn refresh_token_handler(
+    State(app_state): State<AppState>,
+    Json(payload): Json<RefreshTokenRequest>,
+) -> Result<Json<RefreshTokenResponse>, StatusCode> {
+    tracing::info!("Processing token refresh request");
+
+    let database = &app_state.database;
+    let presented_hash = tokens::hash(&payload.refresh_token);
+
+    let session = database
+        .get_session_by_refresh_token(&presented_hash)
+        .await
+        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+    // A rotated-out token being used again means it leaked, kill the whole session
+    // Several new-tab pages often refresh at once, so reuse right after a rotation is tolerated
+    if session.refresh_token_hash != presented_hash {
+        let recently_rotated = session
+            .rotated_at
+            .as_deref()
+            .and_then(|rotated_at| chrono::DateTime::parse_from_rfc3339(rotated_at).ok())
+            .is_some_and(|rotated_at| {
+                Utc::now().signed_duration_since(rotated_at) < chrono::Duration::seconds(30)
+            });
+
+        if !recently_rotated {
+            tracing::warn!(
+                "Refresh token reuse detected for session {}, revoking it",
+                session.id
+            );
+            if let Err(e) = database.revoke_session(&session.id).await {
+                tracing::error!("Failed to revoke session: {:?}", e);
+            }
+        }
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    if session.revoked_at.is_some() || session.expires_at < Utc::now().to_rfc3339() {
+        tracing::info!("Refresh token used for inactive session {}", session.id);
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let user = database.get_user(&session.user_id).await.map_err(|e| {
+        tracing::warn!("Refresh for missing user {}: {:?}", session.user_id, e);
+        StatusCode::UNAUTHORIZED
+    })?;
+    if user.disabled_at.is_some() {
+        tracing::warn!("Rejected token refresh for disabled user {}", user.id);
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let refresh_token = tokens::generate();
+    database
+        .rotate_session(
+            &session.id,
+            &presented_hash,
+            &tokens::hash(&refresh_token),
+            &(Utc::now() + refresh_token_ttl()),
+        )
+        .await
+        .map_err(|e| {
+            tracing::warn!("Failed to rotate session {}: {:?}", session.id, e);
+            StatusCode::UNAUTHORIZED
+        })?;
+
+    let token = app_state
+        .jwt_keys
+        .generate_jwt(&user.id, &user.email, &session.id)
+        .map_err(|e| {
+            tracing::error!("Failed to generate JWT: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Json(RefreshTokenResponse {
+        token,
+        refresh_token,
+    }))
+}
+
+// End the current session and revoke the access token used to call this
+async fn logout_handler(
+    State(app_state): State<AppState>,
+    user_context: AuthUser,
+) -> Result<StatusCode, StatusCode> {
+    let user_id = user_context.user_id.clone();
+    tracing::info!(
+        "Logging out session {} for user {}",
+        user_context.session_id,
+        user_id
+    );
+
+    let database = &app_state.database;
+
+    database
+        .revoke_session(&user_context.session_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to revoke session: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let token_expires_at =
+        chrono::DateTime::from_timestamp(user_context.token_expires_at as i64, 0)
+            .unwrap_or_else(Utc::now);
+    database
+        .revoke_token(&user_context.token_id, &token_expires_at)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to revoke token: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    record_audit(
+        database,
+        database::AuditEvent::new(&user_id, "session", &user_context.session_id, "logout"),
+    )
+    .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Revoke every session for the user, signing them out on all devices
+async fn logout_all_handler(
+    State(app_state): State<AppState>,
+    user_context: AuthUser,
+) -> Result<StatusCode, StatusCode> {
+    let user_id = user_context.user_id.clone();
+    tracing::info!("Logging out all sessions for user {}", user_id);
+
+    let database = &app_state.database;
+
+    database.revoke_user_sessions(&user_id).await.map_err(|e| {
+        tracing::error!("Failed to revoke sessions: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    record_audit(
+        database,
+        database::AuditEvent::new(&user_id, "user", &user_id, "logout_all"),
+    )
+    .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Send the user a fresh email verification link
+async fn request_email_verification_handler(
+    State(app_state): State<AppState>,
+    user_context: AuthUser,
+) -> Result<StatusCode, StatusCode> {
+    let user_id = user_context.user_id.clone();
+    tracing::info!("Requesting email verification for user {}", user_id);
+
+    let user = app_state.database.get_user(&user_id).await.map_err(|e| {
+        tracing::error!("Failed to fetch user: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    if user.email_verified_at.is_some() {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    send_auth_token_email(
+        &app_state,
+        &user,
+        database::TOKEN_PURPOSE_VERIFY_EMAIL,
+        None,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to send verification email: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+// Redeem an email verification token
+async fn confirm_email_handler(
+    State(app_state): State<AppState>,
+    Json(payload): Json<ConfirmEmailRequest>,
+) -> Result<StatusCode, StatusCode> {
+    tracing::info!("Confirming email verification");
+
+    let database = &app_state.database;
+
+    let user_id = database
+        .consume_auth_token(
+            database::TOKEN_PURPOSE_VERIFY_EMAIL,
+            &tokens::hash(&payload.token),
+        )
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    database.mark_email_verified(&user_id).await.map_err(|e| {
+        tracing::error!("Failed to mark email verified: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    record_audit(
+        database,
+        database::AuditEvent::new(&user_id, "user", &user_id, "verify_email"),
+    )
+    .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Email a password reset link
+// Always accepted, so the response doesn't reveal which emails have accounts
+async fn request_password_reset_handler(
+    State(app_state): State<AppState>,
+    Json(payload): Json<PasswordResetRequest>,
+) -> StatusCode {
+    tracing::info!("Password reset requested for: {}", payload.email);
+
+    match app_state.database.get_user_by_email(&payload.email).await {
+        Ok(user) => {
+            if let Err(e) = send_auth_token_email(
+                &app_state,
+                &user,
+                database::TOKEN_PURPOSE_RESET_PASSWORD,
+                None,
+            )
+            .await
+            {
+                tracing::error!("Failed to send password reset email: {:?}", e);
+            }
+        }
+        Err(e) => tracing::info!("No user for password reset request: {:?}", e),
+    }
+
+    StatusCode::ACCEPTED
+}
+
+// Redeem a password reset token, setting the new password and signing out every session
+async fn confirm_password_reset_handler(
+    State(app_state): State<AppState>,
+    Json(payload): Json<ConfirmPasswordResetRequest>,
+) -> Result<StatusCode, LoginError> {
+    tracing::info!("Confirming password reset");
+
+    let database = &app_state.database;
+    let token_hash = tokens::hash(&payload.token);
+
+    let user_id = database
+        .peek_auth_token(database::TOKEN_PURPOSE_RESET_PASSWORD, &token_hash)
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let user = database.get_user(&user_id).await.map_err(|e| {
+        tracing::error!("Failed to fetch user: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Check the new password before using up the link, so a rejected one can be retried
+    app_state
+        .password_policy
+        .check(&payload.new_password, &user.email)
+        .await?;
+
+    database
+        .consume_auth_token(database::TOKEN_PURPOSE_RESET_PASSWORD, &token_hash)
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    database
+        .update_password(&user_id, &payload.new_password)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to update password: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    database.revoke_user_sessions(&user_id).await.map_err(|e| {
+        tracing::error!("Failed to revoke sessions: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // The reset link proves the user can read mail at this address
+    if let Err(e) = database.mark_email_verified(&user_id).await {
+        tracing::warn!("Failed to mark email verified: {:?}", e);
+    }
+
+    record_audit(
+        database,
+        database::AuditEvent::new(&user_id, "user", &user_id, "reset_password"),
+    )
+    .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Change the password after checking the current one, signing out every other session
+async fn change_password_handler(
+    State(app_state): State<AppState>,
+    user_context: AuthUser,
+    Json(payload): Json<ChangePasswordRequest>,
+) -> Result<StatusCode, LoginError> {
+    let user_id = user_context.user_id.clone();
+    tracing::info!("Changing password for user {}", user_id);
+
+    let database = &app_state.database;
+
+    database
+        .verify_password(&user_context.email, &payload.current_password)
+        .await
+        .map_err(|e| {
+            tracing::warn!("Current password check failed for {}: {:?}", user_id, e);
+            StatusCode::FORBIDDEN
+        })?;
+
+    app_state
+        .password_policy
+        .check(&payload.new_password, &user_context.email)
+        .await?;
+
+    database
+        .update_password(&user_id, &payload.new_password)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to update password: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    database
+        .revoke_other_sessions(&user_id, &user_context.session_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to revoke other sessions: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    record_audit(
+        database,
+        database::AuditEvent::new(&user_id, "user", &user_id, "change_password"),
+    )
+    .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Delete the account and all of its data, optionally emailing an export first
+async fn delete_account_handler(
+    State(app_state): State<AppState>,
+    user_context: AuthUser,
+    Json(payload): Json<DeleteAccountRequest>,
+) -> Result<StatusCode, StatusCode> {
+    let user_id = user_context.user_id.clone();
+    tracing::info!("Deleting account for user {}", user_id);
+
+    let database = &app_state.database;
+
+    let user = database.get_user(&user_id).await.map_err(|e| {
+        tracing::error!("Failed to fetch user: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    if user.password_hash.is_empty() {
+        require_recent_sign_in(database, &user_context).await?;
+    } else {
+        database
+            .verify_password(&user.email, payload.password.as_deref().unwrap_or_default())
+            .await
+            .map_err(|e| {
+                tracing::warn!("Password check failed for {}: {:?}", user_id, e);
+                StatusCode::FORBIDDEN
+            })?;
+    }
+
+    // Don't delete anything if the export the user asked for couldn't be sent
+    if payload.email_export {
+        let links = database.get_links(&user_id, "user").await.map_err(|e| {
+            tracing::error!("Failed to fetch links for export: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        let export = UserDataResponse {
+            user: user.clone(),
+            settings: database.get_user_settings(&user_id).await.ok(),
+            links,
+        };
+        let export_json = serde_json::to_string_pretty(&export).map_err(|e| {
+            tracing::error!("Failed to serialize export: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+        app_state
+            .mailer
+            .send(&email_templates::account_export(&user.email, &export_json))
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to send account export: {:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+    }
+
+    database.delete_account(&user_id).await.map_err(|e| {
+        tracing::error!("Failed to delete account: {:?}", e);
+        if e.to_string() == "404" {
+            StatusCode::NOT_FOUND
+        } else {
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    })?;
+
+    record_audit(
+        database,
+        // No snapshot, the user's details shouldn't outlive their account
+        database::AuditEvent::new(&user_id, "user", &user_id, "delete_account"),
+    )
+    .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// How long after signing in a passwordless account can still do things that need re-authentication
+const RECENT_SIGN_IN_MINUTES: i64 = 10;
+
+/// Accounts without a password (SSO, passkey-only) re-authenticate by signing in again,
+/// so their session has to be fresh. A trusted proxy or local mode vouches for every request
+async fn require_recent_sign_in(
+    database: &Database,
+    user_context: &AuthUser,
+) -> Result<(), StatusCode> {
+    if user_context.session_id.is_empty() {
+        return if user_context.api_token_id.is_none() {
+            Ok(())
+        } else {
+            Err(StatusCode::FORBIDDEN)
+        };
+    }
+
+    let sessions = database
+        .get_active_sessions(&user_context.user_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to fetch sessions: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let signed_in_at = sessions
+        .iter()
+        .find(|session| session.id == user_context.session_id)
+        .and_then(|session| chrono::DateTime::parse_from_rfc3339(&session.created_at).ok());
+
+    match signed_in_at {
+        Some(signed_in_at)
+            if Utc::now().signed_duration_since(signed_in_at)
+                < chrono::Duration::minutes(RECENT_SIGN_IN_MINUTES) =>
+        {
+            Ok(())
+        }
+        _ => {
+            tracing::warn!("User {} needs to sign in again first", user_context.user_id);
+            Err(StatusCode::FORBIDDEN)
+        }
+    }
+}
+
+// Start an email change by sending a confirmation link to the new address
+async fn request_email_change_handler(
+    State(app_state): State<AppState>,
+    user_context: AuthUser,
+    Json(payload): Json<ChangeEmailRequest>,
+) -> Result<StatusCode, StatusCode> {
+    let user_id = user_context.user_id.clone();
+    tracing::info!("Requesting email change for user {}", user_id);
+
+    let new_email = payload.new_email.trim();
+    if new_email.is_empty() || !new_email.contains('@') {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let database = &app_state.database;
+
+    let user = database
+        .verify_password(&user_context.email, &payload.current_password)
+        .await
+        .map_err(|e| {
+            tracing::warn!("Current password check failed for {}: {:?}", user_id, e);
+            StatusCode::FORBIDDEN
+        })?;
+
+    if database.get_user_by_email(new_email).await.is_ok() {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    send_auth_token_email(
+        &app_state,
+        &user,
+        database::TOKEN_PURPOSE_CHANGE_EMAIL,
+        Some(new_email),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to send email change confirmation: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+// Confirm an email change, signing out every existing session and starting a new one here
+// with tokens for the new email
+async fn confirm_email_change_handler(
+    State(app_state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<ConfirmEmailRequest>,
+) -> Result<Json<AuthResponse>, StatusCode> {
+    let database = &app_state.database;
+
+    // The token alone authorizes the change, the link is often opened on another device
+    let (user_id, new_email) = database
+        .consume_email_change_token(&tokens::hash(&payload.token))
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    tracing::info!("Confirming email change for user {}", user_id);
+
+    let before = database.get_user(&user_id).await.map_err(|e| {
+        tracing::error!("Failed to fetch user: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    database
+        .update_email(&user_id, &new_email)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to update email: {:?}", e);
+            if e.to_string().contains("already exists") {
+                StatusCode::CONFLICT
+            } else {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+
+    // Every other session's tokens carry the old email, so they all sign in again with the new one
+    database.revoke_user_sessions(&user_id).await.map_err(|e| {
+        tracing::error!("Failed to revoke sessions: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let mut user = database.get_user(&user_id).await.map_err(|e| {
+        tracing::error!("Failed to fetch user: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    record_audit(
+        database,
+        database::AuditEvent::new(&user_id, "user", &user_id, "change_email")
+            .before(&before)
+            .after(&user),
+    )
+    .await;
+
+    // The device confirming carries on signed in, its new token carries the new email
+    let device = session_device(&app_state, &headers, &addr, None);
+    let (token, refresh_token) = start_session(&app_state, &user, &device).await?;
+    user.auth_token = Some(token.clone());
+
+    Ok(Json(AuthResponse {
+        token,
+        refresh_token,
+        user,
+    }))
+}
+
+// List the user's active sessions, flagging the one making the request
+async fn sessions_handler(
+    State(app_state): State<AppState>,
+    user_context: AuthUser,
+) -> Result<Json<Vec<SessionResponse>>, StatusCode> {
+    let user_id = user_context.user_id.clone();
+    tracing::info!("Fetching sessions for user {}", user_id);
+
+    let database = &app_state.database;
+
+    let sessions = database.get_active_sessions(&user_id).await.map_err(|e| {
+        tracing::error!("Failed to fetch sessions: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(Json(
+        sessions
+            .into_iter()
+            .map(|session| SessionResponse {
+                current: session.id == user_context.session_id,
+                session,
+            })
+            .collect(),
+    ))
+}
+
+// Revoke a single session, signing that device out
+async fn revoke_session_handler(
+    State(app_state): State<AppState>,
+    Path(session_id): Path<String>,
+    user_context: AuthUser,
+) -> Result<StatusCode, StatusCode> {
+    let user_id = user_context.user_id.clone();
+    tracing::info!("Revoking session {} for user {}", session_id, user_id);
+
+    let database = &app_state.database;
+
+    database
+        .revoke_user_session(&user_id, &session_id)
+        .await
+        .map_err(|e| match e.to_string().as_str() {
+            "404" => StatusCode::NOT_FOUND,
+            _ => {
+                tracing::error!("Failed to revoke session: {:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+
+    record_audit(
+        database,
+        database::AuditEvent::new(&user_id, "session", &session_id, "revoke"),
+    )
+    .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Staging login handler
+async fn staging_login_handler(
+    Json(payload): Json<StagingLoginRequest>,
+) -> Result<StatusCode, StatusCode> {
+    println!("Processing staging login request");
+
+    // Get the staging password from environment variables
+    let staging_password = match env::var("STAGING_PASSWORD") {
+        Ok(pwd) => pwd,
+        Err(_) => {
+            println!("STAGING_PASSWORD environment variable not set");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    // Simple password validation
+    if payload.password == staging_password {
+        println!("Staging login successful");
+        return Ok(StatusCode::OK);
+    } else {
+        println!("Invalid staging password provided");
+        return Err(StatusCode::FORBIDDEN);
+    }
+}
+
+async fn links_handler(
+    State(app_state): State<AppState>,
+    user_context: AuthUser,
+) -> Result<Json<Vec<database::Link>>, StatusCode> {
+    let user_id = user_context.user_id.clone();
+    println!("Fetching links for user: {}", user_id);
+
+    tracing::info!("Fetching links for user: {}", user_id);
+
+    // Use app_state's database instance
+    let database = &app_state.database;
+
+    let links = database.get_links(&user_id, "user").await.map_err(|e| {
+        tracing::error!("Failed to fetch links for user {}: {:?}", user_id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    tracing::info!(
+        "Successfully fetched {} links for user {}",
+        links.len(),
+        user_id
+    );
+    Ok(Json(links))
+}
+
+async fn duplicate_links_handler(
+    State(app_state): State<AppState>,
+    user_context: AuthUser,
+) -> Result<Json<Vec<database::DuplicateLinkGroup>>, StatusCode> {
+    let user_id = user_context.user_id.clone();
+
+    tracing::info!("Fetching duplicate links for user: {}", user_id);
+
+    let database = &app_state.database;
+
+    let groups = database
+        .get_duplicate_link_groups(&user_id, "user")
+        .await
+        .map_err(|e| {
+            tracing::error!(
+                "Failed to fetch duplicate links for user {}: {:?}",
+                user_id,
+                e
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Json(groups))
+}
+
+async fn create_link(
+    State(app_state): State<AppState>,
+    user_context: AuthUser,
+    headers: HeaderMap,
+    Json(payload): Json<CreateLinkRequest>,
+) -> Result<(StatusCode, Json<database::Link>), StatusCode> {
+    let user_id = user_context.user_id.clone();
+    let client = &app_state.client;
+    let database = &app_state.database;
+
+    println!(
+        "Creating new link for owner {}: {}",
+        payload.owner_id, payload.url
+    );
+
+    tracing::info!(
+        "Creating new link for owner {}: {}",
+        payload.owner_id,
+        payload.url
+    );
+
+    require_link_owner_role(
+        database,
+        &user_id,
+        &payload.owner_type,
+        &payload.owner_id,
+        Role::Member,
+    )
+    .await?;
+
+    let url = link_url::with_default_scheme(&payload.url);
+
+    let scheme = link_url::check_scheme(&url).map_err(|e| {
+        tracing::warn!("Rejected link URL {}: {:?}", url, e);
+        StatusCode::BAD_REQUEST
+    })?;
+
+    // Only http(s) links have a page to pull metadata and favicons from
+    let fetchable = link_url::is_http(&url);
+
+    let canonical_url =
+        link_url::normalize(&url, link_url::strip_tracking_enabled()).map_err(|e| {
+            tracing::warn!("Invalid link URL {}: {:?}", url, e);
+            StatusCode::BAD_REQUEST
+        })?;
+
+    // Reject duplicates within the owner unless the client explicitly allows them
+    let duplicate = database
+        .find_duplicate_link(&payload.owner_id, &payload.owner_type, &canonical_url, None)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to check for duplicate link: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    if let Some(existing) = duplicate {
+        if !payload.allow_duplicate {
+            tracing::info!(
+                "Rejecting duplicate of link {} for owner {}",
+                existing.id,
+                payload.owner_id
+            );
+            return Err(StatusCode::CONFLICT);
+        }
+        tracing::warn!(
+            "Creating duplicate of link {} for owner {}",
+            existing.id,
+            payload.owner_id
+        );
+    }
+
+    let metadata_on = headers
+        .get("X-Fetch-Metadata")
+        .and_then(|m| m.to_str().ok())
+        .map(|s| s.to_lowercase() == "true")
+        .unwrap_or(false);
+
+    // init metadata, retrieve from link's URL, else use defaults
+    let metadata = if metadata_on && fetchable {
+        match get_metadata(State(client.clone()), &url).await {
+            Ok(metadata) => metadata,
+            Err(StatusCode::BAD_GATEWAY) => {
+                // If we get BAD_GATEWAY from get_metadata, return it directly to the client
+                return Err(StatusCode::BAD_GATEWAY);
+            }
+            Err(_) => {
+                // For any other errors, use default metadata
+                Metadata {
+                    title: Some(url.clone()),
+                    description: None,
+                    favicon: None,
+                    mime_type: None,
+                }
+            }
+        }
+    } else {
+        Metadata {
+            title: Some(url.clone()),
+            description: None,
+            favicon: None,
+            mime_type: None,
+        }
+    };
+
+    // use the user's title, if empty use metadata, metadata will be the URL if metadata is not fetched
+    let title = if payload.title.as_deref() == Some("") {
+        metadata.title.unwrap_or_else(|| "".to_string())
+    } else {
+        payload
+            .title
+            .unwrap_or_else(|| metadata.title.unwrap().clone())
+    };
+
+    // grab the favicon, fall back to a bundled icon for non-HTTP schemes, or just pass an empty string
+    let favicon = if !fetchable {
+        link_url::default_icon(&scheme)
+    } else if metadata_on {
+        get_favicon(
+            State(client),
+            &url,
+            metadata.favicon.clone(),
+            metadata.mime_type.clone(),
+        )
+        .await
+        .map_err(|e| {
+            tracing::error!("Error getting favicon: {:?}", e);
+        })
+        .unwrap_or_else(|_| "".to_string())
+    } else {
+        "".to_string()
+    };
+
+    // use the user's description, or the metadata description
+    let description = if payload.description.as_deref() == Some("") {
+        metadata.description.unwrap_or_else(|| "".to_string())
+    } else {
+        payload
+            .description
+            .unwrap_or_else(|| metadata.description.unwrap().clone())
+    };
+
+    let link = database::Link {
+        id: uuid::Uuid::new_v4().to_string(),
+        url: url,
+        description: Some(description),
+        created_at: Utc::now().to_rfc3339(),
+        title: title,
+        icon: Some(favicon),
+        order_index: payload.next_order_index,
+        owner_type: payload.owner_type,
+        owner_id: payload.owner_id,
+        column_type: payload.column_type,
+        canonical_url: Some(canonical_url),
+        deleted_at: None,
+    };
+
+    if let Err(e) = database.create_link(link.clone()).await {
+        tracing::error!("Failed to create link in database: {:?}", e);
+        println!("Failed to create link in database: {:?}", e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    record_audit(
+        database,
+        database::AuditEvent::new(&user_id, "link", &link.id, "create")
+            .in_organization(link_organization(database, &link).await.as_deref())
+            .after(&link),
+    )
+    .await;
+
+    tracing::info!("Successfully created link with ID: {}", link.id);
+    Ok((StatusCode::CREATED, Json(link)))
+}
+
+async fn update_link(
+    State(app_state): State<AppState>,
+    user_context: AuthUser,
+    Json(payload): Json<UpdateLinkRequest>,
+) -> Result<StatusCode, StatusCode> {
+    let user_id = user_context.user_id.clone();
+    println!("Updating link: {}", payload.id);
+
+    tracing::info!("Updating link: {}", payload.id);
+
+    // Use app_state's database instance
+    let database = &app_state.database;
+
+    let before = get_permitted_link(database, &user_id, &payload.id, Role::Member).await?;
+
+    // Trashed links have to be restored before they can be edited
+    if before.deleted_at.is_some() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let url = payload.url.as_deref().map(link_url::with_default_scheme);
+
+    if let Some(url) = url.as_deref() {
+        if let Err(e) = link_url::check_scheme(url) {
+            tracing::warn!("Rejected link URL {}: {:?}", url, e);
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    }
+
+    // Only a changed url needs a new canonical form, otherwise the link keeps what it has
+    let (url, canonical_url) = match url {
+        Some(url) if url != before.url => {
+            let canonical_url = link_url::normalize(&url, link_url::strip_tracking_enabled()).ok();
+            (url, canonical_url)
+        }
+        _ => (before.url.clone(), before.canonical_url.clone()),
+    };
+
+    // Moving a link onto another link's URL is held to the same rule as creating one there
+    if canonical_url != before.canonical_url && !payload.allow_duplicate {
+        if let Some(canonical_url) = canonical_url.as_deref() {
+            let duplicate = database
+                .find_duplicate_link(
+                    &before.owner_id,
+                    &before.owner_type,
+                    canonical_url,
+                    Some(&before.id),
+                )
+                .await
+                .map_err(|e| {
+                    tracing::error!("Failed to check for duplicate link: {:?}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })?;
+            if let Some(existing) = duplicate {
+                tracing::info!(
+                    "Rejecting update of link {} onto duplicate {}",
+                    before.id,
+                    existing.id
+                );
+                return Err(StatusCode::CONFLICT);
+            }
+        }
+    }
+
+    let link = database::Link {
+        id: payload.id.clone(),
+        url,
+        description: payload.description.clone(),
+        title: payload.title.clone().unwrap(),
+        icon: payload.icon.clone(),
+        column_type: payload.column_type.clone().unwrap(),
+        created_at: Utc::now().to_rfc3339(),
+        order_index: 0,
+        owner_type: "".to_string(),
+        owner_id: "".to_string(),
+        canonical_url,
+        deleted_at: None,
+    };
+
+    if let Err(e) = database.update_link(link).await {
+        tracing::error!("Failed to update link: {:?}", e);
+        println!("Failed to update link: {:?}", e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    let mut event = database::AuditEvent::new(&user_id, "link", &payload.id, "update")
+        .in_organization(link_organization(database, &before).await.as_deref())
+        .before(&before);
+    if let Ok(after) = database.get_link_by_id(&payload.id).await {
+        event = event.after(&after);
+    }
+    record_audit(database, event).await;
+
+    tracing::info!("Successfully updated link {}", payload.id);
+    Ok(StatusCode::OK)
+}
+
+async fn delete_link(
+    State(app_state): State<AppState>,
+    Path(link_id): Path<String>,
+    user_context: AuthUser,
+) -> Result<StatusCode, StatusCode> {
+    let user_id = user_context.user_id.clone();
+
+    // Use app_state's database instance
+    let database = &app_state.database;
+
+    // The user's own link, or one in a workspace they're a member of
+    let link = get_permitted_link(database, &user_id, &link_id, Role::Member).await?;
+
+    // Already in the trash
+    if link.deleted_at.is_some() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    // Move the link to the trash
+    if let Err(e) = database.delete_link(&link_id).await {
+        tracing::error!("Error deleting link: {:?}", e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    record_audit(
+        database,
+        database::AuditEvent::new(&user_id, "link", &link_id, "delete")
+            .in_organization(link_organization(database, &link).await.as_deref())
+            .before(&link),
+    )
+    .await;
+
+    tracing::info!("Successfully deleted link: {}", link_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn trashed_links_handler(
+    State(app_state): State<AppState>,
+    user_context: AuthUser,
+) -> Result<Json<Vec<database::Link>>, StatusCode> {
+    let user_id = user_context.user_id.clone();
+
+    tracing::info!("Fetching trashed links for user: {}", user_id);
+
+    let database = &app_state.database;
+
+    let links = database
+        .get_trashed_links(&user_id, "user")
+        .await
+        .map_err(|e| {
+            tracing::error!(
+                "Failed to fetch trashed links for user {}: {:?}",
+                user_id,
+                e
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Json(links))
+}
+
+async fn restore_link(
+    State(app_state): State<AppState>,
+    Path(link_id): Path<String>,
+    user_context: AuthUser,
+) -> Result<Json<database::Link>, StatusCode> {
+    let user_id = user_context.user_id.clone();
+
+    let database = &app_state.database;
+
+    let mut link = get_permitted_link(database, &user_id, &link_id, Role::Member).await?;
+
+    if link.deleted_at.is_none() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    if let Err(e) = database.restore_link(&link_id).await {
+        tracing::error!("Error restoring link: {:?}", e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    link.deleted_at = None;
+
+    record_audit(
+        database,
+        database::AuditEvent::new(&user_id, "link", &link_id, "restore")
+            .in_organization(link_organization(database, &link).await.as_deref())
+            .after(&link),
+    )
+    .await;
+
+    tracing::info!("Successfully restored link: {}", link_id);
+    Ok(Json(link))
+}
+
+async fn purge_link(
+    State(app_state): State<AppState>,
+    Path(link_id): Path<String>,
+    user_context: AuthUser,
+) -> Result<StatusCode, StatusCode> {
+    let user_id = user_context.user_id.clone();
+
+    let database = &app_state.database;
+
+    let link = get_permitted_link(database, &user_id, &link_id, Role::Member).await?;
+
+    // Only links already in the trash can be purged
+    if link.deleted_at.is_none() {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    if let Err(e) = database.purge_link(&link_id).await {
+        tracing::error!("Error purging link: {:?}", e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    record_audit(
+        database,
+        database::AuditEvent::new(&user_id, "link", &link_id, "purge")
+            .in_organization(link_organization(database, &link).await.as_deref())
+            .before(&link),
+    )
+    .await;
+
+    tracing::info!("Successfully purged link: {}", link_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Look up a user for an admin action, refusing actions an admin takes on their own account
+/// so an instance can't lose its last admin by accident
+async fn admin_target_user(
+    database: &Database,
+    admin: &AuthUser,
+    user_id: &str,
+) -> Result<database::User, StatusCode> {
+    if user_id == admin.user_id {
+        tracing::warn!("Admin {} tried to act on their own account", admin.user_id);
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    database.get_user(user_id).await.map_err(|e| {
+        if e.to_string() == "404" {
+            StatusCode::NOT_FOUND
+        } else {
+            tracing::error!("Failed to fetch user: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    })
+}
+
+// List and search every user on the instance
+async fn admin_users_handler(
+    State(app_state): State<AppState>,
+    _admin: AdminUser,
+    Query(query): Query<AdminUsersQuery>,
+) -> Result<Json<AdminUsersResponse>, StatusCode> {
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query.per_page.unwrap_or(50).clamp(1, 200);
+    let search = query
+        .query
+        .as_deref()
+        .map(str::trim)
+        .filter(|search| !search.is_empty());
+
+    let (users, total) = app_state
+        .database
+        .search_users(search, per_page, (page - 1) * per_page)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to search users: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Json(AdminUsersResponse {
+        users,
+        page,
+        per_page,
+        total,
+    }))
+}
+
+// Disable an account, signing it out everywhere
+async fn admin_disable_user_handler(
+    State(app_state): State<AppState>,
+    AdminUser(admin): AdminUser,
+    Path(user_id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let database = &app_state.database;
+    let user = admin_target_user(database, &admin, &user_id).await?;
+
+    database
+        .set_user_disabled(&user_id, true)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to disable user: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    database.revoke_user_sessions(&user_id).await.map_err(|e| {
+        tracing::error!("Failed to revoke sessions: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    record_audit(
+        database,
+        database::AuditEvent::new(&admin.user_id, "user", &user_id, "disable").before(&user),
+    )
+    .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn admin_enable_user_handler(
+    State(app_state): State<AppState>,
+    AdminUser(admin): AdminUser,
+    Path(user_id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let database = &app_state.database;
+    let user = admin_target_user(database, &admin, &user_id).await?;
+
+    database
+        .set_user_disabled(&user_id, false)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to enable user: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    record_audit(
+        database,
+        database::AuditEvent::new(&admin.user_id, "user", &user_id, "enable").before(&user),
+    )
+    .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Replace the password with a random one, sign the user out and email them a reset link
+async fn admin_reset_password_handler(
+    State(app_state): State<AppState>,
+    AdminUser(admin): AdminUser,
+    Path(user_id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let database = &app_state.database;
+    let user = admin_target_user(database, &admin, &user_id).await?;
+
+    database
+        .update_password(&user_id, &tokens::generate())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to update password: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    database.revoke_user_sessions(&user_id).await.map_err(|e| {
+        tracing::error!("Failed to revoke sessions: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    record_audit(
+        database,
+        database::AuditEvent::new(&admin.user_id, "user", &user_id, "force_password_reset"),
+    )
+    .await;
+
+    // The old password is already gone, so "forgot password" still works if this email doesn't arrive
+    send_auth_token_email(
+        &app_state,
+        &user,
+        database::TOKEN_PURPOSE_RESET_PASSWORD,
+        None,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to send password reset email: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+// Delete an account and all of its data
+async fn admin_delete_user_handler(
+    State(app_state): State<AppState>,
+    AdminUser(admin): AdminUser,
+    Path(user_id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let database = &app_state.database;
+    admin_target_user(database, &admin, &user_id).await?;
+
+    database.delete_account(&user_id).await.map_err(|e| {
+        tracing::error!("Failed to delete account: {:?}", e);
+        if e.to_string() == "404" {
+            StatusCode::NOT_FOUND
+        } else {
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    })?;
+
+    record_audit(
+        database,
+        // No snapshot, the user's details shouldn't outlive their account
+        database::AuditEvent::new(&admin.user_id, "user", &user_id, "delete_account"),
+    )
+    .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn admin_stats_handler(
+    State(app_state): State<AppState>,
+    _admin: AdminUser,
+) -> Result<Json<database::InstanceStats>, StatusCode> {
+    let stats = app_state.database.get_instance_stats().await.map_err(|e| {
+        tracing::error!("Failed to fetch instance stats: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(stats))
+}
+
+async fn admin_registration_handler(
+    State(app_state): State<AppState>,
+    _admin: AdminUser,
+) -> Json<RegistrationSettings> {
+    Json(RegistrationSettings {
+        mode: current_registration_mode(&app_state).await.as_str(),
+    })
+}
+
+// Switch between open, invite-only and closed registration without a restart
+async fn update_registration_handler(
+    State(app_state): State<AppState>,
+    AdminUser(admin): AdminUser,
+    Json(payload): Json<UpdateRegistrationRequest>,
+) -> Result<Json<RegistrationSettings>, StatusCode> {
+    let database = &app_state.database;
+    let mode = RegistrationMode::parse(&payload.mode).ok_or(StatusCode::BAD_REQUEST)?;
+    let before = RegistrationSettings {
+        mode: current_registration_mode(&app_state).await.as_str(),
+    };
+
+    database
+        .set_instance_setting(invites::REGISTRATION_MODE_SETTING, mode.as_str())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to update registration mode: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let after = RegistrationSettings {
+        mode: mode.as_str(),
+    };
+    record_audit(
+        database,
+        database::AuditEvent::new(
+            &admin.user_id,
+            "instance",
+            invites::REGISTRATION_MODE_SETTING,
+            "update",
+        )
+        .before(&before)
+        .after(&after),
+    )
+    .await;
+
+    Ok(Json(after))
+}
+
+/// Organization and team routes, nested under `/organizations` and `/teams`
+fn workspace_routes(kind: Kind) -> Router<AppState> {
+    Router::new()
+        .route("/", get(workspaces_handler).post(create_workspace_handler))
+        .route(
+            "/{id}",
+            get(workspace_handler)
+                .put(rename_workspace_handler)
+                .delete(delete_workspace_handler),
+        )
+        .route(
+            "/{id}/members",
+            get(workspace_members_handler).post(add_workspace_member_handler),
+        )
+        .route(
+            "/{id}/members/{user_id}",
+            put(update_workspace_member_handler).delete(remove_workspace_member_handler),
+        )
+        .route("/{id}/leave", post(leave_workspace_handler))
+        .route("/{id}/transfer", post(transfer_workspace_handler))
+        .layer(Extension(kind))
+}
+
+/// Organization a workspace's audit events belong to
+fn workspace_organization(kind: Kind, workspace: &database::Workspace) -> Option<&str> {
+    match kind {
+        Kind::Organization => Some(workspace.id.as_str()),
+        Kind::Team => workspace.organization_id.as_deref(),
+    }
+}
+
+/// The user's role in a workspace, where admins of a team's organization are admins of the team too
+async fn workspace_role(
+    database: &Database,
+    user_id: &str,
+    kind: Kind,
+    workspace: &database::Workspace,
+) -> Result<Option<Role>, StatusCode> {
+    let membership = database
+        .get_membership(user_id, &workspace.id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to fetch membership: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let role = membership
+        .filter(|m| m.entity_type == kind.entity_type())
+        .and_then(|m| Role::parse(&m.role));
+
+    if let (Kind::Team, Some(organization_id)) = (kind, &workspace.organization_id) {
+        let organization_admin = database
+            .get_membership(user_id, organization_id)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to fetch membership: {:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?
+            .is_some_and(|m| m.entity_type == "organization" && m.role == "admin");
+        if organization_admin {
+            return Ok(Some(Role::Admin));
+        }
+    }
+
+    Ok(role)
+}
+
+/// Load a workspace the user has at least `min_role` in, with their role filled in
+/// Anyone who isn't a member gets a 404, so workspaces can't be discovered by id
+async fn require_workspace_role(
+    database: &Database,
+    user_id: &str,
+    kind: Kind,
+    id: &str,
+    min_role: Role,
+) -> Result<database::Workspace, StatusCode> {
+    let mut workspace = database
+        .get_workspace(kind.entity_type(), id)
+        .await
+        .map_err(|e| {
+            if e.to_string() == "404" {
+                StatusCode::NOT_FOUND
+            } else {
+                tracing::error!("Failed to fetch {}: {:?}", kind.entity_type(), e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+
+    let role = workspace_role(database, user_id, kind, &workspace)
+        .await?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if role < min_role {
+        tracing::warn!(
+            "User {} is a {} of {} {}, needs {}",
+            user_id,
+            role.as_str(),
+            kind.entity_type(),
+            id,
+            min_role.as_str()
+        );
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    workspace.role = Some(role.as_str().to_string());
+    Ok(workspace)
+}
+
+/// Only the owner can delete a workspace or hand it to someone else
+fn require_workspace_owner(
+    user_id: &str,
+    workspace: &database::Workspace,
+) -> Result<(), StatusCode> {
+    if workspace.owner_id.as_deref() != Some(user_id) {
+        tracing::warn!("User {} does not own {}", user_id, workspace.id);
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(())
+}
+
+async fn workspaces_handler(
+    State(app_state): State<AppState>,
+    Extension(kind): Extension<Kind>,
+    user_context: AuthUser,
+) -> Result<Json<Vec<database::Workspace>>, StatusCode> {
+    let workspaces = app_state
+        .database
+        .get_user_workspaces(&user_context.user_id, kind.entity_type())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to fetch {}s: {:?}", kind.entity_type(), e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(Json(workspaces))
+}
+
+async fn create_workspace_handler(
+    State(app_state): State<AppState>,
+    Extension(kind): Extension<Kind>,
+    user_context: AuthUser,
+    Json(payload): Json<CreateWorkspaceRequest>,
+) -> Result<(StatusCode, Json<database::Workspace>), StatusCode> {
+    let user_id = user_context.user_id.clone();
+    let database = &app_state.database;
+    let name = workspaces::parse_name(&payload.name).ok_or(StatusCode::BAD_REQUEST)?;
+
+    let id = match kind {
+        Kind::Organization => {
+            if payload.organization_id.is_some() {
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            database
+                .create_organization(name, &user_id, &workspaces::free_plan_id())
+                .await
+        }
+        Kind::Team => {
+            // Viewers of an organization can't add teams to it
+            if let Some(organization_id) = &payload.organization_id {
+                require_workspace_role(
+                    database,
+                    &user_id,
+                    Kind::Organization,
+                    organization_id,
+                    Role::Member,
+                )
+                .await?;
+            }
+            database
+                .create_team(
+                    name,
+                    &user_id,
+                    &workspaces::free_plan_id(),
+                    payload.organization_id.as_deref(),
+                )
+                .await
+        }
+    }
+    .map_err(|e| {
+        tracing::error!("Failed to create {}: {:?}", kind.entity_type(), e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let workspace = require_workspace_role(database, &user_id, kind, &id, Role::Admin).await?;
+
+    record_audit(
+        database,
+        database::AuditEvent::new(&user_id, kind.entity_type(), &id, "create")
+            .after(&workspace)
+            .in_organization(workspace_organization(kind, &workspace)),
+    )
+    .await;
+
+    Ok((StatusCode::CREATED, Json(workspace)))
+}
+
+async fn workspace_handler(
+    State(app_state): State<AppState>,
+    Extension(kind): Extension<Kind>,
+    user_context: AuthUser,
+    Path(id): Path<String>,
+) -> Result<Json<database::Workspace>, StatusCode> {
+    let workspace = require_workspace_role(
+        &app_state.database,
+        &user_context.user_id,
+        kind,
+        &id,
+        Role::Viewer,
+    )
+    .await?;
+    Ok(Json(workspace))
+}
+
+async fn rename_workspace_handler(
+    State(app_state): State<AppState>,
+    Extension(kind): Extension<Kind>,
+    user_context: AuthUser,
+    Path(id): Path<String>,
+    Json(payload): Json<RenameWorkspaceRequest>,
+) -> Result<Json<database::Workspace>, StatusCode> {
+    let user_id = user_context.user_id.clone();
+    let database = &app_state.database;
+    let name = workspaces::parse_name(&payload.name).ok_or(StatusCode::BAD_REQUEST)?;
+    let before = require_workspace_role(database, &user_id, kind, &id, Role::Admin).await?;
+
+    let mut workspace = database
+        .rename_workspace(kind.entity_type(), &id, name)
+        .await
+        .map_err(|e| {
+            if e.to_string() == "404" {
+                StatusCode::NOT_FOUND
+            } else {
+                tracing::error!("Failed to rename {}: {:?}", kind.entity_type(), e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+    workspace.role = before.role.clone();
+
+    record_audit(
+        database,
+        database::AuditEvent::new(&user_id, kind.entity_type(), &id, "rename")
+            .before(&before)
+            .after(&workspace)
+            .in_organization(workspace_organization(kind, &workspace)),
+    )
+    .await;
+
+    Ok(Json(workspace))
+}
+
+async fn delete_workspace_handler(
+    State(app_state): State<AppState>,
+    Extension(kind): Extension<Kind>,
+    user_context: AuthUser,
+    Path(id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let user_id = user_context.user_id.clone();
+    let database = &app_state.database;
+    let workspace = require_workspace_role(database, &user_id, kind, &id, Role::Viewer).await?;
+    require_workspace_owner(&user_id, &workspace)?;
+
+    database
+        .delete_workspace(kind.entity_type(), &id)
+        .await
+        .map_err(|e| {
+            if e.to_string() == "404" {
+                StatusCode::NOT_FOUND
+            } else {
+                tracing::error!("Failed to delete {}: {:?}", kind.entity_type(), e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+
+    record_audit(
+        database,
+        database::AuditEvent::new(&user_id, kind.entity_type(), &id, "delete")
+            .before(&workspace)
+            .in_organization(workspace_organization(kind, &workspace)),
+    )
+    .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn workspace_members_handler(
+    State(app_state): State<AppState>,
+    Extension(kind): Extension<Kind>,
+    user_context: AuthUser,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<database::Member>>, StatusCode> {
+    let database = &app_state.database;
+    require_workspace_role(database, &user_context.user_id, kind, &id, Role::Viewer).await?;
+
+    let members = database
+        .get_members(kind.entity_type(), &id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to fetch members: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(Json(members))
+}
+
+// Add someone who already has an account, invites are for people who don't
+async fn add_workspace_member_handler(
+    State(app_state): State<AppState>,
+    Extension(kind): Extension<Kind>,
+    user_context: AuthUser,
+    Path(id): Path<String>,
+    Json(payload): Json<AddMemberRequest>,
+) -> Result<(StatusCode, Json<database::Member>), StatusCode> {
+    let user_id = user_context.user_id.clone();
+    let database = &app_state.database;
+    let role = match payload.role.as_deref() {
+        Some(role) => Role::parse(role).ok_or(StatusCode::BAD_REQUEST)?,
+        None => Role::Member,
+    };
+    let workspace = require_workspace_role(database, &user_id, kind, &id, Role::Admin).await?;
+
+    let user = database
+        .get_user_by_email(payload.email.trim())
+        .await
+        .map_err(|e| {
+            if e.to_string() == "404" {
+                StatusCode::NOT_FOUND
+            } else {
+                tracing::error!("Failed to fetch user: {:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
         })?;
 
-    // Generate JWT token
-    let token = user_jwt::generate_jwt(&user.id, &user.email).map_err(|e| {
-        tracing::error!("Failed to generate JWT: {:?}", e);
+    let existing = database.get_membership(&user.id, &id).await.map_err(|e| {
+        tracing::error!("Failed to fetch membership: {:?}", e);
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
+    if existing.is_some() {
+        return Err(StatusCode::CONFLICT);
+    }
 
-    // Set auth_token in user object
-    user.auth_token = Some(token.clone());
-
-    tracing::info!("Successfully logged in user: {}", user.email);
-
-    Ok(Json(AuthResponse { token, user }))
-}
-
-// Staging login handler
-async fn staging_login_handler(
-    Json(payload): Json<StagingLoginRequest>,
-) -> Result<StatusCode, StatusCode> {
-    println!("Processing staging login request");
-
-    // Get the staging password from environment variables
-    let staging_password = match env::var("STAGING_PASSWORD") {
-        Ok(pwd) => pwd,
-        Err(_) => {
-            println!("STAGING_PASSWORD environment variable not set");
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    // A team in an organization only takes that organization's members
+    if let Some(organization_id) = &workspace.organization_id {
+        let in_organization = database
+            .get_membership(&user.id, organization_id)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to fetch membership: {:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        if in_organization.is_none() {
+            return Err(StatusCode::UNPROCESSABLE_ENTITY);
         }
+    }
+
+    let membership = database::UserMembership {
+        user_id: user.id.clone(),
+        entity_id: id.clone(),
+        entity_type: kind.entity_type().to_string(),
+        role: role.as_str().to_string(),
+        created_at: Utc::now().to_rfc3339(),
+    };
+    let event = database::AuditEvent::new(&user_id, "membership", &user.id, "add")
+        .after(&membership)
+        .in_organization(workspace_organization(kind, &workspace));
+    let member = database::Member {
+        user_id: user.id.clone(),
+        email: user.email.clone(),
+        role: membership.role.clone(),
+        created_at: membership.created_at.clone(),
     };
 
-    // Simple password validation
-    if payload.password == staging_password {
-        println!("Staging login successful");
-        return Ok(StatusCode::OK);
-    } else {
-        println!("Invalid staging password provided");
-        return Err(StatusCode::FORBIDDEN);
-    }
+    database.add_member(membership).await.map_err(|e| {
+        tracing::error!("Failed to add member: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    record_audit(database, event).await;
+
+    Ok((StatusCode::CREATED, Json(member)))
 }
 
-async fn create_user_handler(
+async fn update_workspace_member_handler(
     State(app_state): State<AppState>,
-    Extension(user_context): Extension<UserContext>,
-    Json(payload): Json<CreateUserRequest>,
-) -> Result<Json<database::User>, StatusCode> {
-    let user_email = user_context.email.clone();
+    Extension(kind): Extension<Kind>,
+    user_context: AuthUser,
+    Path((id, member_id)): Path<(String, String)>,
+    Json(payload): Json<UpdateMemberRequest>,
+) -> Result<Json<database::UserMembership>, StatusCode> {
     let user_id = user_context.user_id.clone();
     let database = &app_state.database;
+    let role = Role::parse(&payload.role).ok_or(StatusCode::BAD_REQUEST)?;
+    let workspace = require_workspace_role(database, &user_id, kind, &id, Role::Admin).await?;
 
-    println!("Creating new user: {}", payload.email);
+    // The owner stays an admin, so there's always someone who can manage the workspace
+    if workspace.owner_id.as_deref() == Some(member_id.as_str()) {
+        return Err(StatusCode::CONFLICT);
+    }
 
-    sentry::configure_scope(|scope| {
-        scope.set_user(Some(sentry::User {
-            email: Some(user_email.clone()),
-            id: Some(user_id.clone()),
-            ..Default::default()
-        }));
-        scope.set_tag("http.method", "POST");
-    });
+    let before = database
+        .get_membership(&member_id, &id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to fetch membership: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
 
-    tracing::info!("Creating new user: {}", payload.email);
+    let membership = database
+        .update_member_role(&member_id, &id, role.as_str())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to update member role: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
 
-    let user = database::User {
-        id: payload.user_id,
-        email: payload.email,
-        created_at: Utc::now().to_rfc3339(),
-        password_hash: String::new(), // Legacy endpoint - password not used
-        auth_token: None,
-    };
+    record_audit(
+        database,
+        database::AuditEvent::new(&user_id, "membership", &member_id, "update")
+            .before(&before)
+            .after(&membership)
+            .in_organization(workspace_organization(kind, &workspace)),
+    )
+    .await;
 
-    match database.create_user(user.clone()).await {
-        Ok(_) => Ok(Json(user)),
-        Err(e) => {
-            tracing::error!("Failed to create user: {}", e);
-            match e.to_string().as_str() {
-                "409" => Err(StatusCode::BAD_REQUEST),
-                _ => Err(StatusCode::INTERNAL_SERVER_ERROR),
-            }
+    Ok(Json(membership))
+}
+
+/// Take a user out of a workspace, and out of its teams when it's an organization
+async fn remove_from_workspace(
+    database: &Database,
+    kind: Kind,
+    workspace: &database::Workspace,
+    member_id: &str,
+) -> Result<database::UserMembership, StatusCode> {
+    let membership = database
+        .get_membership(member_id, &workspace.id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to fetch membership: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    database
+        .remove_member(member_id, &workspace.id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to remove member: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    if kind == Kind::Organization {
+        if let Err(e) = database
+            .remove_member_from_teams(member_id, &workspace.id)
+            .await
+        {
+            tracing::error!("Failed to remove member from teams: {:?}", e);
         }
     }
+
+    Ok(membership)
 }
 
-async fn links_handler(
+async fn remove_workspace_member_handler(
     State(app_state): State<AppState>,
-    Extension(user_context): Extension<UserContext>,
-) -> Result<Json<Vec<database::Link>>, StatusCode> {
-    let user_email = user_context.email.clone();
+    Extension(kind): Extension<Kind>,
+    user_context: AuthUser,
+    Path((id, member_id)): Path<(String, String)>,
+) -> Result<StatusCode, StatusCode> {
     let user_id = user_context.user_id.clone();
-    println!("Fetching links for user: {}", user_id);
-
-    sentry::configure_scope(|scope| {
-        scope.set_user(Some(sentry::User {
-            email: Some(user_email.clone()),
-            id: Some(user_id.clone()),
-            ..Default::default()
-        }));
-        scope.set_tag("http.method", "GET");
-    });
+    let database = &app_state.database;
+    let workspace = require_workspace_role(database, &user_id, kind, &id, Role::Admin).await?;
 
-    tracing::info!("Fetching links for user: {}", user_id);
+    if workspace.owner_id.as_deref() == Some(member_id.as_str()) {
+        return Err(StatusCode::CONFLICT);
+    }
 
-    // Use app_state's database instance
-    let database = &app_state.database;
+    let membership = remove_from_workspace(database, kind, &workspace, &member_id).await?;
 
-    let links = database.get_links(&user_id, "user").await.map_err(|e| {
-        tracing::error!("Failed to fetch links for user {}: {:?}", user_id, e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    record_audit(
+        database,
+        database::AuditEvent::new(&user_id, "membership", &member_id, "remove")
+            .before(&membership)
+            .in_organization(workspace_organization(kind, &workspace)),
+    )
+    .await;
 
-    tracing::info!(
-        "Successfully fetched {} links for user {}",
-        links.len(),
-        user_id
-    );
-    Ok(Json(links))
+    Ok(StatusCode::NO_CONTENT)
 }
 
-async fn create_link(
+// Owners have to transfer the workspace before they can leave it
+async fn leave_workspace_handler(
     State(app_state): State<AppState>,
-    Extension(user_context): Extension<UserContext>,
-    headers: HeaderMap,
-    Json(payload): Json<CreateLinkRequest>,
-) -> Result<(StatusCode, Json<database::Link>), StatusCode> {
-    let user_email = user_context.email.clone();
+    Extension(kind): Extension<Kind>,
+    user_context: AuthUser,
+    Path(id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
     let user_id = user_context.user_id.clone();
-    let client = &app_state.client;
     let database = &app_state.database;
+    let workspace = require_workspace_role(database, &user_id, kind, &id, Role::Viewer).await?;
 
-    println!(
-        "Creating new link for owner {}: {}",
-        payload.owner_id, payload.url
-    );
+    if workspace.owner_id.as_deref() == Some(user_id.as_str()) {
+        return Err(StatusCode::CONFLICT);
+    }
 
-    sentry::configure_scope(|scope| {
-        scope.set_user(Some(sentry::User {
-            email: Some(user_email.clone()),
-            id: Some(user_id.clone()),
-            ..Default::default()
-        }));
-        scope.set_tag("http.method", "POST");
-    });
+    let membership = remove_from_workspace(database, kind, &workspace, &user_id).await?;
 
-    tracing::info!(
-        "Creating new link for owner {}: {}",
-        payload.owner_id,
-        payload.url
-    );
+    record_audit(
+        database,
+        database::AuditEvent::new(&user_id, "membership", &user_id, "leave")
+            .before(&membership)
+            .in_organization(workspace_organization(kind, &workspace)),
+    )
+    .await;
 
-    let url = if !payload.url.starts_with("https://") {
-        format!("https://{}", payload.url)
-    } else {
-        payload.url
-    };
+    Ok(StatusCode::NO_CONTENT)
+}
 
-    // Check for the custom authorization header
-    let auth_token = headers
-        .get("X-User-Authorization")
-        .ok_or_else(|| {
-            println!("Missing X-User-Authorization header");
-            StatusCode::UNAUTHORIZED
-        })?
-        .to_str()
+async fn transfer_workspace_handler(
+    State(app_state): State<AppState>,
+    Extension(kind): Extension<Kind>,
+    user_context: AuthUser,
+    Path(id): Path<String>,
+    Json(payload): Json<TransferWorkspaceRequest>,
+) -> Result<Json<database::Workspace>, StatusCode> {
+    let user_id = user_context.user_id.clone();
+    let database = &app_state.database;
+    let before = require_workspace_role(database, &user_id, kind, &id, Role::Viewer).await?;
+    require_workspace_owner(&user_id, &before)?;
+
+    if payload.user_id == user_id {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    // The new owner has to be a member already
+    let mut workspace = database
+        .transfer_ownership(kind.entity_type(), &id, &payload.user_id)
+        .await
         .map_err(|e| {
-            println!("Invalid X-User-Authorization header: {:?}", e);
-            StatusCode::BAD_REQUEST
+            if e.to_string() == "404" {
+                StatusCode::NOT_FOUND
+            } else {
+                tracing::error!("Failed to transfer {}: {:?}", kind.entity_type(), e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
         })?;
+    workspace.role = before.role.clone();
+
+    record_audit(
+        database,
+        database::AuditEvent::new(&user_id, kind.entity_type(), &id, "transfer")
+            .before(&before)
+            .after(&workspace)
+            .in_organization(workspace_organization(kind, &workspace)),
+    )
+    .await;
 
-    let metadata_on = headers
-        .get("X-Fetch-Metadata")
-        .and_then(|m| m.to_str().ok())
-        .map(|s| s.to_lowercase() == "true")
-        .unwrap_or(false);
+    Ok(Json(workspace))
+}
 
-    // Validate the JWT token
-    let user_claims = match user_jwt::validate_jwt(auth_token) {
-        Ok(claims) => claims,
-        Err(e) => {
-            println!("Invalid JWT token: {:?}", e);
-            return Err(StatusCode::UNAUTHORIZED);
-        }
-    };
+async fn audit_handler(
+    State(app_state): State<AppState>,
+    user_context: AuthUser,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<AuditResponse>, StatusCode> {
+    let user_id = user_context.user_id.clone();
 
-    // Verify the user ID in the token matches the request user ID
-    if user_claims.user_id != user_id {
-        println!("Token user ID does not match request user ID");
-        return Err(StatusCode::UNAUTHORIZED);
-    }
+    let database = &app_state.database;
 
-    // init metadata, retrieve from link's URL, else use defaults
-    let metadata = if metadata_on {
-        match get_metadata(State(client.clone()), &url).await {
-            Ok(metadata) => metadata,
-            Err(StatusCode::BAD_GATEWAY) => {
-                // If we get BAD_GATEWAY from get_metadata, return it directly to the client
-                return Err(StatusCode::BAD_GATEWAY);
-            }
-            Err(_) => {
-                // For any other errors, use default metadata
-                Metadata {
-                    title: Some(url.clone()),
-                    description: None,
-                    favicon: None,
-                    mime_type: None,
-                }
-            }
-        }
-    } else {
-        Metadata {
-            title: Some(url.clone()),
-            description: None,
-            favicon: None,
-            mime_type: None,
-        }
-    };
+    // Organization-wide history is only visible to that organization's admins
+    if let Some(organization_id) = &query.organization_id {
+        require_organization_admin(database, &user_id, organization_id).await?;
+    }
 
-    // use the user's title, if empty use metadata, metadata will be the URL if metadata is not fetched
-    let title = if payload.title.as_deref() == Some("") {
-        metadata.title.unwrap_or_else(|| "".to_string())
-    } else {
-        payload
-            .title
-            .unwrap_or_else(|| metadata.title.unwrap().clone())
-    };
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query.per_page.unwrap_or(50).clamp(1, 200);
 
-    // grab the favicon, or just pass an empty string
-    let favicon = if metadata_on {
-        get_favicon(
-            State(client),
-            &url,
-            metadata.favicon.clone(),
-            metadata.mime_type.clone(),
+    let (events, total) = database
+        .get_audit_events(
+            &user_id,
+            query.organization_id.as_deref(),
+            per_page,
+            (page - 1) * per_page,
         )
         .await
         .map_err(|e| {
-            tracing::error!("Error getting favicon: {:?}", e);
-        })
-        .unwrap_or_else(|_| "".to_string())
-    } else {
-        "".to_string()
-    };
-
-    // use the user's description, or the metadata description
-    let description = if payload.description.as_deref() == Some("") {
-        metadata.description.unwrap_or_else(|| "".to_string())
-    } else {
-        payload
-            .description
-            .unwrap_or_else(|| metadata.description.unwrap().clone())
-    };
-
-    let link = database::Link {
-        id: uuid::Uuid::new_v4().to_string(),
-        url: url,
-        description: Some(description),
-        created_at: Utc::now().to_rfc3339(),
-        title: title,
-        icon: Some(favicon),
-        order_index: payload.next_order_index,
-        owner_type: payload.owner_type,
-        owner_id: payload.owner_id,
-        column_type: payload.column_type,
-    };
-
-    if let Err(e) = database.create_link(link.clone()).await {
-        tracing::error!("Failed to create link in database: {:?}", e);
-        println!("Failed to create link in database: {:?}", e);
-        return Err(StatusCode::INTERNAL_SERVER_ERROR);
-    }
+            tracing::error!("Failed to fetch audit events: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
 
-    tracing::info!("Successfully created link with ID: {}", link.id);
-    Ok((StatusCode::CREATED, Json(link)))
+    Ok(Json(AuditResponse {
+        events,
+        page,
+        per_page,
+        total,
+    }))
 }
 
-async fn update_link(
+async fn link_revisions_handler(
     State(app_state): State<AppState>,
-    Extension(user_context): Extension<UserContext>,
-    Json(payload): Json<UpdateLinkRequest>,
-) -> Result<StatusCode, StatusCode> {
-    let user_email = user_context.email.clone();
+    Path(link_id): Path<String>,
+    user_context: AuthUser,
+) -> Result<Json<Vec<database::LinkRevision>>, StatusCode> {
     let user_id = user_context.user_id.clone();
-    println!("Updating link: {}", payload.id);
-
-    sentry::configure_scope(|scope| {
-        scope.set_user(Some(sentry::User {
-            email: Some(user_email.clone()),
-            id: Some(user_id.clone()),
-            ..Default::default()
-        }));
-        scope.set_tag("http.method", "PUT");
-    });
-
-    tracing::info!("Updating link: {}", payload.id);
 
-    // Use app_state's database instance
     let database = &app_state.database;
 
-    let link = database::Link {
-        id: payload.id.clone(),
-        url: payload.url.clone().unwrap_or_else(|| "".to_string()),
-        description: payload.description.clone(),
-        title: payload.title.clone().unwrap(),
-        icon: payload.icon.clone(),
-        column_type: payload.column_type.clone().unwrap(),
-        created_at: Utc::now().to_rfc3339(),
-        order_index: 0,
-        owner_type: "".to_string(),
-        owner_id: "".to_string(),
-    };
-
-    if let Err(e) = database.update_link(link).await {
-        tracing::error!("Failed to update link: {:?}", e);
-        println!("Failed to update link: {:?}", e);
-        return Err(StatusCode::INTERNAL_SERVER_ERROR);
-    }
+    get_permitted_link(database, &user_id, &link_id, Role::Viewer).await?;
 
-    tracing::info!("Successfully updated link {}", payload.id);
-    Ok(StatusCode::OK)
+    let revisions = database.get_link_revisions(&link_id).await.map_err(|e| {
+        tracing::error!("Failed to fetch revisions for link {}: {:?}", link_id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(Json(revisions))
 }
 
-async fn delete_link(
+async fn revert_link(
     State(app_state): State<AppState>,
-    Path(link_id): Path<String>,
-    Extension(user_context): Extension<UserContext>,
-) -> Result<StatusCode, StatusCode> {
-    let user_email = user_context.email.clone();
+    Path((link_id, revision_id)): Path<(String, String)>,
+    user_context: AuthUser,
+) -> Result<Json<database::Link>, StatusCode> {
     let user_id = user_context.user_id.clone();
 
-    sentry::configure_scope(|scope| {
-        scope.set_user(Some(sentry::User {
-            email: Some(user_email.clone()),
-            id: Some(user_id.clone()),
-            ..Default::default()
-        }));
-        scope.set_tag("http.method", "DELETE");
-    });
+    tracing::info!("Reverting link {} to revision {}", link_id, revision_id);
 
-    // Use app_state's database instance
     let database = &app_state.database;
 
-    // First, verify that the link exists and belongs to the user
-    let link = database.get_link(&link_id, &user_id).await.map_err(|e| {
-        tracing::warn!("Link not found or unauthorized: {:?}", e);
-        if e.to_string().contains("404") {
-            StatusCode::NOT_FOUND
-        } else {
-            StatusCode::FORBIDDEN
-        }
-    })?;
+    let before = get_permitted_link(database, &user_id, &link_id, Role::Member).await?;
 
-    // Verify ownership
-    if link.owner_id != user_id {
-        tracing::warn!(
-            "User {} attempted to delete link {} owned by {}",
-            user_id,
-            link_id,
-            link.owner_id
-        );
-        return Err(StatusCode::FORBIDDEN);
+    if before.deleted_at.is_some() {
+        return Err(StatusCode::NOT_FOUND);
     }
 
-    // Delete the link
-    if let Err(e) = database.delete_link(&link_id).await {
-        tracing::error!("Error deleting link: {:?}", e);
+    let revision = database
+        .get_link_revision(&link_id, &revision_id)
+        .await
+        .map_err(|e| match e.to_string().as_str() {
+            "404" => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        })?;
+
+    // update_link snapshots the current state first, so a revert can itself be reverted
+    let link = database::Link {
+        title: revision.title,
+        canonical_url: link_url::normalize(&revision.url, link_url::strip_tracking_enabled()).ok(),
+        url: revision.url,
+        icon: revision.icon,
+        description: revision.description,
+        column_type: revision.column_type,
+        ..before.clone()
+    };
+
+    if let Err(e) = database.update_link(link.clone()).await {
+        tracing::error!("Failed to revert link: {:?}", e);
         return Err(StatusCode::INTERNAL_SERVER_ERROR);
     }
 
-    tracing::info!("Successfully deleted link: {}", link_id);
-    Ok(StatusCode::NO_CONTENT)
+    record_audit(
+        database,
+        database::AuditEvent::new(&user_id, "link", &link_id, "revert")
+            .in_organization(link_organization(database, &before).await.as_deref())
+            .before(&before)
+            .after(&link),
+    )
+    .await;
+
+    tracing::info!(
+        "Successfully reverted link {} to revision {}",
+        link_id,
+        revision_id
+    );
+    Ok(Json(link))
 }
 
 async fn get_user_handler(
     State(app_state): State<AppState>,
-    Extension(user_context): Extension<UserContext>,
+    user_context: AuthUser,
 ) -> Result<Json<database::User>, StatusCode> {
-    let user_email = user_context.email.clone();
     let user_id = user_context.user_id.clone();
 
-    sentry::configure_scope(|scope| {
-        scope.set_user(Some(sentry::User {
-            email: Some(user_email.clone()),
-            id: Some(user_id.clone()),
-            ..Default::default()
-        }));
-        scope.set_tag("http.method", "GET");
-    });
-
     // Use app_state's database instance
     let database = &app_state.database;
 
@@ -869,12 +4930,18 @@ async fn get_favicon(
     mime_type: Option<String>,
 ) -> Result<String, StatusCode> {
     let parsed_url = Url::parse(url).expect("Invalid URL");
-    let domain = parsed_url.host_str().unwrap_or("").to_string();
-
-    let domain = if !domain.starts_with("https://") {
-        format!("https://{}", domain)
-    } else {
-        domain
+    let domain = match parsed_url.port() {
+        Some(port) => format!(
+            "{}://{}:{}",
+            parsed_url.scheme(),
+            parsed_url.host_str().unwrap_or(""),
+            port
+        ),
+        None => format!(
+            "{}://{}",
+            parsed_url.scheme(),
+            parsed_url.host_str().unwrap_or("")
+        ),
     };
 
     let favicon_urls = vec![
@@ -937,61 +5004,16 @@ async fn get_favicon(
 }
 
 async fn suggest_handler(
+    State(app_state): State<AppState>,
     Path(query): Path<String>,
-    Extension(user_context): Extension<UserContext>,
-    headers: HeaderMap,
+    _user: AuthUser,
 ) -> Result<Json<SuggestionResponse>, StatusCode> {
-    let user_email = user_context.email.clone();
-    let user_id = user_context.user_id.clone();
-    println!("Suggesting: {}", query);
-
-    sentry::configure_scope(|scope| {
-        scope.set_user(Some(sentry::User {
-            email: Some(user_email.clone()),
-            id: Some(user_id.clone()),
-            ..Default::default()
-        }));
-        scope.set_tag("http.method", "GET");
-    });
-
-    // Check for the custom authorization header
-    let auth_token = headers
-        .get("X-User-Authorization")
-        .ok_or_else(|| {
-            println!("Missing X-User-Authorization header");
-            StatusCode::UNAUTHORIZED
-        })?
-        .to_str()
-        .map_err(|e| {
-            println!("Invalid X-User-Authorization header: {:?}", e);
-            StatusCode::BAD_REQUEST
-        })?;
-
-    // Validate the JWT token
-    let user_claims = match user_jwt::validate_jwt(auth_token) {
-        Ok(claims) => claims,
-        Err(e) => {
-            println!("Invalid JWT token: {:?}", e);
-            return Err(StatusCode::UNAUTHORIZED);
-        }
-    };
-
-    // Verify the user ID in the token matches the request user ID
-    if user_claims.user_id != user_id {
-        println!("Token user ID does not match request user ID");
-        return Err(StatusCode::UNAUTHORIZED);
-    }
-
     println!("Suggesting: {}", query);
 
-    let brave = Brave::new(
-        std::env::var("BRAVE_SUGGEST_URL").expect("BRAVE_URL must be set"),
-        std::env::var("BRAVE_API_KEY").expect("BRAVE_API_KEY must be set"),
-    )
-    .map_err(|e| {
-        println!("Error initializing Brave client: {:?}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    let brave = app_state
+        .brave
+        .as_ref()
+        .ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
 
     let response = brave.get_suggestions(&query).await.map_err(|e| {
         // Check for rate limit error specifically
@@ -1010,22 +5032,12 @@ async fn suggest_handler(
 
 async fn feedback_handler(
     State(app_state): State<AppState>,
-    Extension(user_context): Extension<UserContext>,
+    user_context: AuthUser,
     Json(payload): Json<FeedbackRequest>,
 ) -> Result<StatusCode, StatusCode> {
-    let user_email = user_context.email.clone();
     let user_id = user_context.user_id.clone();
     println!("Feedback for user: {}", user_id);
 
-    sentry::configure_scope(|scope| {
-        scope.set_user(Some(sentry::User {
-            email: Some(user_email.clone()),
-            id: Some(user_id.clone()),
-            ..Default::default()
-        }));
-        scope.set_tag("http.method", "POST");
-    });
-
     let user_id = user_context.user_id.clone();
     let user_email = user_context.email.clone();
     println!("Feedback for user: {}", user_id);
@@ -1046,29 +5058,22 @@ async fn feedback_handler(
         return Err(StatusCode::TOO_MANY_REQUESTS);
     }
 
-    let resend_service = ResendClient::new();
-
     let customer_support_email =
         std::env::var("CUSTOMER_SUPPORT_EMAIL").expect("CUSTOMER_SUPPORT_EMAIL must be set");
 
-    let email_body = format!(
-        "<p>Feedback from user: {} | {}<br/><br/>Reasons: {:?}<br/><br/>Feedback: {}</p>",
-        user_id,
-        user_email,
-        payload.reasons,
-        payload.feedback_comment.unwrap_or_else(|| "".to_string())
+    let email = email_templates::feedback(
+        &customer_support_email,
+        &user_id,
+        &user_email,
+        payload.reasons.as_deref(),
+        payload.feedback_comment.as_deref(),
     );
 
-    let subject = format!("Feedback from: {}", user_email);
-
-    resend_service
-        .send_email(&customer_support_email, &subject, &email_body)
-        .await
-        .map_err(|e| {
-            println!("Error sending email: {:?}", e);
-            tracing::error!("Error sending email: {:?}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+    app_state.mailer.send(&email).await.map_err(|e| {
+        println!("Error sending email: {:?}", e);
+        tracing::error!("Error sending email: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
 
     // Create a feedback timestamp record
     database
@@ -1084,22 +5089,12 @@ async fn feedback_handler(
 
 async fn create_settings(
     State(app_state): State<AppState>,
-    Extension(user_context): Extension<UserContext>,
+    user_context: AuthUser,
     Json(payload): Json<UserSettingsRequest>,
 ) -> Result<StatusCode, StatusCode> {
-    let user_email = user_context.email.clone();
     let user_id = user_context.user_id.clone();
     println!("Creating settings for user: {}", user_id);
 
-    sentry::configure_scope(|scope| {
-        scope.set_user(Some(sentry::User {
-            email: Some(user_email.clone()),
-            id: Some(user_id.clone()),
-            ..Default::default()
-        }));
-        scope.set_tag("http.method", "POST");
-    });
-
     println!("Creating settings for user: {}", user_id);
     println!("Payload: {:?}", payload);
 
@@ -1117,33 +5112,37 @@ async fn create_settings(
         return Err(StatusCode::INTERNAL_SERVER_ERROR);
     }
 
+    record_audit(
+        database,
+        database::AuditEvent::new(&user_id, "settings", &user_id, "create").after(&payload),
+    )
+    .await;
+
     Ok(StatusCode::CREATED)
 }
 
 async fn update_settings(
     State(app_state): State<AppState>,
-    Extension(user_context): Extension<UserContext>,
+    user_context: AuthUser,
     Json(payload): Json<UserSettingsRequest>,
 ) -> Result<StatusCode, StatusCode> {
-    let user_email = user_context.email.clone();
     let user_id = user_context.user_id.clone();
     println!("Updating settings for user: {}", user_id);
 
-    sentry::configure_scope(|scope| {
-        scope.set_user(Some(sentry::User {
-            email: Some(user_email.clone()),
-            id: Some(user_id.clone()),
-            ..Default::default()
-        }));
-        scope.set_tag("http.method", "PUT");
-    });
-
     println!("Updating settings for user: {}", user_id);
     println!("Payload: {:?}", payload);
 
     // Use app_state's database instance
     let database = &app_state.database;
 
+    let before = database
+        .get_user_settings(&user_id)
+        .await
+        .ok()
+        .and_then(|settings| {
+            serde_json::from_str::<serde_json::Value>(&settings.settings_blob).ok()
+        });
+
     let mut updates = HashMap::new();
     updates.insert("settings_blob".to_string(), json!(payload));
 
@@ -1152,26 +5151,23 @@ async fn update_settings(
         return Err(StatusCode::INTERNAL_SERVER_ERROR);
     }
 
+    let mut event =
+        database::AuditEvent::new(&user_id, "settings", &user_id, "update").after(&payload);
+    if let Some(before) = before {
+        event = event.before(&before);
+    }
+    record_audit(database, event).await;
+
     Ok(StatusCode::OK)
 }
 
 async fn get_settings(
     State(app_state): State<AppState>,
-    Extension(user_context): Extension<UserContext>,
+    user_context: AuthUser,
 ) -> Result<Json<database::UserSettings>, StatusCode> {
-    let user_email = user_context.email.clone();
     let user_id = user_context.user_id.clone();
     println!("Getting settings for user: {}", user_id);
 
-    sentry::configure_scope(|scope| {
-        scope.set_user(Some(sentry::User {
-            email: Some(user_email.clone()),
-            id: Some(user_id.clone()),
-            ..Default::default()
-        }));
-        scope.set_tag("http.method", "GET");
-    });
-
     println!("Getting settings for user: {}", user_id);
 
     // Use app_state's database instance
@@ -1191,7 +5187,7 @@ async fn get_settings(
 
 async fn get_user_data_handler(
     State(app_state): State<AppState>,
-    Extension(user_context): Extension<UserContext>,
+    user_context: AuthUser,
 ) -> Result<Json<UserDataResponse>, StatusCode> {
     let user_email = user_context.email.clone();
     let user_id = user_context.user_id.clone();
@@ -1200,15 +5196,6 @@ async fn get_user_data_handler(
     println!("Fetching user data for {}", user_email);
     tracing::info!("Fetching user data for {}", user_email);
 
-    sentry::configure_scope(|scope| {
-        scope.set_user(Some(sentry::User {
-            email: Some(user_email.clone()),
-            id: Some(user_id.clone()),
-            ..Default::default()
-        }));
-        scope.set_tag("http.method", "GET");
-    });
-
     let database = &app_state.database;
 
     // Get or create user
@@ -1228,6 +5215,9 @@ async fn get_user_data_handler(
                 created_at: Utc::now().to_rfc3339(),
                 auth_token: None,
                 password_hash: String::new(),
+                email_verified_at: None,
+                is_admin: false,
+                disabled_at: None,
             };
             database.create_user(new_user.clone()).await.map_err(|e| {
                 tracing::error!("Failed to create user: {:?}", e);
@@ -1281,11 +5271,14 @@ async fn get_user_data_handler(
     })?;
 
     // Generate JWT token with user ID
-    let auth_token = user_jwt::generate_jwt(&user_id, &user_email).map_err(|e| {
-        tracing::error!("Failed to generate JWT token: {:?}", e);
-        println!("Failed to generate JWT token: {:?}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    let auth_token = app_state
+        .jwt_keys
+        .generate_jwt(&user_id, &user_email, &user_context.session_id)
+        .map_err(|e| {
+            tracing::error!("Failed to generate JWT token: {:?}", e);
+            println!("Failed to generate JWT token: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
 
     tracing::info!(
         "Successfully assembled user data response for {}",
@@ -1344,3 +5337,597 @@ async fn create_user_default_settings(
         return Err(StatusCode::FOUND);
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use axum::response::IntoResponse;
+    use oidc::mock::{MockIdp, EMAIL, SUBJECT};
+
+    /// App state over an in-memory database, with nothing configured beyond the defaults
+    pub(crate) async fn test_app_state() -> AppState {
+        let key_dir = env::temp_dir().join(format!("omega-tab-test-{}", uuid::Uuid::new_v4()));
+        AppState {
+            client: reqwest::Client::new(),
+            database: Database::in_memory().await.unwrap(),
+            mailer: Arc::new(mailer::LogMailer),
+            webauthn: None,
+            brave: None,
+            oidc: None,
+            proxy_auth: None,
+            trusted_proxies: Arc::default(),
+            jwt_keys: Arc::new(user_jwt::JwtKeys::load(&key_dir).unwrap()),
+            rate_limiter: Arc::new(rate_limit::RateLimiter::from_env()),
+            password_policy: Arc::new(password_policy::PasswordPolicy::from_env().unwrap()),
+            registration_mode: RegistrationMode::Open,
+            local_user: None,
+        }
+    }
+
+    pub(crate) async fn create_test_user(database: &Database, email: &str) -> database::User {
+        database
+            .create_user(database::User {
+                id: uuid::Uuid::new_v4().to_string(),
+                email: email.to_string(),
+                created_at: Utc::now().to_rfc3339(),
+                auth_token: None,
+                password_hash: bcrypt::hash("correct horse battery staple", 4).unwrap(),
+                email_verified_at: None,
+                is_admin: false,
+                disabled_at: None,
+            })
+            .await
+            .unwrap()
+    }
+
+    /// Run a single sign-on through the mock provider and return where the callback redirects
+    async fn sso_callback(
+        app_state: &AppState,
+        idp: &MockIdp,
+        link_user_id: Option<&str>,
+    ) -> String {
+        let url = begin_oidc_login(app_state, link_user_id).await.unwrap();
+        let (code, state) = idp.authorize(&url).await;
+        let redirect = oidc_callback_handler(
+            State(app_state.clone()),
+            ConnectInfo("127.0.0.1:50000".parse().unwrap()),
+            HeaderMap::new(),
+            Query(OidcCallbackQuery {
+                code: Some(code),
+                state: Some(state),
+                error: None,
+            }),
+        )
+        .await
+        .into_response();
+        redirect.headers()["location"].to_str().unwrap().to_string()
+    }
+
+    fn enable_sso(
+        app_state: &mut AppState,
+        idp: &MockIdp,
+        group_mappings: Vec<oidc::GroupMapping>,
+    ) {
+        let mut config = idp.config();
+        config.group_mappings = group_mappings;
+        app_state.oidc = Some(Arc::new(config));
+    }
+
+    fn viewers_of(organization_id: &str) -> Vec<oidc::GroupMapping> {
+        vec![oidc::GroupMapping {
+            group: "eng".to_string(),
+            entity_type: "organization".to_string(),
+            entity_id: organization_id.to_string(),
+            role: Role::Viewer,
+        }]
+    }
+
+    #[tokio::test]
+    async fn sso_provisions_a_verified_user_with_mapped_groups() {
+        let idp = MockIdp::start().await;
+        let mut app_state = test_app_state().await;
+        let database = app_state.database.clone();
+        let owner = create_test_user(&database, "owner@example.com").await;
+        let org_id = database
+            .create_organization("Acme", &owner.id, &workspaces::free_plan_id())
+            .await
+            .unwrap();
+        enable_sso(&mut app_state, &idp, viewers_of(&org_id));
+        idp.set_claims(json!({ "groups": ["eng"] }));
+
+        let location = sso_callback(&app_state, &idp, None).await;
+        assert!(location.contains("/login/sso#token="), "{}", location);
+
+        let user = database
+            .get_user_by_identity(&idp.issuer, SUBJECT)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.email, EMAIL);
+        assert!(user.email_verified_at.is_some());
+        let membership = database
+            .get_membership(&user.id, &org_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(membership.role, "viewer");
+    }
+
+    #[tokio::test]
+    async fn sso_refuses_an_unverified_email() {
+        let idp = MockIdp::start().await;
+        let mut app_state = test_app_state().await;
+        enable_sso(&mut app_state, &idp, Vec::new());
+        idp.set_claims(json!({ "email_verified": false }));
+
+        let location = sso_callback(&app_state, &idp, None).await;
+        assert!(
+            location.ends_with("/login?sso_error=email_not_verified"),
+            "{}",
+            location
+        );
+        assert!(app_state.database.get_user_by_email(EMAIL).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn sso_does_not_take_over_an_existing_account() {
+        let idp = MockIdp::start().await;
+        let mut app_state = test_app_state().await;
+        enable_sso(&mut app_state, &idp, Vec::new());
+        let existing = create_test_user(&app_state.database, EMAIL).await;
+
+        let location = sso_callback(&app_state, &idp, None).await;
+        assert!(
+            location.ends_with("/login?sso_error=account_exists"),
+            "{}",
+            location
+        );
+
+        // Once its owner links the provider while signed in, single sign-on reaches that account
+        let location = sso_callback(&app_state, &idp, Some(&existing.id)).await;
+        assert!(
+            location.ends_with("/settings?sso_linked=true"),
+            "{}",
+            location
+        );
+        let location = sso_callback(&app_state, &idp, None).await;
+        assert!(location.contains("/login/sso#token="), "{}", location);
+
+        let linked = app_state
+            .database
+            .get_user_by_identity(&idp.issuer, SUBJECT)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(linked.id, existing.id);
+        let (events, _) = app_state
+            .database
+            .get_audit_events(&existing.id, None, 50, 0)
+            .await
+            .unwrap();
+        assert!(events.iter().any(|event| event.action == "link_identity"));
+    }
+
+    #[tokio::test]
+    async fn sso_identity_links_to_one_account() {
+        let idp = MockIdp::start().await;
+        let mut app_state = test_app_state().await;
+        enable_sso(&mut app_state, &idp, Vec::new());
+        let first = create_test_user(&app_state.database, "first@example.com").await;
+        let second = create_test_user(&app_state.database, "second@example.com").await;
+
+        sso_callback(&app_state, &idp, Some(&first.id)).await;
+        let location = sso_callback(&app_state, &idp, Some(&second.id)).await;
+        assert!(
+            location.ends_with("/settings?sso_error=identity_in_use"),
+            "{}",
+            location
+        );
+    }
+
+    #[tokio::test]
+    async fn group_mappings_leave_the_owner_role_alone() {
+        let idp = MockIdp::start().await;
+        let mut app_state = test_app_state().await;
+        let database = app_state.database.clone();
+        let owner = create_test_user(&database, EMAIL).await;
+        let org_id = database
+            .create_organization("Acme", &owner.id, &workspaces::free_plan_id())
+            .await
+            .unwrap();
+        enable_sso(&mut app_state, &idp, viewers_of(&org_id));
+        idp.set_claims(json!({ "groups": ["eng"] }));
+
+        sso_callback(&app_state, &idp, Some(&owner.id)).await;
+        let location = sso_callback(&app_state, &idp, None).await;
+        assert!(location.contains("/login/sso#token="), "{}", location);
+
+        let membership = database
+            .get_membership(&owner.id, &org_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(membership.role, "admin");
+    }
+
+    async fn register(app_state: &AppState, email: &str) -> StatusCode {
+        let response = register_handler(
+            State(app_state.clone()),
+            ConnectInfo("127.0.0.1:50000".parse().unwrap()),
+            HeaderMap::new(),
+            Json(RegisterRequest {
+                email: email.to_string(),
+                password: "correct horse battery staple".to_string(),
+                device_label: None,
+                invite_code: None,
+            }),
+        )
+        .await;
+        match response {
+            Ok(_) => StatusCode::OK,
+            Err(e) => e.into_response().status(),
+        }
+    }
+
+    #[tokio::test]
+    async fn closed_registration_only_lets_the_first_user_in() {
+        let mut app_state = test_app_state().await;
+        app_state.registration_mode = RegistrationMode::Closed;
+
+        assert_eq!(
+            register(&app_state, "first@example.com").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            register(&app_state, "second@example.com").await,
+            StatusCode::FORBIDDEN
+        );
+        let first = app_state
+            .database
+            .get_user_by_email("first@example.com")
+            .await
+            .unwrap();
+        assert!(first.is_admin);
+    }
+
+    #[tokio::test]
+    async fn only_one_concurrent_registration_is_first() {
+        let app_state = test_app_state().await;
+        let database = &app_state.database;
+
+        let (first, second) = tokio::join!(
+            database.register_first_user("first@example.com", "correct horse battery staple"),
+            database.register_first_user("second@example.com", "correct horse battery staple"),
+        );
+        let created = [first.unwrap(), second.unwrap()];
+        assert_eq!(created.iter().flatten().count(), 1);
+        assert_eq!(database.count_users().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn sso_does_not_provision_while_registration_is_invite_only() {
+        let idp = MockIdp::start().await;
+        let mut app_state = test_app_state().await;
+        app_state.registration_mode = RegistrationMode::InviteOnly;
+        enable_sso(&mut app_state, &idp, Vec::new());
+        create_test_user(&app_state.database, "admin@example.com").await;
+
+        let location = sso_callback(&app_state, &idp, None).await;
+        assert!(
+            location.ends_with("/login?sso_error=registration_closed"),
+            "{}",
+            location
+        );
+        assert!(app_state.database.get_user_by_email(EMAIL).await.is_err());
+
+        // Local mode's own account isn't a registration
+        let local = create_passwordless_user(&app_state, local_user_email(), false).await;
+        assert!(local.is_ok());
+    }
+
+    fn auth_user(user: &database::User) -> AuthUser {
+        AuthUser {
+            user_id: user.id.clone(),
+            email: user.email.clone(),
+            session_id: String::new(),
+            token_id: String::new(),
+            token_expires_at: 0,
+            api_token_id: None,
+        }
+    }
+
+    async fn add_link(
+        app_state: &AppState,
+        user: &database::User,
+        owner: (&str, &str),
+    ) -> StatusCode {
+        let response = create_link(
+            State(app_state.clone()),
+            auth_user(user),
+            HeaderMap::new(),
+            Json(CreateLinkRequest {
+                url: "https://example.com".to_string(),
+                description: Some(String::new()),
+                title: Some("Example".to_string()),
+                next_order_index: 0,
+                owner_type: owner.0.to_string(),
+                owner_id: owner.1.to_string(),
+                column_type: "default".to_string(),
+                allow_duplicate: true,
+            }),
+        )
+        .await;
+        match response {
+            Ok((status, _)) => status,
+            Err(status) => status,
+        }
+    }
+
+    #[tokio::test]
+    async fn only_members_write_workspace_links() {
+        let app_state = test_app_state().await;
+        let database = &app_state.database;
+        let owner = create_test_user(database, "owner@example.com").await;
+        let viewer = create_test_user(database, "viewer@example.com").await;
+        let outsider = create_test_user(database, "outsider@example.com").await;
+        let org_id = database
+            .create_organization("Acme", &owner.id, &workspaces::free_plan_id())
+            .await
+            .unwrap();
+        database
+            .add_member(database::UserMembership {
+                user_id: viewer.id.clone(),
+                entity_id: org_id.clone(),
+                entity_type: "organization".to_string(),
+                role: Role::Viewer.as_str().to_string(),
+                created_at: Utc::now().to_rfc3339(),
+            })
+            .await
+            .unwrap();
+        let org = ("organization", org_id.as_str());
+
+        assert_eq!(
+            add_link(&app_state, &outsider, org).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            add_link(&app_state, &viewer, org).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(add_link(&app_state, &owner, org).await, StatusCode::CREATED);
+        assert_eq!(
+            add_link(&app_state, &viewer, ("user", &owner.id)).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            add_link(&app_state, &viewer, ("user", &viewer.id)).await,
+            StatusCode::CREATED
+        );
+
+        // Nothing the rejected attempts did reached the organization's audit log
+        let (events, _) = database
+            .get_audit_events(&owner.id, Some(&org_id), 50, 0)
+            .await
+            .unwrap();
+        let link_events: Vec<_> = events
+            .iter()
+            .filter(|event| event.entity_type == "link")
+            .collect();
+        assert_eq!(link_events.len(), 1);
+        assert_eq!(link_events[0].actor_id, owner.id);
+
+        let link_id = database.get_links(&org_id, "organization").await.unwrap()[0]
+            .id
+            .clone();
+        let delete = |user: &database::User| {
+            delete_link(
+                State(app_state.clone()),
+                Path(link_id.clone()),
+                auth_user(user),
+            )
+        };
+        assert_eq!(delete(&outsider).await.unwrap_err(), StatusCode::NOT_FOUND);
+        assert_eq!(delete(&viewer).await.unwrap_err(), StatusCode::FORBIDDEN);
+        assert_eq!(delete(&owner).await.unwrap(), StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn team_link_events_are_audited_under_the_organization() {
+        let app_state = test_app_state().await;
+        let database = &app_state.database;
+        let owner = create_test_user(database, "owner@example.com").await;
+        let org_id = database
+            .create_organization("Acme", &owner.id, &workspaces::free_plan_id())
+            .await
+            .unwrap();
+        let team_id = database
+            .create_team("Ops", &owner.id, &workspaces::free_plan_id(), Some(&org_id))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            add_link(&app_state, &owner, ("team", &team_id)).await,
+            StatusCode::CREATED
+        );
+
+        let (events, _) = database
+            .get_audit_events(&owner.id, Some(&org_id), 50, 0)
+            .await
+            .unwrap();
+        assert!(events
+            .iter()
+            .any(|event| event.entity_type == "link" && event.action == "create"));
+    }
+
+    #[tokio::test]
+    async fn email_change_signs_in_here_and_out_everywhere_else() {
+        let app_state = test_app_state().await;
+        let database = &app_state.database;
+        let user = create_test_user(database, "old@example.com").await;
+        let old_session = start_session(&app_state, &user, &database::SessionDevice::default())
+            .await
+            .unwrap();
+        let token = tokens::generate();
+        database
+            .create_auth_token(
+                &user.id,
+                database::TOKEN_PURPOSE_CHANGE_EMAIL,
+                &tokens::hash(&token),
+                &(Utc::now() + chrono::Duration::hours(1)),
+                Some("new@example.com"),
+            )
+            .await
+            .unwrap();
+
+        let Json(response) = confirm_email_change_handler(
+            State(app_state.clone()),
+            ConnectInfo("127.0.0.1:50000".parse().unwrap()),
+            HeaderMap::new(),
+            Json(ConfirmEmailRequest { token }),
+        )
+        .await
+        .unwrap();
+
+        let claims = app_state.jwt_keys.validate_jwt(&response.token).unwrap();
+        assert_eq!(claims.email, "new@example.com");
+        let sessions = database.get_active_sessions(&user.id).await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].id, claims.sid);
+        let old_claims = app_state.jwt_keys.validate_jwt(&old_session.0).unwrap();
+        assert_ne!(old_claims.sid, claims.sid);
+    }
+
+    #[tokio::test]
+    async fn updating_a_link_onto_a_duplicate_url_conflicts() {
+        let app_state = test_app_state().await;
+        let user = create_test_user(&app_state.database, "user@example.com").await;
+        let owner = ("user", user.id.as_str());
+        assert_eq!(
+            add_link(&app_state, &user, owner).await,
+            StatusCode::CREATED
+        );
+        let existing = app_state
+            .database
+            .get_links(&user.id, "user")
+            .await
+            .unwrap()[0]
+            .clone();
+
+        let other = database::Link {
+            id: uuid::Uuid::new_v4().to_string(),
+            url: "https://example.org".to_string(),
+            canonical_url: Some("https://example.org".to_string()),
+            ..existing.clone()
+        };
+        app_state.database.create_link(other.clone()).await.unwrap();
+
+        let update = |link: &database::Link, url: &str, allow_duplicate: bool| {
+            update_link(
+                State(app_state.clone()),
+                auth_user(&user),
+                Json(UpdateLinkRequest {
+                    id: link.id.clone(),
+                    url: Some(url.to_string()),
+                    description: link.description.clone(),
+                    title: Some(link.title.clone()),
+                    icon: link.icon.clone(),
+                    column_type: Some(link.column_type.clone()),
+                    allow_duplicate,
+                }),
+            )
+        };
+        // Editing a link without moving it isn't a duplicate of itself
+        assert_eq!(
+            update(&existing, "https://example.com", false).await,
+            Ok(StatusCode::OK)
+        );
+        assert_eq!(
+            update(&other, "https://EXAMPLE.com/", false).await,
+            Err(StatusCode::CONFLICT)
+        );
+        assert_eq!(
+            update(&other, "https://example.com", true).await,
+            Ok(StatusCode::OK)
+        );
+
+        // A trashed link is gone until it's restored
+        app_state.database.delete_link(&existing.id).await.unwrap();
+        assert_eq!(
+            update(&existing, "https://example.net", false).await,
+            Err(StatusCode::NOT_FOUND)
+        );
+    }
+
+    #[tokio::test]
+    async fn disabled_users_cannot_refresh() {
+        let app_state = test_app_state().await;
+        let database = &app_state.database;
+        let user = create_test_user(database, "user@example.com").await;
+        let (_, refresh_token) =
+            start_session(&app_state, &user, &database::SessionDevice::default())
+                .await
+                .unwrap();
+        // Only the flag, without the session revocation disabling also does
+        database.set_user_disabled(&user.id, true).await.unwrap();
+
+        let response = refresh_token_handler(
+            State(app_state.clone()),
+            Json(RefreshTokenRequest { refresh_token }),
+        )
+        .await;
+        assert_eq!(response.err(), Some(StatusCode::FORBIDDEN));
+    }
+
+    #[tokio::test]
+    async fn disabled_users_cannot_sign_in() {
+        let app_state = test_app_state().await;
+        let database = &app_state.database;
+        let user = create_test_user(database, "user@example.com").await;
+        // With 2FA on, a disabled account still mustn't get as far as a challenge
+        database
+            .start_totp_enrollment(&user.id, "JBSWY3DPEHPK3PXP")
+            .await
+            .unwrap();
+        database.enable_totp(&user.id, 0, &[]).await.unwrap();
+        database.set_user_disabled(&user.id, true).await.unwrap();
+
+        let response = login_handler(
+            State(app_state.clone()),
+            ConnectInfo("127.0.0.1:50000".parse().unwrap()),
+            HeaderMap::new(),
+            Json(LoginRequest {
+                email: user.email.clone(),
+                password: "correct horse battery staple".to_string(),
+                device_label: None,
+            }),
+        )
+        .await;
+        match response {
+            Err(LoginError::Status(status)) => assert_eq!(status, StatusCode::FORBIDDEN),
+            other => panic!("expected 403, got {:?}", other.map(|_| ())),
+        }
+
+        let (events, _) = database
+            .get_audit_events(&user.id, None, 50, 0)
+            .await
+            .unwrap();
+        assert!(events.iter().all(|event| event.action != "login"));
+    }
+
+    #[tokio::test]
+    async fn session_address_comes_through_trusted_proxies() {
+        let mut app_state = test_app_state().await;
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Forwarded-For", "203.0.113.7".parse().unwrap());
+        let addr: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+
+        let device = session_device(&app_state, &headers, &addr, None);
+        assert_eq!(device.ip_address.as_deref(), Some("127.0.0.1"));
+
+        app_state.trusted_proxies = Arc::new(proxy_auth::TrustedProxies::new(vec![
+            proxy_auth::TrustedNetwork::parse("127.0.0.1").unwrap(),
+        ]));
+        let device = session_device(&app_state, &headers, &addr, None);
+        assert_eq!(device.ip_address.as_deref(), Some("203.0.113.7"));
+    }
+}
@@ -0,0 +1,144 @@
+use anyhow::Result;
+use base64::prelude::*;
+use webauthn_rs::prelude::*;
+
+/// Ceremony kinds, stored alongside their state
+pub const REGISTRATION: &str = "registration";
+pub const AUTHENTICATION: &str = "authentication";
+
+/// Build the relying party from `WEBAUTHN_RP_ID` and `WEBAUTHN_RP_ORIGIN`
+/// The origin defaults to `APP_URL` and the id to the origin's host, which suits a local install
+pub fn from_env() -> Result<Webauthn> {
+    let origin = std::env::var("WEBAUTHN_RP_ORIGIN")
+        .or_else(|_| std::env::var("APP_URL"))
+        .unwrap_or_else(|_| "http://localhost:3000".to_string());
+    let origin = Url::parse(&origin)?;
+
+    let rp_id = match std::env::var("WEBAUTHN_RP_ID") {
+        Ok(rp_id) => rp_id,
+        Err(_) => origin
+            .host_str()
+            .ok_or_else(|| anyhow::anyhow!("WebAuthn origin has no host: {}", origin))?
+            .to_string(),
+    };
+
+    tracing::info!("WebAuthn relying party {} at {}", rp_id, origin);
+    let webauthn = WebauthnBuilder::new(&rp_id, &origin)?
+        .rp_name("OmegaTab")
+        .build()?;
+    Ok(webauthn)
+}
+
+/// Credential IDs are stored base64url encoded so they can be looked up directly
+pub fn encode_credential_id(credential_id: &[u8]) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(credential_id)
+}
+
+/// Namespace for user handles derived from IDs that aren't UUIDs
+const USER_HANDLE_NAMESPACE: Uuid = Uuid::from_u128(0x6f6d_6567_6174_4000_8000_7061_7373_6b79);
+
+/// WebAuthn user handles are UUIDs, reuse the user's ID when it already is one
+/// Otherwise derive one from it, so every passkey a user registers shares the same handle
+pub fn user_handle(user_id: &str) -> Uuid {
+    Uuid::parse_str(user_id)
+        .unwrap_or_else(|_| Uuid::new_v5(&USER_HANDLE_NAMESPACE, user_id.as_bytes()))
+}
+
+/// Registration options asking for a discoverable credential, so sign-in doesn't need an email first
+/// `start_passkey_registration` leaves this as a hint the authenticator may ignore, and the
+/// type isn't re-exported, so it's set on the JSON sent to the browser
+pub fn require_resident_key(options: &CreationChallengeResponse) -> Result<serde_json::Value> {
+    let mut options = serde_json::to_value(options)?;
+    if let Some(selection) = options
+        .pointer_mut("/publicKey/authenticatorSelection")
+        .and_then(|selection| selection.as_object_mut())
+    {
+        selection.insert("residentKey".to_string(), "required".into());
+        selection.insert("requireResidentKey".to_string(), true.into());
+    }
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webauthn_authenticator_rs::{softpasskey::SoftPasskey, WebauthnAuthenticator};
+
+    const ORIGIN: &str = "http://localhost:3000";
+
+    fn webauthn() -> Webauthn {
+        WebauthnBuilder::new("localhost", &Url::parse(ORIGIN).unwrap())
+            .unwrap()
+            .rp_name("OmegaTab")
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn user_handle_is_stable() {
+        let uuid = "0b6f2c1e-3f43-4d3a-9c55-2f3e1f8f5a10";
+        assert_eq!(user_handle(uuid), Uuid::parse_str(uuid).unwrap());
+        assert_eq!(user_handle("user-1"), user_handle("user-1"));
+        assert_ne!(user_handle("user-1"), user_handle("user-2"));
+    }
+
+    #[test]
+    fn registration_options_require_a_resident_key() {
+        let (options, _) = webauthn()
+            .start_passkey_registration(user_handle("user-1"), "a@b.c", "a@b.c", None)
+            .unwrap();
+        let options = require_resident_key(&options).unwrap();
+
+        let selection = &options["publicKey"]["authenticatorSelection"];
+        assert_eq!(selection["residentKey"], "required");
+        assert_eq!(selection["requireResidentKey"], true);
+        // Still what the browser expects
+        serde_json::from_value::<CreationChallengeResponse>(options).unwrap();
+    }
+
+    /// Register a passkey then sign in with it the way `passkey_login_finish_handler` does
+    /// The software authenticator can't hold resident keys, so it's told which credential to use
+    /// and the user handle a browser would return is filled in on its response
+    #[test]
+    fn registered_passkey_signs_in_without_an_email() {
+        let webauthn = webauthn();
+        let origin = Url::parse(ORIGIN).unwrap();
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new(true));
+        let user_id = "user-1";
+
+        let (options, registration) = webauthn
+            .start_passkey_registration(user_handle(user_id), "a@b.c", "a@b.c", None)
+            .unwrap();
+        let credential = authenticator
+            .do_registration(origin.clone(), options)
+            .unwrap();
+        let passkey = webauthn
+            .finish_passkey_registration(&credential, &registration)
+            .unwrap();
+
+        // Stored and read back as JSON, keyed by the encoded credential ID
+        let stored = serde_json::to_string(&passkey).unwrap();
+        let stored_id = encode_credential_id(passkey.cred_id());
+
+        let (options, authentication) = webauthn.start_discoverable_authentication().unwrap();
+        assert!(options.public_key.allow_credentials.is_empty());
+        let mut options = serde_json::to_value(options).unwrap();
+        options["publicKey"]["allowCredentials"] =
+            serde_json::json!([{ "type": "public-key", "id": stored_id }]);
+        let options = serde_json::from_value(options).unwrap();
+        let mut response = authenticator.do_authentication(origin, options).unwrap();
+        response.response.user_handle = Some(user_handle(user_id).as_bytes().to_vec().into());
+
+        let (handle, credential_id) = webauthn
+            .identify_discoverable_authentication(&response)
+            .unwrap();
+        assert_eq!(handle, user_handle(user_id));
+        assert_eq!(encode_credential_id(credential_id), stored_id);
+
+        let passkey: Passkey = serde_json::from_str(&stored).unwrap();
+        let result = webauthn
+            .finish_discoverable_authentication(&response, authentication, &[(&passkey).into()])
+            .unwrap();
+        assert!(result.user_verified());
+    }
+}
@@ -0,0 +1,405 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+use sha1::{Digest, Sha1};
+use std::path::PathBuf;
+
+/// Passwords attackers try first, matched after undoing common letter substitutions
+const COMMON_PASSWORDS: &[&str] = &[
+    "password", "123456", "qwerty", "letmein", "iloveyou", "admin", "welcome", "monkey", "dragon",
+    "football", "baseball", "sunshine", "princess", "trustno", "master", "shadow", "superman",
+    "batman", "michael", "starwars", "whatever", "freedom", "secret", "login", "abc123", "111111",
+    "000000", "changeme", "default", "summer", "winter", "spring", "autumn", "hello", "charlie",
+    "jordan", "pokemon", "computer", "internet", "omegatab",
+];
+
+/// Keyboard rows, walking along one is as easy to guess as a sequence
+const KEYBOARD_ROWS: &[&str] = &["1234567890", "qwertyuiop", "asdfghjkl", "zxcvbnm"];
+
+/// Why a password was refused, sent to the client as a stable `error` code
+#[derive(Debug, Clone)]
+pub enum PasswordError {
+    TooShort { min_length: usize },
+    TooWeak { min_strength: u8, strength: u8 },
+    Breached,
+}
+
+impl PasswordError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            PasswordError::TooShort { .. } => "password_too_short",
+            PasswordError::TooWeak { .. } => "password_too_weak",
+            PasswordError::Breached => "password_breached",
+        }
+    }
+}
+
+impl IntoResponse for PasswordError {
+    fn into_response(self) -> Response {
+        let body = match &self {
+            PasswordError::TooShort { min_length } => json!({
+                "error": self.code(),
+                "min_length": min_length,
+            }),
+            PasswordError::TooWeak {
+                min_strength,
+                strength,
+            } => json!({
+                "error": self.code(),
+                "min_strength": min_strength,
+                "strength": strength,
+            }),
+            PasswordError::Breached => json!({ "error": self.code() }),
+        };
+        (StatusCode::UNPROCESSABLE_ENTITY, Json(body)).into_response()
+    }
+}
+
+/// Undo the substitutions people make to dress up a dictionary word
+fn unleet(c: char) -> char {
+    match c {
+        '0' => 'o',
+        '1' | '!' => 'i',
+        '3' => 'e',
+        '4' | '@' => 'a',
+        '5' | '$' => 's',
+        '7' => 't',
+        _ => c,
+    }
+}
+
+/// Whether `c` follows `prev` in the alphabet, the digits or along a keyboard row, either way
+fn continues_sequence(prev: char, c: char) -> bool {
+    if !prev.is_ascii_alphanumeric() || !c.is_ascii_alphanumeric() {
+        return false;
+    }
+    let (prev, c) = (
+        prev.to_ascii_lowercase() as u8,
+        c.to_ascii_lowercase() as u8,
+    );
+    if prev.abs_diff(c) == 1 {
+        return true;
+    }
+    KEYBOARD_ROWS.iter().any(|row| {
+        row.as_bytes()
+            .windows(2)
+            .any(|pair| pair == [prev, c] || pair == [c, prev])
+    })
+}
+
+/// Rough strength score from 0 (trivially guessable) to 4 (very strong), on the same scale as zxcvbn
+/// Guesses are estimated from the character pool, with repeats, sequences, keyboard walks,
+/// years, common passwords and the user's own details (like their email) counting for next to nothing
+pub fn estimate_strength(password: &str, user_inputs: &[&str]) -> u8 {
+    let chars: Vec<char> = password.chars().collect();
+    if chars.is_empty() {
+        return 0;
+    }
+
+    let mut pool = 0;
+    if chars.iter().any(|c| c.is_ascii_lowercase()) {
+        pool += 26;
+    }
+    if chars.iter().any(|c| c.is_ascii_uppercase()) {
+        pool += 26;
+    }
+    if chars.iter().any(|c| c.is_ascii_digit()) {
+        pool += 10;
+    }
+    if chars.iter().any(|c| c.is_ascii_punctuation() || *c == ' ') {
+        pool += 33;
+    }
+    if chars.iter().any(|c| !c.is_ascii()) {
+        pool += 100;
+    }
+    let bits_per_char = (pool as f64).log2();
+
+    // Characters inside a known word cost nothing, the word itself costs its rank in the list
+    let normalized: Vec<char> = chars
+        .iter()
+        .map(|c| unleet(c.to_ascii_lowercase()))
+        .collect();
+    let mut covered = vec![false; chars.len()];
+    let mut bits = 0.0;
+    let words = COMMON_PASSWORDS
+        .iter()
+        .copied()
+        .chain(user_inputs.iter().copied())
+        .map(|word| word.to_lowercase())
+        .filter(|word| word.chars().count() >= 3);
+    for (rank, word) in words.enumerate() {
+        let word: Vec<char> = word.chars().map(unleet).collect();
+        let mut start = 0;
+        while start + word.len() <= normalized.len() {
+            if normalized[start..start + word.len()] == word[..] {
+                covered[start..start + word.len()]
+                    .iter_mut()
+                    .for_each(|c| *c = true);
+                bits += ((rank + 2) as f64).log2();
+                start += word.len();
+            } else {
+                start += 1;
+            }
+        }
+    }
+
+    // Years are one of a couple of hundred guesses
+    let mut start = 0;
+    while start + 4 <= chars.len() {
+        let span = &chars[start..start + 4];
+        let is_year = span.iter().all(|c| c.is_ascii_digit())
+            && matches!(span[..2], ['1', '9'] | ['2', '0'])
+            && !covered[start..start + 4].iter().any(|c| *c);
+        if is_year {
+            covered[start..start + 4].iter_mut().for_each(|c| *c = true);
+            bits += 200f64.log2();
+            start += 4;
+        } else {
+            start += 1;
+        }
+    }
+
+    for (i, c) in chars.iter().enumerate() {
+        if covered[i] {
+            continue;
+        }
+        let predictable = i > 0 && (chars[i - 1] == *c || continues_sequence(chars[i - 1], *c));
+        bits += if predictable { 1.0 } else { bits_per_char };
+    }
+
+    // zxcvbn's cut-offs of 10^3, 10^6, 10^8 and 10^10 guesses, in bits
+    match bits {
+        b if b < 10.0 => 0,
+        b if b < 20.0 => 1,
+        b if b < 26.6 => 2,
+        b if b < 33.2 => 3,
+        _ => 4,
+    }
+}
+
+/// Known-breached passwords, as a directory of SHA-1 range files like the Have I Been Pwned
+/// downloader writes: `<first 5 hex chars>.txt`, each line `<remaining 35 hex chars>:<count>`
+/// Only the one range file for a password's prefix is read, and nothing leaves the machine
+#[derive(Debug, Clone)]
+pub struct BreachedPasswords {
+    dir: PathBuf,
+}
+
+impl BreachedPasswords {
+    pub async fn contains(&self, password: &str) -> anyhow::Result<bool> {
+        let hash = hex::encode_upper(Sha1::digest(password.as_bytes()));
+        let (prefix, suffix) = hash.split_at(5);
+
+        let contents =
+            match tokio::fs::read_to_string(self.dir.join(format!("{}.txt", prefix))).await {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+                Err(e) => return Err(e.into()),
+            };
+
+        Ok(contents.lines().any(|line| {
+            line.split(':')
+                .next()
+                .is_some_and(|entry| entry.trim().eq_ignore_ascii_case(suffix))
+        }))
+    }
+}
+
+/// Rules new passwords have to meet, loaded once at startup
+#[derive(Debug, Clone)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub min_strength: u8,
+    pub breached: Option<BreachedPasswords>,
+}
+
+impl PasswordPolicy {
+    /// Configure from `PASSWORD_MIN_LENGTH` (default 8), `PASSWORD_MIN_STRENGTH` (0-4, default 2)
+    /// and `PASSWORD_BREACH_LIST_DIR`, which turns on the breached-password check
+    pub fn from_env() -> anyhow::Result<Self> {
+        let min_length = std::env::var("PASSWORD_MIN_LENGTH")
+            .ok()
+            .and_then(|length| length.parse::<usize>().ok())
+            .unwrap_or(8);
+        let min_strength = std::env::var("PASSWORD_MIN_STRENGTH")
+            .ok()
+            .and_then(|strength| strength.parse::<u8>().ok())
+            .unwrap_or(2)
+            .min(4);
+
+        let breached = match std::env::var("PASSWORD_BREACH_LIST_DIR") {
+            Ok(dir) if !dir.trim().is_empty() => {
+                let dir = PathBuf::from(dir.trim());
+                if !dir.is_dir() {
+                    return Err(anyhow::anyhow!(
+                        "PASSWORD_BREACH_LIST_DIR {} is not a directory",
+                        dir.display()
+                    ));
+                }
+                tracing::info!("Checking new passwords against {}", dir.display());
+                Some(BreachedPasswords { dir })
+            }
+            _ => None,
+        };
+
+        Ok(PasswordPolicy {
+            min_length,
+            min_strength,
+            breached,
+        })
+    }
+
+    /// Check a new password for the account with the given email
+    pub async fn check(&self, password: &str, email: &str) -> Result<(), PasswordError> {
+        if password.chars().count() < self.min_length.max(1) {
+            return Err(PasswordError::TooShort {
+                min_length: self.min_length.max(1),
+            });
+        }
+
+        let local_part = email.split('@').next().unwrap_or_default();
+        let strength = estimate_strength(password, &[email, local_part]);
+        if strength < self.min_strength {
+            return Err(PasswordError::TooWeak {
+                min_strength: self.min_strength,
+                strength,
+            });
+        }
+
+        if let Some(breached) = &self.breached {
+            match breached.contains(password).await {
+                Ok(true) => return Err(PasswordError::Breached),
+                Ok(false) => {}
+                // The list is an extra safeguard, so a broken one doesn't stop anyone setting a password
+                Err(e) => tracing::error!("Failed to check breached passwords: {:?}", e),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guessable_passwords_score_low() {
+        let email = "jsmith@example.com";
+        let user_inputs = [email, "jsmith"];
+        let cases = [
+            ("password", 0),
+            ("p@ssw0rd", 0),
+            ("P4ssw0rd!", 1),
+            ("qwertyuiop", 0),
+            ("1234567890", 0),
+            ("asdfghjkl;", 1),
+            ("jsmith2024", 1),
+            ("j5m1th@example.com", 1),
+        ];
+        for (password, max) in cases {
+            let strength = estimate_strength(password, &user_inputs);
+            assert!(
+                strength <= max,
+                "{} scored {}, expected at most {}",
+                password,
+                strength,
+                max
+            );
+        }
+    }
+
+    #[test]
+    fn long_passphrases_score_high() {
+        assert_eq!(estimate_strength("correct horse battery staple", &[]), 4);
+        assert_eq!(estimate_strength("vT9#qLm2!xZ8", &[]), 4);
+        assert_eq!(estimate_strength("", &[]), 0);
+    }
+
+    fn range_dir(files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("omega-tab-pwned-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, contents) in files {
+            std::fs::write(dir.join(name), contents).unwrap();
+        }
+        dir
+    }
+
+    #[tokio::test]
+    async fn breached_passwords_come_from_the_range_file() {
+        // SHA-1 of "password" is 5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8
+        let dir = range_dir(&[(
+            "5BAA6.txt",
+            "0018A45C4D1DEF81644B54AB7F969B88D65:1\r\n1E4C9B93F3F0682250B6CF8331B7EE68FD8:9545824\r\n",
+        )]);
+        let breached = BreachedPasswords { dir };
+
+        assert!(breached.contains("password").await.unwrap());
+        // No range file for the prefix at all
+        assert!(!breached
+            .contains("correct horse battery staple")
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn misses_within_a_range_file() {
+        let hash = hex::encode_upper(Sha1::digest(b"password"));
+        let dir = range_dir(&[(
+            &format!("{}.txt", &hash[..5]),
+            "00000000000000000000000000000000000:3\n",
+        )]);
+        let breached = BreachedPasswords { dir };
+        assert!(!breached.contains("password").await.unwrap());
+    }
+
+    async fn error_body(error: PasswordError) -> serde_json::Value {
+        let response = error.into_response();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = axum::body::to_bytes(response.into_body(), 1024)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn policy_errors_carry_the_client_codes() {
+        let hash = hex::encode_upper(Sha1::digest(b"vT9#qLm2!xZ8"));
+        let dir = range_dir(&[(
+            &format!("{}.txt", &hash[..5]),
+            &format!("{}:2\n", &hash[5..]),
+        )]);
+        let policy = PasswordPolicy {
+            min_length: 8,
+            min_strength: 2,
+            breached: Some(BreachedPasswords { dir }),
+        };
+        let email = "jsmith@example.com";
+
+        let too_short = policy.check("abc", email).await.unwrap_err();
+        assert_eq!(
+            error_body(too_short).await,
+            json!({ "error": "password_too_short", "min_length": 8 })
+        );
+
+        let too_weak = policy.check("password", email).await.unwrap_err();
+        assert_eq!(
+            error_body(too_weak).await,
+            json!({ "error": "password_too_weak", "min_strength": 2, "strength": 0 })
+        );
+
+        let breached = policy.check("vT9#qLm2!xZ8", email).await.unwrap_err();
+        assert_eq!(
+            error_body(breached).await,
+            json!({ "error": "password_breached" })
+        );
+
+        assert!(policy
+            .check("correct horse battery staple", email)
+            .await
+            .is_ok());
+    }
+}
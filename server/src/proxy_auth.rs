@@ -0,0 +1,313 @@
+use axum::http::HeaderMap;
+use std::net::IpAddr;
+
+/// Issuer recorded against identities that came from the proxy's user header
+pub const IDENTITY_ISSUER: &str = "proxy";
+
+/// A trusted proxy address or CIDR range, like `10.0.0.2` or `172.16.0.0/12`
+#[derive(Debug, Clone)]
+pub struct TrustedNetwork {
+    address: IpAddr,
+    prefix_len: u32,
+}
+
+impl TrustedNetwork {
+    pub fn parse(raw: &str) -> Option<Self> {
+        let (address, prefix_len) = match raw.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (raw, None),
+        };
+        let address: IpAddr = address.trim().parse().ok()?;
+        let max_len = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .trim()
+                .parse()
+                .ok()
+                .filter(|len| *len <= max_len)?,
+            None => max_len,
+        };
+        Some(TrustedNetwork {
+            address,
+            prefix_len,
+        })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        // Proxies on an IPv4 address can show up as IPv4-mapped IPv6 peers
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(*ip),
+            IpAddr::V4(_) => *ip,
+        };
+        match (self.address, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Parse a comma-separated list of addresses and CIDR ranges from the named setting
+fn parse_networks(raw: &str, setting: &str) -> anyhow::Result<Vec<TrustedNetwork>> {
+    raw.split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| {
+            TrustedNetwork::parse(entry)
+                .ok_or_else(|| anyhow::anyhow!("Invalid {} entry {:?}", setting, entry))
+        })
+        .collect()
+}
+
+/// Reverse proxies whose `Forwarded` and `X-Forwarded-For` headers are believed, from
+/// `TRUSTED_PROXIES` plus the authenticating proxies in proxy mode
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    networks: Vec<TrustedNetwork>,
+}
+
+impl TrustedProxies {
+    pub fn new(networks: Vec<TrustedNetwork>) -> Self {
+        TrustedProxies { networks }
+    }
+
+    pub fn from_env(proxy_auth: Option<&ProxyAuthConfig>) -> anyhow::Result<Self> {
+        let raw = std::env::var("TRUSTED_PROXIES").unwrap_or_default();
+        let mut networks = parse_networks(&raw, "TRUSTED_PROXIES")?;
+        if let Some(proxy_auth) = proxy_auth {
+            networks.extend(proxy_auth.trusted_proxies.iter().cloned());
+        }
+        Ok(TrustedProxies { networks })
+    }
+
+    fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(ip))
+    }
+
+    /// The address a request came from: the peer itself, or when the peer is a trusted proxy,
+    /// the nearest address in its forwarding header that isn't another trusted proxy
+    /// Entries left of that one were written by the client, so they're never believed
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.is_trusted(&peer) {
+            return peer;
+        }
+
+        let mut client = peer;
+        for entry in forwarded_for(headers).iter().rev() {
+            match parse_forwarded_address(entry) {
+                Some(ip) => {
+                    client = ip;
+                    if !self.is_trusted(&ip) {
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+        client
+    }
+}
+
+/// Hops from the `Forwarded` header's `for` parameters, or else `X-Forwarded-For`, client first
+fn forwarded_for(headers: &HeaderMap) -> Vec<String> {
+    let values = |name: &str| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|entry| entry.trim().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    let forwarded: Vec<String> = values("forwarded")
+        .iter()
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (name, value) = pair.trim().split_once('=')?;
+                name.trim()
+                    .eq_ignore_ascii_case("for")
+                    .then(|| value.trim().trim_matches('"').to_string())
+            })
+        })
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+    values("x-forwarded-for")
+}
+
+/// An address from a forwarding header, which may carry a port and brackets around IPv6
+fn parse_forwarded_address(entry: &str) -> Option<IpAddr> {
+    if let Ok(ip) = entry.parse() {
+        return Some(ip);
+    }
+    if let Some(rest) = entry.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    entry.rsplit_once(':')?.0.parse().ok()
+}
+
+/// Trust a reverse proxy (Authelia, oauth2-proxy, Tailscale...) to authenticate users,
+/// enabled with `AUTH_MODE=proxy`
+#[derive(Debug, Clone)]
+pub struct ProxyAuthConfig {
+    pub user_header: String,
+    pub email_header: String,
+    pub trusted_proxies: Vec<TrustedNetwork>,
+}
+
+/// A user as asserted by the proxy
+#[derive(Debug, Clone)]
+pub struct ProxyIdentity {
+    pub username: String,
+    pub email: Option<String>,
+}
+
+impl ProxyAuthConfig {
+    /// Configure from `PROXY_AUTH_USER_HEADER` (default `Remote-User`),
+    /// `PROXY_AUTH_EMAIL_HEADER` (default `Remote-Email`) and `PROXY_AUTH_TRUSTED_IPS`
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let mode = std::env::var("AUTH_MODE").unwrap_or_default();
+        if !mode.eq_ignore_ascii_case("proxy") {
+            return Ok(None);
+        }
+
+        let raw_proxies = std::env::var("PROXY_AUTH_TRUSTED_IPS").unwrap_or_default();
+        let trusted_proxies = parse_networks(&raw_proxies, "PROXY_AUTH_TRUSTED_IPS")?;
+        // Without a trusted proxy anyone could send the header and sign in as anyone
+        if trusted_proxies.is_empty() {
+            return Err(anyhow::anyhow!(
+                "PROXY_AUTH_TRUSTED_IPS must be set when AUTH_MODE=proxy"
+            ));
+        }
+
+        let config = ProxyAuthConfig {
+            user_header: std::env::var("PROXY_AUTH_USER_HEADER")
+                .unwrap_or_else(|_| "Remote-User".to_string()),
+            email_header: std::env::var("PROXY_AUTH_EMAIL_HEADER")
+                .unwrap_or_else(|_| "Remote-Email".to_string()),
+            trusted_proxies,
+        };
+
+        tracing::info!(
+            "Proxy authentication enabled, trusting {} from {}",
+            config.user_header,
+            raw_proxies
+        );
+        Ok(Some(config))
+    }
+
+    pub fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.trusted_proxies
+            .iter()
+            .any(|network| network.contains(ip))
+    }
+
+    /// Read the user from the request headers
+    /// Falls back to the email header when the proxy only sends an email
+    pub fn identity(&self, headers: &HeaderMap) -> Option<ProxyIdentity> {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+        };
+
+        let email = header(&self.email_header);
+        let username = header(&self.user_header).or_else(|| email.clone())?;
+        // Proxies that only forward a username often use the email as the username
+        let email = email.or_else(|| username.contains('@').then(|| username.clone()));
+
+        Some(ProxyIdentity { username, email })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn proxies(raw: &str) -> TrustedProxies {
+        TrustedProxies::new(parse_networks(raw, "TRUSTED_PROXIES").unwrap())
+    }
+
+    fn header(name: &'static str, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    fn ip(raw: &str) -> IpAddr {
+        raw.parse().unwrap()
+    }
+
+    #[test]
+    fn untrusted_peer_headers_are_ignored() {
+        let proxies = proxies("10.0.0.1");
+        let headers = header("x-forwarded-for", "203.0.113.7");
+        assert_eq!(
+            proxies.client_ip(ip("198.51.100.2"), &headers),
+            ip("198.51.100.2")
+        );
+    }
+
+    #[test]
+    fn trusted_proxy_forwards_the_client() {
+        let proxies = proxies("10.0.0.0/8");
+        let headers = header("x-forwarded-for", "203.0.113.7");
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), &headers),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn spoofed_entries_left_of_the_client_are_ignored() {
+        let proxies = proxies("10.0.0.0/8");
+        let headers = header("x-forwarded-for", "1.2.3.4, 203.0.113.7, 10.0.0.2");
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), &headers),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn forwarded_header_takes_precedence() {
+        let proxies = proxies("127.0.0.1");
+        let mut headers = header("forwarded", "for=\"[2001:db8::1]:4711\";proto=https");
+        headers.insert("x-forwarded-for", HeaderValue::from_static("203.0.113.7"));
+        assert_eq!(
+            proxies.client_ip(ip("127.0.0.1"), &headers),
+            ip("2001:db8::1")
+        );
+
+        let headers = header("forwarded", "for=192.0.2.60:8080");
+        assert_eq!(
+            proxies.client_ip(ip("127.0.0.1"), &headers),
+            ip("192.0.2.60")
+        );
+    }
+
+    #[test]
+    fn unparseable_hop_stops_at_the_proxy() {
+        let proxies = proxies("127.0.0.1");
+        let headers = header("x-forwarded-for", "203.0.113.7, garbage");
+        assert_eq!(
+            proxies.client_ip(ip("127.0.0.1"), &headers),
+            ip("127.0.0.1")
+        );
+    }
+
+    #[test]
+    fn invalid_network_is_an_error() {
+        assert!(parse_networks("10.0.0.0/8, nonsense", "TRUSTED_PROXIES").is_err());
+        assert!(parse_networks("", "TRUSTED_PROXIES").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,412 @@
+use axum::{
+    body::{to_bytes, Body},
+    extract::{ConnectInfo, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::{api_tokens, tokens, AppState};
+
+/// Rate limit headers from the IETF RateLimit header fields draft
+pub const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+pub const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+pub const RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+pub const RATELIMIT_POLICY: HeaderName = HeaderName::from_static("ratelimit-policy");
+
+/// Headers the browser client needs to see to back off, exposed through CORS
+pub const EXPOSED_HEADERS: [HeaderName; 5] = [
+    RATELIMIT_LIMIT,
+    RATELIMIT_REMAINING,
+    RATELIMIT_RESET,
+    RATELIMIT_POLICY,
+    header::RETRY_AFTER,
+];
+
+/// Routes are limited separately by how expensive or sensitive they are
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RouteClass {
+    /// Sign-in, registration and other credential endpoints
+    Auth,
+    /// Search suggestions, each one is a call to the Brave API
+    Suggest,
+    Write,
+    Read,
+}
+
+impl RouteClass {
+    /// Classify a request by its path relative to `/api`
+    pub fn of(method: &Method, path: &str) -> Self {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+        match segments.as_slice() {
+            ["login", ..]
+            | ["register"]
+            | ["staging_login"]
+            | ["password", "reset", ..]
+            | ["token", "refresh"]
+            | ["email", "verify", "confirm"]
+            | ["user", "email", "confirm"]
+            | ["passkeys", "login", ..]
+            | ["oidc", "login" | "callback"] => RouteClass::Auth,
+            ["suggest", ..] => RouteClass::Suggest,
+            _ if method == Method::GET || method == Method::HEAD => RouteClass::Read,
+            _ => RouteClass::Write,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            RouteClass::Auth => "auth",
+            RouteClass::Suggest => "suggest",
+            RouteClass::Write => "write",
+            RouteClass::Read => "read",
+        }
+    }
+}
+
+fn env_u32(name: &str, default: u32) -> u32 {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse::<u32>().ok())
+        .unwrap_or(default)
+}
+
+struct Window {
+    started: Instant,
+    count: u32,
+}
+
+/// Where a client stands against its limit after a request
+struct Decision {
+    limit: u32,
+    remaining: u32,
+    reset: Duration,
+    allowed: bool,
+}
+
+impl Decision {
+    fn reset_secs(&self) -> u64 {
+        self.reset.as_secs().max(1)
+    }
+}
+
+/// Fixed-window request counters per route class and client, kept in memory
+pub struct RateLimiter {
+    auth: u32,
+    suggest: u32,
+    write: u32,
+    read: u32,
+    window: Duration,
+    windows: Mutex<HashMap<(RouteClass, String), Window>>,
+}
+
+// Counters are pruned once there are this many, dropping windows that have ended
+const MAX_TRACKED_WINDOWS: usize = 10_000;
+
+impl RateLimiter {
+    /// Requests per minute from `RATE_LIMIT_AUTH_PER_MINUTE` (default 20),
+    /// `RATE_LIMIT_SUGGEST_PER_MINUTE` (60), `RATE_LIMIT_WRITE_PER_MINUTE` (120)
+    /// and `RATE_LIMIT_READ_PER_MINUTE` (600), 0 turns the limit off
+    pub fn from_env() -> Self {
+        RateLimiter {
+            auth: env_u32("RATE_LIMIT_AUTH_PER_MINUTE", 20),
+            suggest: env_u32("RATE_LIMIT_SUGGEST_PER_MINUTE", 60),
+            write: env_u32("RATE_LIMIT_WRITE_PER_MINUTE", 120),
+            read: env_u32("RATE_LIMIT_READ_PER_MINUTE", 600),
+            window: Duration::from_secs(60),
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn limit(&self, class: RouteClass) -> u32 {
+        match class {
+            RouteClass::Auth => self.auth,
+            RouteClass::Suggest => self.suggest,
+            RouteClass::Write => self.write,
+            RouteClass::Read => self.read,
+        }
+    }
+
+    /// Count a request, or None when the class isn't limited
+    fn hit(&self, class: RouteClass, client: String) -> Option<Decision> {
+        let limit = self.limit(class);
+        if limit == 0 {
+            return None;
+        }
+
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        if windows.len() >= MAX_TRACKED_WINDOWS {
+            windows.retain(|_, window| now.duration_since(window.started) < self.window);
+        }
+
+        let window = windows.entry((class, client)).or_insert(Window {
+            started: now,
+            count: 0,
+        });
+        if now.duration_since(window.started) >= self.window {
+            window.started = now;
+            window.count = 0;
+        }
+
+        let allowed = window.count < limit;
+        if allowed {
+            window.count += 1;
+        }
+
+        Some(Decision {
+            limit,
+            remaining: limit - window.count,
+            reset: self
+                .window
+                .saturating_sub(now.duration_since(window.started)),
+            allowed,
+        })
+    }
+
+    fn policy(&self, limit: u32) -> HeaderValue {
+        HeaderValue::from_str(&format!("{};w={}", limit, self.window.as_secs())).unwrap()
+    }
+}
+
+/// Who a request is counted against: the signed-in user where we can tell cheaply,
+/// otherwise the IP it came from, read through any trusted proxies
+/// Credential endpoints are always counted per IP, since that's who's guessing
+fn client_key(
+    app_state: &AppState,
+    class: RouteClass,
+    headers: &HeaderMap,
+    peer: Option<IpAddr>,
+) -> String {
+    let ip_key = match peer {
+        Some(ip) => format!("ip:{}", ip),
+        None => "ip:unknown".to_string(),
+    };
+    if class == RouteClass::Auth {
+        return ip_key;
+    }
+
+    if let Some(proxy) = &app_state.proxy_auth {
+        if let Some(identity) = peer
+            .filter(|ip| proxy.is_trusted(ip))
+            .and_then(|_| proxy.identity(headers))
+        {
+            return format!("user:{}", identity.username);
+        }
+        return ip_key;
+    }
+
+    // API tokens are only checked against the database later, so they count against the IP
+    let token = headers
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim_start_matches("Bearer "))
+        .filter(|token| !token.is_empty() && !token.starts_with(api_tokens::PREFIX));
+    match token.and_then(|token| app_state.jwt_keys.validate_jwt(token).ok()) {
+        Some(claims) => format!("user:{}", claims.user_id),
+        None => ip_key,
+    }
+}
+
+fn is_refresh(path: &str) -> bool {
+    path.trim_matches('/') == "token/refresh"
+}
+
+// Refresh bodies are just the token, anything bigger isn't worth reading
+const REFRESH_BODY_LIMIT: usize = 4096;
+
+#[derive(Deserialize)]
+struct RefreshBody {
+    refresh_token: String,
+}
+
+/// The session a token refresh is for, so refreshes are counted per session rather than per IP,
+/// where every new-tab page behind one proxy would share a bucket
+/// Unknown tokens stay on the IP's count. The buffered body is put back for the handler
+async fn refresh_session(
+    app_state: &AppState,
+    request: Request<Body>,
+) -> Result<(Request<Body>, Option<String>), Response> {
+    let (parts, body) = request.into_parts();
+    let bytes = to_bytes(body, REFRESH_BODY_LIMIT)
+        .await
+        .map_err(|_| StatusCode::PAYLOAD_TOO_LARGE.into_response())?;
+
+    let session_id = match serde_json::from_slice::<RefreshBody>(&bytes) {
+        Ok(body) => app_state
+            .database
+            .get_session_by_refresh_token(&tokens::hash(&body.refresh_token))
+            .await
+            .ok()
+            .map(|session| session.id),
+        Err(_) => None,
+    };
+
+    Ok((Request::from_parts(parts, Body::from(bytes)), session_id))
+}
+
+/// Middleware applying the rate limits, answering 429 with `Retry-After` once a client is over
+/// Every limited response carries `RateLimit-*` headers so the client can slow down before then
+pub async fn limit_requests(
+    State(app_state): State<AppState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let class = RouteClass::of(request.method(), request.uri().path());
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let peer = peer.map(|ip| app_state.trusted_proxies.client_ip(ip, request.headers()));
+    let mut client = client_key(&app_state, class, request.headers(), peer);
+
+    let request = if is_refresh(request.uri().path()) {
+        let (request, session_id) = match refresh_session(&app_state, request).await {
+            Ok(refresh) => refresh,
+            Err(response) => return response,
+        };
+        if let Some(session_id) = session_id {
+            client = format!("session:{}", session_id);
+        }
+        request
+    } else {
+        request
+    };
+
+    let decision = match app_state.rate_limiter.hit(class, client.clone()) {
+        Some(decision) => decision,
+        None => return next.run(request).await,
+    };
+
+    let mut response = if decision.allowed {
+        next.run(request).await
+    } else {
+        tracing::warn!("Rate limited {} requests from {}", class.name(), client);
+        let mut response = StatusCode::TOO_MANY_REQUESTS.into_response();
+        response.headers_mut().insert(
+            header::RETRY_AFTER,
+            HeaderValue::from(decision.reset_secs()),
+        );
+        response
+    };
+
+    let headers = response.headers_mut();
+    headers.insert(RATELIMIT_LIMIT, HeaderValue::from(decision.limit));
+    headers.insert(RATELIMIT_REMAINING, HeaderValue::from(decision.remaining));
+    headers.insert(RATELIMIT_RESET, HeaderValue::from(decision.reset_secs()));
+    headers.insert(
+        RATELIMIT_POLICY,
+        app_state.rate_limiter.policy(decision.limit),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{create_test_user, test_app_state};
+    use crate::{database, proxy_auth};
+    use axum::{middleware, routing::post, Router};
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    fn app(app_state: &AppState) -> Router {
+        Router::new()
+            .route("/token/refresh", post(|body: String| async move { body }))
+            .layer(middleware::from_fn_with_state(
+                app_state.clone(),
+                limit_requests,
+            ))
+    }
+
+    fn refresh(refresh_token: &str, peer: &str, forwarded_for: Option<&str>) -> Request<Body> {
+        let mut builder = Request::post("/token/refresh");
+        if let Some(forwarded_for) = forwarded_for {
+            builder = builder.header("X-Forwarded-For", forwarded_for);
+        }
+        let mut request = builder
+            .body(Body::from(format!(
+                r#"{{"refresh_token":"{}"}}"#,
+                refresh_token
+            )))
+            .unwrap();
+        let peer: SocketAddr = format!("{}:443", peer).parse().unwrap();
+        request.extensions_mut().insert(ConnectInfo(peer));
+        request
+    }
+
+    async fn remaining(app: &Router, request: Request<Body>) -> u32 {
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        response.headers()[RATELIMIT_REMAINING]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn refreshes_count_per_session() {
+        let app_state = test_app_state().await;
+        let app = app(&app_state);
+
+        let mut refresh_tokens = Vec::new();
+        for email in ["alice@example.com", "bob@example.com"] {
+            let user = create_test_user(&app_state.database, email).await;
+            let device = database::SessionDevice::default();
+            let (_, refresh_token) = crate::start_session(&app_state, &user, &device)
+                .await
+                .unwrap();
+            refresh_tokens.push(refresh_token);
+        }
+
+        let limit = app_state.rate_limiter.limit(RouteClass::Auth);
+        let first = remaining(&app, refresh(&refresh_tokens[0], "127.0.0.1", None)).await;
+        let second = remaining(&app, refresh(&refresh_tokens[1], "127.0.0.1", None)).await;
+        assert_eq!((first, second), (limit - 1, limit - 1));
+
+        // Unknown tokens fall back to the IP
+        let unknown = remaining(&app, refresh("nope", "127.0.0.1", None)).await;
+        assert_eq!(unknown, limit - 1);
+        let unknown = remaining(&app, refresh("still-nope", "127.0.0.1", None)).await;
+        assert_eq!(unknown, limit - 2);
+    }
+
+    #[tokio::test]
+    async fn refresh_body_reaches_the_handler() {
+        let app_state = test_app_state().await;
+        let response = app(&app_state)
+            .oneshot(refresh("abc", "127.0.0.1", None))
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), 1024).await.unwrap();
+        assert_eq!(&body[..], br#"{"refresh_token":"abc"}"#);
+    }
+
+    #[tokio::test]
+    async fn clients_behind_a_trusted_proxy_count_separately() {
+        let mut app_state = test_app_state().await;
+        app_state.trusted_proxies = Arc::new(proxy_auth::TrustedProxies::new(vec![
+            proxy_auth::TrustedNetwork::parse("127.0.0.1").unwrap(),
+        ]));
+        let app = app(&app_state);
+        let limit = app_state.rate_limiter.limit(RouteClass::Auth);
+
+        let first = refresh("x", "127.0.0.1", Some("203.0.113.1"));
+        assert_eq!(remaining(&app, first).await, limit - 1);
+        let second = refresh("x", "127.0.0.1", Some("203.0.113.2"));
+        assert_eq!(remaining(&app, second).await, limit - 1);
+
+        // Headers from anyone else are ignored
+        let spoofed = refresh("x", "198.51.100.9", Some("203.0.113.1"));
+        assert_eq!(remaining(&app, spoofed).await, limit - 1);
+        let spoofed = refresh("x", "198.51.100.9", Some("203.0.113.3"));
+        assert_eq!(remaining(&app, spoofed).await, limit - 2);
+    }
+}
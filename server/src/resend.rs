@@ -3,32 +3,34 @@ use resend_rs::{Resend, Result};
 
 pub struct ResendClient {
   client: Resend,
+  from: String,
 }
 
 impl ResendClient {
-  pub fn new() -> Self {
-    tracing::info!("Initializing Resend client");
+  pub fn new(from: &str) -> Self {
+    tracing::info!("Initializing Resend client sending as: {}", from);
     ResendClient {
       client: Resend::default(),
+      from: from.to_string(),
     }
   }
 
   pub async fn send_email(
     &self,
-    customer_support_email: &str,
+    to: &str,
     subject: &str,
-    email_body: &str,
+    html: &str,
+    text: &str,
   ) -> Result<()> {
-    tracing::info!("Sending email to: {}", customer_support_email);
-    let from = "evan@updates.omega-tab.evanrobertson.dev";
-    let to = [customer_support_email];
+    tracing::info!("Sending email to: {}", to);
 
-    let email = CreateEmailBaseOptions::new(from, to, subject)
-      .with_html(email_body);
+    let email = CreateEmailBaseOptions::new(&self.from, [to], subject)
+      .with_html(html)
+      .with_text(text);
 
     match self.client.emails.send(email).await {
       Ok(_email) => {
-        tracing::info!("Successfully sent email to: {}", customer_support_email);
+        tracing::info!("Successfully sent email to: {}", to);
         Ok(())
       },
       Err(e) => {
@@ -37,4 +39,4 @@ impl ResendClient {
       }
     }
   }
-}
\ No newline at end of file
+}
@@ -0,0 +1,17 @@
+use base64::prelude::*;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+/// Generate a random, URL-safe opaque token with 256 bits of entropy
+pub fn generate() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    BASE64_URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Hash an opaque token for storage
+/// Tokens are high-entropy random values, so a fast unsalted hash is enough to keep them
+/// useless if the database leaks while still allowing lookups by hash
+pub fn hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
@@ -0,0 +1,204 @@
+use hmac::{Hmac, Mac};
+use rand::{Rng, RngCore};
+use sha1::Sha1;
+
+/// RFC 6238 defaults, which is what authenticator apps assume
+const STEP_SECONDS: u64 = 30;
+const DIGITS: u32 = 6;
+/// Accept codes from one step either side to allow for clock drift
+const ALLOWED_DRIFT_STEPS: u64 = 1;
+const ISSUER: &str = "OmegaTab";
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// RFC 4648 base32 without padding, the encoding authenticator apps expect secrets in
+pub fn base32_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for &byte in bytes {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+
+    encoded
+}
+
+/// Decode base32, ignoring case, spaces and padding
+pub fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for c in encoded.chars().filter(|c| !c.is_whitespace() && *c != '=') {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|&a| a as char == c.to_ascii_uppercase())? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(decoded)
+}
+
+/// New random 160-bit secret, base32 encoded
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    base32_encode(&bytes)
+}
+
+/// HOTP value (RFC 4226) for a counter, truncated to `digits`
+fn hotp(secret: &[u8], counter: u64, digits: u32) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+
+    format!(
+        "{:0width$}",
+        binary % 10u32.pow(digits),
+        width = digits as usize
+    )
+}
+
+/// Check a code against a base32 secret, returning the time step it matched
+/// Callers store the step and reject codes at or before it, so a code can't be replayed
+pub fn verify(secret: &str, code: &str, unix_time: u64) -> Option<u64> {
+    let secret = base32_decode(secret)?;
+    let code = code.trim().replace(' ', "");
+    if code.len() != DIGITS as usize {
+        return None;
+    }
+
+    let current = unix_time / STEP_SECONDS;
+    (current.saturating_sub(ALLOWED_DRIFT_STEPS)..=current + ALLOWED_DRIFT_STEPS)
+        .find(|&step| hotp(&secret, step, DIGITS) == code)
+}
+
+/// `otpauth://` URI for enrolling the secret in an authenticator app, usually shown as a QR code
+pub fn otpauth_uri(secret: &str, account: &str) -> String {
+    let label: String =
+        url::form_urlencoded::byte_serialize(format!("{}:{}", ISSUER, account).as_bytes())
+            .collect();
+    format!(
+        "otpauth://totp/{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        label.replace('+', "%20"),
+        secret,
+        ISSUER,
+        DIGITS,
+        STEP_SECONDS
+    )
+}
+
+/// Ten single-use recovery codes like `k3f9x-2mqa7-...`, shown to the user once
+/// Each is 20 characters from a 31-letter alphabet, about 99 bits, so the fast unsalted
+/// `tokens::hash` they're stored as can't be brute-forced if the database leaks
+pub fn generate_recovery_codes() -> Vec<String> {
+    const ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+    let mut rng = rand::thread_rng();
+
+    (0..10)
+        .map(|_| {
+            let chars: Vec<char> = (0..20)
+                .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+                .collect();
+            chars
+                .chunks(5)
+                .map(|group| group.iter().collect::<String>())
+                .collect::<Vec<_>>()
+                .join("-")
+        })
+        .collect()
+}
+
+/// Recovery codes are compared case-insensitively and without the dash
+pub fn normalize_recovery_code(code: &str) -> String {
+    code.trim().to_lowercase().replace(['-', ' '], "")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The SHA-1 seed from RFC 6238 appendix B
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn matches_rfc_6238_sha1_vectors() {
+        let vectors = [
+            (59, "94287082"),
+            (1111111109, "07081804"),
+            (1111111111, "14050471"),
+            (1234567890, "89005924"),
+            (2000000000, "69279037"),
+            (20000000000, "65353130"),
+        ];
+        for (unix_time, expected) in vectors {
+            assert_eq!(
+                hotp(RFC_SECRET, unix_time / STEP_SECONDS, 8),
+                expected,
+                "T = {}",
+                unix_time
+            );
+        }
+    }
+
+    #[test]
+    fn accepts_one_step_of_drift_either_way() {
+        let secret = base32_encode(RFC_SECRET);
+        let unix_time = 1111111111;
+        let step = unix_time / STEP_SECONDS;
+        let code_at = |step: u64| hotp(RFC_SECRET, step, DIGITS);
+
+        assert_eq!(verify(&secret, &code_at(step), unix_time), Some(step));
+        assert_eq!(
+            verify(&secret, &code_at(step - 1), unix_time),
+            Some(step - 1)
+        );
+        assert_eq!(
+            verify(&secret, &code_at(step + 1), unix_time),
+            Some(step + 1)
+        );
+        assert_eq!(verify(&secret, &code_at(step - 2), unix_time), None);
+        assert_eq!(verify(&secret, &code_at(step + 2), unix_time), None);
+    }
+
+    #[test]
+    fn base32_round_trips() {
+        let secret = base32_encode(RFC_SECRET);
+        assert_eq!(secret, "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+        assert_eq!(base32_decode(&secret.to_lowercase()).unwrap(), RFC_SECRET);
+    }
+
+    #[test]
+    fn recovery_codes_have_at_least_80_bits() {
+        let codes = generate_recovery_codes();
+        assert_eq!(codes.len(), 10);
+        for code in &codes {
+            let normalized = normalize_recovery_code(code);
+            let bits = normalized.len() as f64 * 31f64.log2();
+            assert!(bits >= 80.0, "{} has {} bits", code, bits);
+            assert!(code.contains('-'));
+        }
+    }
+}
@@ -1,16 +1,63 @@
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{
+    decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
 use serde::{Deserialize, Serialize};
-use std::time::{SystemTime, UNIX_EPOCH};
 use std::env;
-use anyhow::Result;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::tokens;
 
 // Define the JWT claims structure
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UserClaims {
     pub user_id: String,
     pub email: String,
-    pub exp: usize, // Expiration time (as UTC timestamp)
-    pub iat: usize, // Issued at (as UTC timestamp)
+    pub exp: usize,  // Expiration time (as UTC timestamp)
+    pub iat: usize,  // Issued at (as UTC timestamp)
+    pub jti: String, // Unique token ID, checked against revoked_tokens
+    pub sid: String, // Session the token was issued for
+}
+
+/// An HMAC signing key, identified by the `kid` in token headers
+#[derive(Clone, Serialize, Deserialize)]
+struct JwtKey {
+    kid: String,
+    secret: String,
+    created_at: String,
+    #[serde(default)]
+    retired_at: Option<String>,
+}
+
+impl JwtKey {
+    fn new(secret: String) -> Self {
+        JwtKey {
+            // Derived from the secret so the same secret always gets the same kid
+            kid: tokens::hash(&secret)[..16].to_string(),
+            secret,
+            created_at: Utc::now().to_rfc3339(),
+            retired_at: None,
+        }
+    }
+}
+
+/// Layout of `jwt_keys.json` in the data directory
+#[derive(Serialize, Deserialize)]
+struct KeyFile {
+    current: JwtKey,
+    previous: Option<JwtKey>,
+}
+
+/// Signing keys, loaded once at startup
+/// Tokens are signed with the current key, and the previous key is still accepted
+/// for a grace period after a rotation so nobody is signed out mid-session
+pub struct JwtKeys {
+    current: JwtKey,
+    previous: Option<JwtKey>,
+    grace_period: chrono::Duration,
 }
 
 // Helper function to get current timestamp
@@ -21,57 +68,224 @@ fn get_current_timestamp() -> usize {
         .as_secs() as usize
 }
 
-pub fn generate_jwt(user_id: &str, email: &str) -> Result<String> {
-    // Get JWT secret from environment variable
-    let secret = env::var("JWT_SECRET").expect("JWT_SECRET must be set");
-
-    // Set expiration time to 100 years from now (effectively forever for a local app)
-    let exp = get_current_timestamp() + (100 * 365 * 24 * 60 * 60); // ~100 years in seconds
-    let iat = get_current_timestamp();
-
-    let claims = UserClaims {
-        user_id: user_id.to_string(),
-        email: email.to_string(),
-        exp,
-        iat,
-    };
-    
-    // Create header
-    let header = Header::new(Algorithm::HS256);
-    
-    // Encode the JWT
-    let token = encode(
-        &header,
-        &claims,
-        &EncodingKey::from_secret(secret.as_bytes()),
-    )?;
-    
-    Ok(token)
+/// Lifetime of an access token in seconds (`ACCESS_TOKEN_TTL_MINUTES`, default 15 minutes)
+pub fn access_token_ttl() -> usize {
+    env::var("ACCESS_TOKEN_TTL_MINUTES")
+        .ok()
+        .and_then(|minutes| minutes.parse::<usize>().ok())
+        .unwrap_or(15)
+        * 60
+}
+
+/// How long a rotated-out key keeps verifying tokens (`JWT_KEY_GRACE_HOURS`, default 24 hours)
+fn grace_period() -> chrono::Duration {
+    let hours = env::var("JWT_KEY_GRACE_HOURS")
+        .ok()
+        .and_then(|hours| hours.parse::<i64>().ok())
+        .unwrap_or(24);
+    chrono::Duration::hours(hours)
 }
 
-pub fn validate_jwt(token: &str) -> Result<UserClaims> {
-    // Get JWT secret from environment variable
-    let secret = env::var("JWT_SECRET").expect("JWT_SECRET must be set");
-    
-    // Decode and validate the JWT
-    let validation = Validation::new(Algorithm::HS256);
-    
-    let token_data = decode::<UserClaims>(
-        token,
-        &DecodingKey::from_secret(secret.as_bytes()),
-        &validation,
-    )?;
-    
-    Ok(token_data.claims)
+/// Age after which the generated key is rotated at startup (`JWT_KEY_ROTATION_DAYS`, off by default)
+fn rotation_age() -> Option<chrono::Duration> {
+    env::var("JWT_KEY_ROTATION_DAYS")
+        .ok()
+        .and_then(|days| days.parse::<i64>().ok())
+        .filter(|days| *days > 0)
+        .map(chrono::Duration::days)
 }
 
-// Function to check if token needs refresh (if it's close to expiring)
-pub fn needs_refresh(token: &str) -> Result<bool> {
-    let claims = validate_jwt(token)?;
-    
-    // Get current time
-    let now = get_current_timestamp();
-    
-    // If token is more than 45 minutes old (75% of its lifetime), it needs refresh
-    Ok(claims.exp - now < 900) // 900 = 15 minutes left before expiration
-}
\ No newline at end of file
+/// Write the key file readable only by the current user, replacing it atomically
+fn write_key_file(path: &Path, key_file: &KeyFile) -> Result<()> {
+    let tmp_path = path.with_extension("json.tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(&tmp_path)?;
+    file.write_all(serde_json::to_string_pretty(key_file)?.as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Make an existing key file readable only by the current user, in case it was created
+/// or copied without the permissions `write_key_file` gives it
+fn restrict_permissions(path: &Path) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(path)?.permissions().mode();
+        if mode & 0o077 != 0 {
+            tracing::warn!(
+                "Restricting permissions on {} from {:o} to 600",
+                path.display(),
+                mode & 0o777
+            );
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+impl JwtKeys {
+    /// Use `JWT_SECRET` (and `JWT_PREVIOUS_SECRET` while rotating) when set, otherwise
+    /// load `jwt_keys.json` from the data directory, generating it on first run
+    pub fn load(data_dir: &Path) -> Result<Self> {
+        let grace_period = grace_period();
+
+        if let Ok(secret) = env::var("JWT_SECRET").map(|s| s.trim().to_string()) {
+            if !secret.is_empty() {
+                tracing::info!("Using JWT_SECRET for signing tokens");
+                // The operator removes JWT_PREVIOUS_SECRET once the grace period is over
+                let previous = env::var("JWT_PREVIOUS_SECRET")
+                    .ok()
+                    .filter(|s| !s.trim().is_empty())
+                    .map(|s| JwtKey::new(s.trim().to_string()));
+                return Ok(JwtKeys {
+                    current: JwtKey::new(secret),
+                    previous,
+                    grace_period: chrono::Duration::MAX,
+                });
+            }
+        }
+
+        std::fs::create_dir_all(data_dir)?;
+        let path: PathBuf = data_dir.join("jwt_keys.json");
+
+        let mut key_file = if path.exists() {
+            restrict_permissions(&path)?;
+            let key_file: KeyFile = serde_json::from_str(&std::fs::read_to_string(&path)?)
+                .map_err(|e| anyhow::anyhow!("Invalid key file {}: {}", path.display(), e))?;
+            key_file
+        } else {
+            tracing::info!("Generating JWT signing key at {}", path.display());
+            let key_file = KeyFile {
+                current: JwtKey::new(tokens::generate()),
+                previous: None,
+            };
+            write_key_file(&path, &key_file)?;
+            key_file
+        };
+
+        if let Some(max_age) = rotation_age() {
+            let created_at = DateTime::parse_from_rfc3339(&key_file.current.created_at)?;
+            if Utc::now() - created_at.with_timezone(&Utc) > max_age {
+                tracing::info!("Rotating JWT signing key {}", key_file.current.kid);
+                let mut previous = key_file.current;
+                previous.retired_at = Some(Utc::now().to_rfc3339());
+                key_file = KeyFile {
+                    current: JwtKey::new(tokens::generate()),
+                    previous: Some(previous),
+                };
+                write_key_file(&path, &key_file)?;
+            }
+        }
+
+        Ok(JwtKeys {
+            current: key_file.current,
+            previous: key_file.previous,
+            grace_period,
+        })
+    }
+
+    /// The key a token was signed with, going by its `kid`
+    /// Tokens from before key IDs were added have none, and can only match the current key
+    fn key_for(&self, kid: Option<&str>) -> Option<&JwtKey> {
+        let kid = match kid {
+            Some(kid) => kid,
+            None => return Some(&self.current),
+        };
+        if kid == self.current.kid {
+            return Some(&self.current);
+        }
+
+        let previous = self.previous.as_ref().filter(|key| key.kid == kid)?;
+        let in_grace_period = match &previous.retired_at {
+            Some(retired_at) => DateTime::parse_from_rfc3339(retired_at)
+                .map(|retired_at| Utc::now() - retired_at.with_timezone(&Utc) < self.grace_period)
+                .unwrap_or(false),
+            None => true,
+        };
+        in_grace_period.then_some(previous)
+    }
+
+    pub fn generate_jwt(&self, user_id: &str, email: &str, session_id: &str) -> Result<String> {
+        // Access tokens are short-lived, clients renew them with their refresh token
+        let iat = get_current_timestamp();
+        let exp = iat + access_token_ttl();
+
+        let claims = UserClaims {
+            user_id: user_id.to_string(),
+            email: email.to_string(),
+            exp,
+            iat,
+            jti: uuid::Uuid::new_v4().to_string(),
+            sid: session_id.to_string(),
+        };
+
+        // Create header, naming the key so it can still be verified after a rotation
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some(self.current.kid.clone());
+
+        // Encode the JWT
+        let token = encode(
+            &header,
+            &claims,
+            &EncodingKey::from_secret(self.current.secret.as_bytes()),
+        )?;
+
+        Ok(token)
+    }
+
+    pub fn validate_jwt(&self, token: &str) -> Result<UserClaims> {
+        let header = decode_header(token)?;
+        let key = self
+            .key_for(header.kid.as_deref())
+            .ok_or_else(|| anyhow::anyhow!("Unknown or expired signing key"))?;
+
+        // Decode and validate the JWT
+        let validation = Validation::new(Algorithm::HS256);
+
+        let token_data = decode::<UserClaims>(
+            token,
+            &DecodingKey::from_secret(key.secret.as_bytes()),
+            &validation,
+        )?;
+
+        Ok(token_data.claims)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        let dir = env::temp_dir().join(format!("omega-tab-keys-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn key_file_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = temp_dir();
+        let path = dir.join("jwt_keys.json");
+        let mode = || std::fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+
+        JwtKeys::load(&dir).unwrap();
+        assert_eq!(mode(), 0o600);
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        JwtKeys::load(&dir).unwrap();
+        assert_eq!(mode(), 0o600);
+    }
+}
@@ -0,0 +1,64 @@
+/// Plan new organizations and teams start on (`FREE_PLAN_ID`, default the one the initial migration seeds)
+pub fn free_plan_id() -> String {
+    std::env::var("FREE_PLAN_ID")
+        .ok()
+        .filter(|id| !id.trim().is_empty())
+        .unwrap_or_else(|| "a0b1c2d3-e4f5-6789-abcd-ef0123456789".to_string())
+}
+
+/// Which kind of workspace a nested router serves, as the `entity_type` of its memberships
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Organization,
+    Team,
+}
+
+impl Kind {
+    pub fn entity_type(&self) -> &'static str {
+        match self {
+            Kind::Organization => "organization",
+            Kind::Team => "team",
+        }
+    }
+}
+
+/// A member's role, each one allowing everything the roles before it do
+/// Viewers can see the workspace and who's in it, members can also create teams in an organization,
+/// and admins manage its name and members
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Viewer,
+    Member,
+    Admin,
+}
+
+impl Role {
+    pub fn parse(role: &str) -> Option<Self> {
+        match role.trim().to_ascii_lowercase().as_str() {
+            "viewer" => Some(Role::Viewer),
+            "member" => Some(Role::Member),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Member => "member",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+/// Longest name an organization or team can have
+pub const MAX_NAME_LENGTH: usize = 100;
+
+/// Trimmed workspace name, or None when it's empty or too long
+pub fn parse_name(name: &str) -> Option<&str> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return None;
+    }
+    Some(name)
+}